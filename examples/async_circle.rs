@@ -0,0 +1,42 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Run with `cargo run --example async_circle --features async`.
+
+extern crate etherdream;
+
+use etherdream::async_dac::AsyncDac;
+use etherdream::protocol::Point;
+use etherdream::protocol::X_MAX;
+use etherdream::protocol::Y_MAX;
+use std::f64::consts::PI;
+
+static DIV : i32 = 200;
+
+#[tokio::main]
+async fn main() {
+  println!("Searching for DAC...");
+
+  let ip_addr = match etherdream::async_dac::find_first_dac().await {
+    Err(e) => {
+      println!("Could not find DAC because of error: {}", e);
+      std::process::exit(0);
+    },
+    Ok(result) => {
+      println!("Found DAC at IP: {}", result.ip_address);
+      result.ip_address
+    },
+  };
+
+  let mut dac = AsyncDac::connect(ip_addr).await
+      .expect("Couldn't connect to DAC!");
+
+  let points = futures::stream::iter((0 ..).map(|i| {
+    let j = ((i % DIV) as f64 / DIV as f64) * 2.0 * PI;
+    let x = j.cos() * X_MAX as f64;
+    let y = j.sin() * Y_MAX as f64;
+    Point::xy_binary(x as i16, y as i16, true)
+  }));
+
+  let _r = dac.stream_points(points).await;
+}
@@ -8,13 +8,12 @@ use etherdream::protocol::COLOR_MAX;
 use etherdream::protocol::Point;
 use etherdream::protocol::X_MAX;
 use etherdream::protocol::X_MIN;
-use etherdream::protocol::Y_MAX;
-use etherdream::protocol::Y_MIN;
 use std::f64::consts::PI;
 use std::f64;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::thread;
+use std::time::Duration;
 
 static DIV : i16 = 200;
 
@@ -40,7 +39,7 @@ impl Circle {
       r: COLOR_MAX,
       g: COLOR_MAX,
       b: 0,
-      radius: radius,
+      radius,
       x: 0,
       y: 0,
       x_vel: 170,
@@ -55,7 +54,7 @@ impl Circle {
     for _i in 0 .. num_points {
       *position = (*position + 1) % DIV;
 
-      let j = (*position as f64 / DIV as f64) * 2 as f64 * PI;
+      let j = (*position as f64 / DIV as f64) * 2.0 * PI;
       let x = j.cos() * self.radius as f64 + self.x as f64;
       let y = j.sin() * self.radius as f64 + self.y as f64;
 
@@ -114,8 +113,8 @@ fn main() {
 
   let mut dac = Dac::new(ip_addr);
 
-  let mut circle = Arc::new(RwLock::new(Circle::new(8000)));
-  let mut circle2 = circle.clone();
+  let circle = Arc::new(RwLock::new(Circle::new(8000)));
+  let circle2 = circle.clone();
 
   let mut pos = 0;
 
@@ -123,11 +122,11 @@ fn main() {
     loop {
       // FIXME: Locking critical section is bigger than it has to be.
       circle.write().unwrap().animate();
-      thread::sleep_ms(10);
+      thread::sleep(Duration::from_millis(10));
     }
   });
 
-  dac.play_function(|num_points: u16| {
+  let _r = dac.play_function(|num_points: u16| {
     circle2.read().unwrap().get_points(num_points, &mut pos)
   });
 }
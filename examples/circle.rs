@@ -29,17 +29,15 @@ fn main() {
 
   let mut dac = Dac::new(ip_addr);
 
-  static mut pos: i32 = 0;
+  let mut pos: i32 = 0;
 
   let _r = dac.play_function(|num_points: u16| {
     let mut points = Vec::new();
     for _i in 0 .. num_points {
-      let f = unsafe {
-        pos = (pos + 1) % DIV;
-        pos
-      };
+      pos = (pos + 1) % DIV;
+      let f = pos;
 
-      let j = (f as f64 / DIV as f64) * 2 as f64 * PI;
+      let j = (f as f64 / DIV as f64) * 2.0 * PI;
       let x = j.cos() * X_MAX as f64;
       let y = j.sin() * Y_MAX as f64;
 
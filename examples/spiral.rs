@@ -5,8 +5,6 @@ extern crate etherdream;
 
 use etherdream::dac::Dac;
 use etherdream::protocol::Point;
-use etherdream::protocol::X_MAX;
-use etherdream::protocol::Y_MAX;
 use std::f64::consts::PI;
 use std::f64;
 
@@ -37,7 +35,7 @@ fn main() {
 
   let mut dac = Dac::new(ip_addr);
 
-  static mut pos: i32 = 0;
+  let mut pos: i32 = 0;
 
   let _r = dac.play_function(|num_points: u16| {
     let mut points = Vec::new();
@@ -46,10 +44,8 @@ fn main() {
       // TODO: Let's build this into etherdream.rs
       // Get the current point along the beam.
       // TODO: Also, let's create a `dac.play_stream(S: Stream)`.
-      let f = unsafe {
-        pos = (pos + 1) % (BLANKING_POINTS + SPIRAL_POINTS);
-        pos
-      };
+      pos = (pos + 1) % (BLANKING_POINTS + SPIRAL_POINTS);
+      let f = pos;
 
       if f < SPIRAL_POINTS {
         let (x, y) = get_spiral_point(f);
@@ -49,7 +49,7 @@ impl Square {
     let mut i = pos as usize % self.prototype.len();
 
     while points.len() < num_points {
-      let point = self.prototype.get(i).unwrap().clone(); // FIXME LAZINESS
+      let point = self.prototype[i];
       points.push(point);
 
       i = (i + 1) % self.prototype.len();
@@ -81,7 +81,7 @@ fn main() {
 
   let _r = dac.stream_simple_points(|num_points: u16| {
     let points = square.get_points(num_points, pos);
-    pos = pos + (points.len() as u32);
+    pos += points.len() as u32;
     points
   });
 }
@@ -0,0 +1,476 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! An asynchronous EtherDream client built on Tokio.
+//! This module is only available with the `async` cargo feature.
+
+use crate::error::EtherdreamError;
+use crate::network::COMMUNICATION_PORT;
+use crate::network::SearchResult;
+use crate::network::bind_broadcast_socket;
+use crate::protocol::Begin;
+use crate::protocol::Broadcast;
+use crate::protocol::COMMAND_CLEAR_EMERGENCY_STOP;
+use crate::protocol::COMMAND_EMERGENCY_STOP;
+use crate::protocol::COMMAND_PING;
+use crate::protocol::COMMAND_PREPARE;
+use crate::protocol::COMMAND_STOP;
+use crate::protocol::CommandCode;
+use crate::protocol::DacResponse;
+use crate::protocol::Data;
+use crate::protocol::Point;
+use futures::Sink;
+use futures::Stream;
+use futures::StreamExt;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+
+/// The number of points the DAC can hold in its buffer.
+const BUFFER_CAPACITY : u16 = 1799;
+
+/// Don't bother sending fewer points than this unless the stream is ending.
+const MIN_WRITE_SIZE : u16 = 100;
+
+/// The point rate used if none is configured.
+pub const DEFAULT_POINT_RATE : u32 = 30_000;
+
+/// An EtherDream DAC driven from a Tokio runtime.
+/// Shares the `protocol` types with the blocking `Dac`.
+pub struct AsyncDac {
+  ip_address: IpAddr,
+  stream: TcpStream,
+  last_response: DacResponse,
+  point_rate: u32,
+}
+
+impl AsyncDac {
+  /// Connect to the DAC at the given address.
+  /// Waits for the status message the DAC sends upon connection.
+  pub async fn connect(ip_address: IpAddr) -> Result<AsyncDac, EtherdreamError> {
+    let stream = TcpStream::connect((ip_address, COMMUNICATION_PORT)).await?;
+    AsyncDac::from_stream(ip_address, stream).await
+  }
+
+  /// Take over a connection to a DAC, waiting for its greeting.
+  async fn from_stream(ip_address: IpAddr, mut stream: TcpStream)
+      -> Result<AsyncDac, EtherdreamError> {
+    stream.set_nodelay(true)?;
+
+    let last_response = read_response(&mut stream).await?;
+
+    Ok(AsyncDac {
+      ip_address,
+      stream,
+      last_response,
+      point_rate: DEFAULT_POINT_RATE,
+    })
+  }
+
+  /// IP address the DAC lives at.
+  pub fn get_ip_address(&self) -> &IpAddr {
+    &self.ip_address
+  }
+
+  /// The most recent response received from the DAC.
+  pub fn last_response(&self) -> &DacResponse {
+    &self.last_response
+  }
+
+  /// The point rate used when playback begins.
+  pub fn get_point_rate(&self) -> u32 {
+    self.point_rate
+  }
+
+  /// Set the point rate used when playback begins.
+  pub fn set_point_rate(&mut self, point_rate: u32) {
+    self.point_rate = point_rate;
+  }
+
+  /// Ping the DAC for its current status.
+  pub async fn ping(&mut self) -> Result<DacResponse, EtherdreamError> {
+    self.send_command(&[COMMAND_PING], CommandCode::Ping).await
+  }
+
+  /// Prepare the DAC's playback system to accept points.
+  pub async fn prepare(&mut self) -> Result<DacResponse, EtherdreamError> {
+    self.send_command(&[COMMAND_PREPARE], CommandCode::Prepare).await
+  }
+
+  /// Begin playing the buffered points at the given rate.
+  pub async fn begin(&mut self, point_rate: u32)
+      -> Result<DacResponse, EtherdreamError> {
+    let cmd = Begin { low_water_mark: 0, point_rate };
+    self.send_command(&cmd.serialize(), CommandCode::Begin).await
+  }
+
+  /// Queue points in the DAC's buffer. A single write can't hold more than
+  /// `u16::MAX` points, and the DAC's buffer holds far fewer.
+  pub async fn write_points(&mut self, points: &[Point])
+      -> Result<DacResponse, EtherdreamError> {
+    if points.len() > u16::MAX as usize {
+      return Err(EtherdreamError::BatchTooLarge {
+        description: format!("{} points can't be sent in one write; the limit is {}.",
+            points.len(), u16::MAX),
+      });
+    }
+    let cmd = Data { points };
+    self.send_command(&cmd.serialize(), CommandCode::Data).await
+  }
+
+  /// Stop playback and return the DAC to the idle state.
+  pub async fn stop(&mut self) -> Result<DacResponse, EtherdreamError> {
+    self.send_command(&[COMMAND_STOP], CommandCode::Stop).await
+  }
+
+  /// Put the DAC into the emergency stop state.
+  pub async fn emergency_stop(&mut self)
+      -> Result<DacResponse, EtherdreamError> {
+    self.send_command(&[COMMAND_EMERGENCY_STOP], CommandCode::EmergencyStop)
+        .await
+  }
+
+  /// Clear emergency stop state.
+  pub async fn clear_emergency_stop(&mut self)
+      -> Result<DacResponse, EtherdreamError> {
+    self.send_command(&[COMMAND_CLEAR_EMERGENCY_STOP],
+        CommandCode::ClearEmergencyStop).await
+  }
+
+  /// Stream points until the stream ends. The DAC plays out whatever remains
+  /// in its buffer afterwards.
+  pub async fn stream_points<S>(&mut self, mut points: S)
+      -> Result<(), EtherdreamError> where S: Stream<Item = Point> + Unpin {
+    self.prepare_stream().await?;
+
+    let mut batch = Vec::with_capacity(BUFFER_CAPACITY as usize);
+
+    let mut ended = false;
+
+    while !ended {
+      let free = self.wait_for_space().await? as usize;
+
+      batch.clear();
+      while batch.len() < free {
+        match points.next().await {
+          Some(point) => batch.push(point),
+          None => {
+            ended = true;
+            break;
+          },
+        }
+      }
+
+      if !batch.is_empty() {
+        self.enqueue(&batch).await?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Convert the DAC into a `Sink` of point batches. Each batch is queued as
+  /// soon as the DAC has room for it.
+  pub fn into_sink(self) -> impl Sink<Vec<Point>, Error = EtherdreamError> {
+    futures::sink::unfold((self, false), |(mut dac, prepared), points: Vec<Point>| {
+      async move {
+        if !prepared {
+          dac.prepare_stream().await?;
+        }
+
+        let mut remaining = &points[..];
+        while !remaining.is_empty() {
+          let free = dac.wait_for_space().await? as usize;
+          let (chunk, rest) = remaining.split_at(free.min(remaining.len()));
+          dac.enqueue(chunk).await?;
+          remaining = rest;
+        }
+
+        Ok::<_, EtherdreamError>((dac, true))
+      }
+    })
+  }
+
+  /// Get the playback system into the prepared state.
+  async fn prepare_stream(&mut self) -> Result<(), EtherdreamError> {
+    if self.last_response.status.light_engine_state == 3 {
+      self.clear_emergency_stop().await?;
+    }
+
+    match self.last_response.status.playback_state {
+      0 => {
+        self.prepare().await?;
+      },
+      2 => {
+        self.stop().await?;
+        self.prepare().await?;
+      },
+      _ => {},
+    }
+
+    Ok(())
+  }
+
+  /// Queue points, then begin playback if we haven't already.
+  async fn enqueue(&mut self, points: &[Point]) -> Result<(), EtherdreamError> {
+    self.write_points(points).await?;
+
+    if self.last_response.status.playback_state == 1 {
+      let point_rate = self.point_rate;
+      self.begin(point_rate).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Wait until the DAC can accept a reasonably sized write. The buffer only
+  /// drains while playing, so a full buffer that isn't playing is started, or
+  /// prepared again if the DAC has gone idle.
+  /// Returns the number of points that will fit in the buffer.
+  async fn wait_for_space(&mut self) -> Result<u16, EtherdreamError> {
+    loop {
+      let fullness = self.last_response.status.buffer_fullness;
+      let free = BUFFER_CAPACITY.saturating_sub(fullness);
+
+      if free >= MIN_WRITE_SIZE {
+        return Ok(free);
+      }
+
+      match self.last_response.status.playback_state {
+        0 => {
+          self.prepare().await?;
+          continue;
+        },
+        1 => {
+          let point_rate = self.point_rate;
+          self.begin(point_rate).await?;
+          continue;
+        },
+        _ => {},
+      }
+
+      let point_rate = match self.last_response.status.point_rate {
+        0 => self.point_rate.max(1),
+        rate => rate,
+      };
+
+      let wait = (MIN_WRITE_SIZE - free) as u64 * 1_000_000 / point_rate as u64;
+      tokio::time::sleep(Duration::from_micros(wait)).await;
+      self.ping().await?;
+    }
+  }
+
+  /// Send a command and read the DAC's response to it.
+  async fn send_command(&mut self, bytes: &[u8], expected_command: CommandCode)
+      -> Result<DacResponse, EtherdreamError> {
+    self.stream.write_all(bytes).await?;
+
+    let response = read_response(&mut self.stream).await?;
+    self.last_response = response;
+
+    if !response.acknowledgement.is_ack() {
+      return Err(EtherdreamError::ReceivedNack {
+        code: response.acknowledgement,
+        command: response.command,
+      });
+    }
+
+    if response.command != expected_command {
+      return Err(EtherdreamError::WrongResponse);
+    }
+
+    Ok(response)
+  }
+}
+
+/// Return the first EtherDream DAC found by listening for UDP broadcasts.
+pub async fn find_first_dac() -> Result<SearchResult, EtherdreamError> {
+  let socket = bind_async_broadcast_socket()?;
+  receive_broadcast(&socket).await
+}
+
+/// A stream of every broadcast heard on the network. DACs broadcast about once
+/// per second, so the same DAC will show up repeatedly.
+/// Must be called from within a Tokio runtime.
+pub fn discover()
+    -> Result<impl Stream<Item = Result<SearchResult, EtherdreamError>>,
+        EtherdreamError> {
+  let socket = bind_async_broadcast_socket()?;
+
+  Ok(futures::stream::unfold(socket, |socket| async move {
+    let result = receive_broadcast(&socket).await;
+    Some((result, socket))
+  }))
+}
+
+fn bind_async_broadcast_socket() -> Result<UdpSocket, EtherdreamError> {
+  let socket = bind_broadcast_socket()?;
+  socket.set_nonblocking(true)?;
+  Ok(UdpSocket::from_std(socket)?)
+}
+
+async fn receive_broadcast(socket: &UdpSocket)
+    -> Result<SearchResult, EtherdreamError> {
+  let mut buf = [0u8; 128];
+  let (size, address) = socket.recv_from(&mut buf).await?;
+
+  let broadcast = Broadcast::parse(&buf[0..size])?;
+
+  Ok(SearchResult {
+    ip_address: address.ip(),
+    broadcast,
+  })
+}
+
+async fn read_response(stream: &mut TcpStream)
+    -> Result<DacResponse, EtherdreamError> {
+  let mut buf = [0; 22];
+  stream.read_exact(&mut buf).await?;
+  DacResponse::parse(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::DacStatus;
+  use futures::SinkExt;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+  use tokio::net::TcpListener;
+  use tokio::task::JoinHandle;
+
+  /// Commands received by the mock DAC, with the number of points in each
+  /// data command.
+  type Log = Arc<Mutex<Vec<(u8, usize)>>>;
+
+  fn status(playback_state: u8, buffer_fullness: u16) -> DacStatus {
+    DacStatus {
+      protocol: 0,
+      light_engine_state: 0,
+      playback_state,
+      source: 0,
+      light_engine_flags: 0,
+      playback_flags: 0,
+      source_flags: 0,
+      buffer_fullness,
+      point_rate: if playback_state == 2 { DEFAULT_POINT_RATE } else { 0 },
+      point_count: 0,
+    }
+  }
+
+  /// Accept one connection and play along with the protocol. The buffer
+  /// drains by 600 points each time it's pinged while playing.
+  async fn mock_dac(initial: DacStatus) -> (AsyncDac, Log, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let log = Log::default();
+    let server_log = log.clone();
+
+    let server = tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut status = initial;
+
+      let mut greeting = vec![b'a', COMMAND_PING];
+      greeting.extend(status.serialize());
+      stream.write_all(&greeting).await.unwrap();
+
+      loop {
+        let command = match stream.read_u8().await {
+          Ok(command) => command,
+          Err(_) => return,
+        };
+        let mut count = 0;
+        match command {
+          b'd' => {
+            count = stream.read_u16_le().await.unwrap() as usize;
+            let mut points = vec![0; count * 18];
+            stream.read_exact(&mut points).await.unwrap();
+            status.buffer_fullness += count as u16;
+          },
+          b'b' => {
+            let mut args = [0; 6];
+            stream.read_exact(&mut args).await.unwrap();
+            status.playback_state = 2;
+          },
+          b'p' => status = status_with(status, 1, 0),
+          b's' => status = status_with(status, 0, 0),
+          b'?' if status.playback_state == 2 => {
+            status.buffer_fullness = status.buffer_fullness.saturating_sub(600);
+          },
+          _ => {},
+        }
+        server_log.lock().unwrap().push((command, count));
+
+        let mut response = vec![b'a', command];
+        response.extend(status.serialize());
+        stream.write_all(&response).await.unwrap();
+      }
+    });
+
+    let stream = TcpStream::connect(address).await.unwrap();
+    let dac = AsyncDac::from_stream(address.ip(), stream).await.unwrap();
+    (dac, log, server)
+  }
+
+  fn status_with(status: DacStatus, playback_state: u8, buffer_fullness: u16)
+      -> DacStatus {
+    DacStatus { playback_state, buffer_fullness, ..status }
+  }
+
+  fn commands(log: &Log) -> Vec<u8> {
+    log.lock().unwrap().iter().map(|&(command, _)| command).collect()
+  }
+
+  fn points_sent(log: &Log) -> Vec<usize> {
+    log.lock().unwrap().iter()
+        .filter(|&&(command, _)| command == b'd')
+        .map(|&(_, count)| count)
+        .collect()
+  }
+
+  #[tokio::test]
+  async fn test_full_buffer_begins_playback() {
+    // Prepared by an earlier connection, with a full buffer.
+    let (mut dac, log, server) = mock_dac(status(1, BUFFER_CAPACITY)).await;
+    let points = futures::stream::iter(vec![Point::xy_blank(0, 0); 500]);
+    dac.stream_points(points).await.unwrap();
+    drop(dac);
+    server.await.unwrap();
+
+    assert_eq!(vec![b'b', b'?', b'd'], commands(&log));
+    assert_eq!(vec![500], points_sent(&log));
+  }
+
+  #[tokio::test]
+  async fn test_sink_waits_for_space() {
+    let (dac, log, server) = mock_dac(status(0, 0)).await;
+    let mut sink = Box::pin(dac.into_sink());
+    sink.send(vec![Point::xy_blank(0, 0); 1000]).await.unwrap();
+    sink.send(vec![Point::xy_blank(0, 0); 1500]).await.unwrap();
+    drop(sink);
+    server.await.unwrap();
+
+    let commands = commands(&log);
+    assert_eq!(vec![b'p', b'd', b'b'], commands[.. 3].to_vec());
+    assert_eq!(1, commands.iter().filter(|&&command| command == b'b').count());
+    let sent = points_sent(&log);
+    assert!(sent.iter().all(|&count| count > 0), "{:?}", sent);
+    assert_eq!(2500, sent.iter().sum::<usize>());
+  }
+
+  #[tokio::test]
+  async fn test_oversized_write_rejected() {
+    let (mut dac, log, server) = mock_dac(status(1, 0)).await;
+    let points = vec![Point::xy_blank(0, 0); u16::MAX as usize + 1];
+    match dac.write_points(&points).await {
+      Err(EtherdreamError::BatchTooLarge { .. }) => {},
+      other => panic!("{:?}", other.map(|_| ())),
+    }
+    drop(dac);
+    server.await.unwrap();
+    assert!(commands(&log).is_empty());
+  }
+}
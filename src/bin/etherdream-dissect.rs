@@ -0,0 +1,187 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Decodes captured EtherDream traffic.
+//!
+//! Reads a pcap capture, or with `--commands`, `--responses` or
+//! `--broadcast` a raw byte stream in one direction, and prints each decoded
+//! message. Messages that can't be decoded are flagged as malformed.
+
+extern crate etherdream;
+
+use etherdream::dissect::Command;
+use etherdream::dissect::Dissected;
+use etherdream::dissect::Message;
+use etherdream::dissect::decode_broadcast;
+use etherdream::dissect::decode_commands;
+use etherdream::dissect::decode_responses;
+use etherdream::dissect::dissect;
+use etherdream::dissect::read_pcap;
+use etherdream::protocol::CONTROL_RATE_CHANGE;
+use etherdream::protocol::DacStatus;
+use std::env;
+use std::fs;
+use std::process;
+
+const USAGE : &str = "\
+Usage: etherdream-dissect [OPTIONS] FILE
+
+Decodes EtherDream traffic from a pcap capture.
+
+Options:
+  --commands     FILE is a raw stream of commands sent to the DAC
+  --responses    FILE is a raw stream of responses from the DAC
+  --broadcast    FILE is a single raw broadcast datagram
+  --points       Print every point of data commands
+  -h, --help     Print this help";
+
+enum Input {
+  Pcap,
+  Commands,
+  Responses,
+  Broadcast,
+}
+
+fn main() {
+  let mut input = Input::Pcap;
+  let mut show_points = false;
+  let mut path = None;
+
+  for arg in env::args().skip(1) {
+    match arg.as_str() {
+      "--commands" => input = Input::Commands,
+      "--responses" => input = Input::Responses,
+      "--broadcast" => input = Input::Broadcast,
+      "--points" => show_points = true,
+      "-h" | "--help" => {
+        println!("{}", USAGE);
+        return;
+      },
+      _ if arg.starts_with('-') || path.is_some() => fail(&format!(
+          "unexpected argument: {}", arg)),
+      _ => path = Some(arg),
+    }
+  }
+
+  let path = path.unwrap_or_else(|| fail("no file given"));
+  let bytes = fs::read(&path)
+      .unwrap_or_else(|e| fail(&format!("could not read {}: {}", path, e)));
+
+  let mut malformed = 0;
+  let mut print = |prefix: String, message: &Message| {
+    if let Message::Malformed { .. } = *message {
+      malformed += 1;
+    }
+    println!("{}{}", prefix, describe(message, show_points));
+  };
+
+  match input {
+    Input::Pcap => {
+      let packets = read_pcap(&bytes).unwrap_or_else(|e| fail(&format!(
+          "could not read capture: {}", e)));
+      for Dissected { time, source, destination, message } in dissect(&packets) {
+        let prefix = format!("{}.{:06} {} -> {}  ", time.as_secs(),
+            time.subsec_micros(), source, destination);
+        print(prefix, &message);
+      }
+    },
+    Input::Commands => {
+      let (messages, used) = decode_commands(&bytes);
+      for message in messages.iter() {
+        print(String::new(), message);
+      }
+      if used < bytes.len() {
+        print(String::new(), &Message::Malformed {
+          reason: "incomplete command at end of stream".to_string(),
+          bytes: bytes[used ..].to_vec(),
+        });
+      }
+    },
+    Input::Responses => {
+      let (messages, used) = decode_responses(&bytes);
+      for message in messages.iter() {
+        print(String::new(), message);
+      }
+      if used < bytes.len() {
+        print(String::new(), &Message::Malformed {
+          reason: "incomplete response at end of stream".to_string(),
+          bytes: bytes[used ..].to_vec(),
+        });
+      }
+    },
+    Input::Broadcast => print(String::new(), &decode_broadcast(&bytes)),
+  }
+
+  if malformed > 0 {
+    eprintln!("{} malformed message(s)", malformed);
+    process::exit(1);
+  }
+}
+
+fn describe(message: &Message, show_points: bool) -> String {
+  match *message {
+    Message::Command(ref command) => match *command {
+      Command::Begin(ref begin) => format!("begin: rate {}, low water mark {}",
+          begin.point_rate, begin.low_water_mark),
+      Command::ClearEmergencyStop => "clear emergency stop".to_string(),
+      Command::Data(ref points) => {
+        let rate_changes = points.iter()
+            .filter(|p| p.control & CONTROL_RATE_CHANGE != 0)
+            .count();
+        let mut out = format!("data: {} points", points.len());
+        if rate_changes > 0 {
+          out.push_str(&format!(", {} rate change(s)", rate_changes));
+        }
+        if show_points {
+          for p in points.iter() {
+            out.push_str(&format!("\n    ({}, {}) rgb {} {} {} i {} control {:#06x}",
+                p.x, p.y, p.r, p.g, p.b, p.i, p.control));
+          }
+        }
+        out
+      },
+      Command::EmergencyStop => "emergency stop".to_string(),
+      Command::Ping => "ping".to_string(),
+      Command::Prepare => "prepare".to_string(),
+      Command::QueueRateChange(ref change) => {
+        format!("queue rate change: {}", change.point_rate)
+      },
+      Command::Stop => "stop".to_string(),
+    },
+    Message::Response(ref response) => format!("response to {:?}: {:?}, {}",
+        response.command, response.acknowledgement,
+        describe_status(&response.status)),
+    Message::Broadcast(ref broadcast) => {
+      let mac : Vec<String> = broadcast.mac_address.address.iter()
+          .map(|b| format!("{:02x}", b))
+          .collect();
+      format!("broadcast: mac {}, hw {}, sw {}, capacity {}, max rate {}, {}",
+          mac.join(":"), broadcast.hw_revision, broadcast.sw_revision,
+          broadcast.buffer_capacity, broadcast.max_point_rate,
+          describe_status(&broadcast.status))
+    },
+    Message::Malformed { ref reason, ref bytes } => {
+      let hex : Vec<String> = bytes.iter().take(32).map(|b| format!("{:02x}", b))
+          .collect();
+      let more = if bytes.len() > 32 { " ..." } else { "" };
+      format!("MALFORMED: {} [{}{}]", reason, hex.join(" "), more)
+    },
+  }
+}
+
+fn describe_status(status: &DacStatus) -> String {
+  let playback = match status.playback_state {
+    0 => "idle",
+    1 => "prepared",
+    2 => "playing",
+    _ => "unknown",
+  };
+  format!("{}, buffer {}, rate {}, played {}, flags {:#x}/{:#x}", playback,
+      status.buffer_fullness, status.point_rate, status.point_count,
+      status.light_engine_flags, status.playback_flags)
+}
+
+fn fail(message: &str) -> ! {
+  eprintln!("etherdream-dissect: {}\n\n{}", message, USAGE);
+  process::exit(2);
+}
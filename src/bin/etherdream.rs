@@ -0,0 +1,526 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Command-line tool for finding, inspecting and testing EtherDream DACs.
+
+extern crate etherdream;
+
+use etherdream::EtherdreamError;
+use etherdream::dac::DEFAULT_POINT_RATE;
+use etherdream::dac::Dac;
+use etherdream::frame::FrameStreamer;
+use etherdream::handle::DacHandle;
+use etherdream::ilda_file::IldaFile;
+use etherdream::ilda_file::IldaPlayer;
+use etherdream::ilda_file::PlayMode;
+use etherdream::network::SearchResult;
+use etherdream::network::find_dacs;
+use etherdream::network::find_first_dac;
+use etherdream::patterns::Pattern;
+use etherdream::patterns::PatternSettings;
+use etherdream::preview::TerminalPreview;
+use etherdream::protocol::DacResponse;
+use etherdream::protocol::DacStatus;
+use etherdream::protocol::Point;
+use etherdream::render::Renderer;
+use etherdream::simulate::GalvoModel;
+use etherdream::simulate::Report;
+use etherdream::simulate::Simulator;
+use std::env;
+use std::io;
+use std::net::IpAddr;
+use std::process;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const USAGE : &str = "\
+Usage: etherdream [--dac ADDRESS] [--json] COMMAND [OPTIONS]
+
+Commands:
+  discover                List the DACs on the network
+      --timeout SECONDS   How long to listen for broadcasts (default 2)
+  status                  Poll the status of a DAC
+      --interval MS       Time between polls (default 500)
+      --count N           Stop after N polls
+  estop                   Put a DAC into emergency stop
+  clear-estop             Clear a DAC's emergency stop
+  play FILE.ild           Play an ILDA file
+      --frame-rate FPS    Frames per second (default 30)
+      --point-rate PPS    Points per second (default 30000)
+      --once              Play once instead of looping
+      --duration SECONDS  Stop after this long
+      --preview           Show the output in the terminal
+  test-pattern NAME       Project a test pattern: ilda, crosshair, grid,
+                          color-bars, bounding-box or scan-ladder
+      --size FRACTION     Share of the output range covered (default 0.8)
+      --color RRGGBB      Color as hex (default ffffff)
+      --point-rate PPS    Points per second (default 30000)
+      --duration SECONDS  Stop after this long
+      --preview           Show the output in the terminal
+  render SOURCE           Render a test pattern name or ILDA file to PNG,
+                          without a DAC
+      --output FILE.png   Where to write the image
+      --width PIXELS      Image width (default 800)
+      --height PIXELS     Image height (default 800)
+      --frame N           Frame of an ILDA file to render (default 0)
+      --show-blanked      Draw blanked moves as dim lines
+      --glow              Add a glow around lines
+      --galvo HZ          Simulate scanners of this bandwidth at the point
+                          rate, and report overshoot and flicker
+      Test patterns also take --size, --color and --point-rate.
+
+Options:
+  --dac ADDRESS   IP address of the DAC; defaults to the first one found
+  --json          Print machine-readable JSON
+  -h, --help      Print this help";
+
+/// Parsed command line.
+struct Args {
+  dac: Option<IpAddr>,
+  json: bool,
+  command: String,
+  operand: Option<String>,
+  pattern: Option<Pattern>,
+  timeout: f64,
+  interval: u64,
+  count: Option<u64>,
+  frame_rate: f64,
+  point_rate: u32,
+  size: f64,
+  color: (u16, u16, u16),
+  once: bool,
+  duration: Option<f64>,
+  output: Option<String>,
+  width: u32,
+  height: u32,
+  frame: usize,
+  show_blanked: bool,
+  glow: bool,
+  galvo: Option<f64>,
+  preview: bool,
+}
+
+fn main() {
+  let args = parse_args();
+  if let Err(e) = run(&args) {
+    if args.json {
+      println!("{{\"error\":{}}}", json_string(&e.to_string()));
+    } else {
+      eprintln!("etherdream: {}", e);
+    }
+    process::exit(1);
+  }
+}
+
+fn run(args: &Args) -> Result<(), EtherdreamError> {
+  match args.command.as_str() {
+    "discover" => discover(args),
+    "status" => status(args),
+    "estop" => {
+      let response = connect(args)?.emergency_stop()?;
+      print_response(args, "emergency stop", &response);
+      Ok(())
+    },
+    "clear-estop" => {
+      let response = connect(args)?.clear_emergency_stop()?;
+      print_response(args, "emergency stop cleared", &response);
+      Ok(())
+    },
+    "play" => play(args),
+    "test-pattern" => test_pattern(args),
+    "render" => render(args),
+    _ => unreachable!(),
+  }
+}
+
+fn discover(args: &Args) -> Result<(), EtherdreamError> {
+  let results = find_dacs(Duration::from_millis((args.timeout * 1000.0) as u64))?;
+
+  if args.json {
+    let entries : Vec<String> = results.iter().map(search_result_json).collect();
+    println!("[{}]", entries.join(","));
+    return Ok(());
+  }
+
+  if results.is_empty() {
+    println!("No DACs found.");
+  }
+  for result in results.iter() {
+    let broadcast = &result.broadcast;
+    println!("{}  {}  hw {}  sw {}  buffer {}  max rate {}",
+        mac_string(&broadcast.mac_address.address), result.ip_address,
+        broadcast.hw_revision, broadcast.sw_revision,
+        broadcast.buffer_capacity, broadcast.max_point_rate);
+  }
+  Ok(())
+}
+
+fn status(args: &Args) -> Result<(), EtherdreamError> {
+  let mut dac = connect(args)?;
+  let mut polls = 0;
+  loop {
+    let response = dac.ping()?;
+    if args.json {
+      println!("{}", status_json(&response.status));
+    } else {
+      println!("{}", status_string(&response.status));
+    }
+
+    polls += 1;
+    if args.count.is_some_and(|count| polls >= count) {
+      return Ok(());
+    }
+    thread::sleep(Duration::from_millis(args.interval));
+  }
+}
+
+fn play(args: &Args) -> Result<(), EtherdreamError> {
+  let path = args.operand.as_ref().unwrap();
+  let file = IldaFile::load(path)?;
+
+  let mut player = IldaPlayer::new(file);
+  player.set_frame_rate(args.frame_rate);
+  player.set_point_rate(args.point_rate);
+  if args.once {
+    player.set_mode(PlayMode::Once);
+  }
+
+  let mut dac = connect(args)?;
+  add_preview(args, &mut dac)?;
+  let handle = player.spawn(dac)?;
+  finish(args, handle)
+}
+
+fn test_pattern(args: &Args) -> Result<(), EtherdreamError> {
+  let frame = args.pattern.unwrap().frame(&pattern_settings(args));
+
+  let mut dac = connect(args)?;
+  dac.set_point_rate(args.point_rate);
+  add_preview(args, &mut dac)?;
+
+  let streamer = FrameStreamer::new();
+  streamer.submit(frame);
+  let handle = streamer.spawn(dac)?;
+  finish(args, handle)
+}
+
+/// Wait for a stream to end, stopping it once the duration is up or on
+/// Ctrl-C. Stopping sends the DAC a stop command, so the lasers don't stay
+/// on the way they would if the process just exited. A second Ctrl-C exits
+/// straight away.
+fn finish(args: &Args, handle: DacHandle) -> Result<(), EtherdreamError> {
+  let (interrupted, interrupts) = mpsc::channel();
+  let interrupted_handle = handle.clone();
+  let mut presses = 0;
+  ctrlc::set_handler(move || {
+    presses += 1;
+    if presses > 1 {
+      process::exit(130);
+    }
+    interrupted_handle.stop();
+    let _ = interrupted.send(());
+  }).map_err(io::Error::other)?;
+
+  if let Some(duration) = args.duration {
+    let duration = Duration::from_millis((duration * 1000.0) as u64);
+    let _ = interrupts.recv_timeout(duration);
+    handle.stop();
+  }
+  handle.join()?;
+  print_done(args);
+  Ok(())
+}
+
+fn render(args: &Args) -> Result<(), EtherdreamError> {
+  let source = args.operand.as_ref().unwrap();
+  let points = match Pattern::from_name(source) {
+    Some(pattern) => pattern.frame(&pattern_settings(args)),
+    None => {
+      let mut file = IldaFile::load(source)?;
+      if args.frame >= file.frames.len() {
+        fail(&format!("{} has {} frames", source, file.frames.len()));
+      }
+      file.frames.swap_remove(args.frame).points
+    },
+  };
+
+  let mut renderer = Renderer::new(args.width, args.height);
+  let mut report = None;
+  let points = match args.galvo {
+    Some(bandwidth) => {
+      let model = GalvoModel::SecondOrder { bandwidth, damping: 0.7 };
+      let mut simulator = Simulator::new(model, args.point_rate);
+      report = Some(simulator.measure(&points));
+      let exposure = renderer.get_exposure() / simulator.get_substeps() as f64;
+      renderer = renderer.with_exposure(exposure);
+      simulator.simulate(&points)
+    },
+    None => points,
+  };
+  if args.show_blanked {
+    renderer = renderer.with_blanked(0.15);
+  }
+  if args.glow {
+    renderer = renderer.with_glow(args.width / 200 + 1, 0.5);
+  }
+  renderer.render(&points);
+
+  let output = args.output.as_ref().unwrap();
+  renderer.image().save_png(output)?;
+  print_rendered(args, output, &points, report.as_ref());
+  Ok(())
+}
+
+fn print_rendered(args: &Args, output: &str, points: &[Point],
+    report: Option<&Report>) {
+  if args.json {
+    let report = match report {
+      Some(report) => format!(",\"frame_rate\":{:.2},\"flicker\":{},\
+          \"max_overshoot\":{:.1},\"max_error\":{:.1},\"mean_error\":{:.1}",
+          report.frame_rate, report.flicker, report.max_overshoot,
+          report.max_error, report.mean_error),
+      None => String::new(),
+    };
+    println!("{{\"output\":{},\"points\":{}{}}}", json_string(output),
+        points.len(), report);
+    return;
+  }
+
+  println!("Rendered {} points to {}", points.len(), output);
+  if let Some(report) = report {
+    println!("{:.1} frames per second{}, overshoot {:.0}, error {:.0} max, \
+        {:.0} mean", report.frame_rate,
+        if report.flicker { " (flickers)" } else { "" }, report.max_overshoot,
+        report.max_error, report.mean_error);
+  }
+}
+
+fn pattern_settings(args: &Args) -> PatternSettings {
+  PatternSettings {
+    size: args.size,
+    color: args.color,
+    point_rate: args.point_rate,
+  }
+}
+
+/// Connect to the DAC given on the command line, or the first one found.
+fn connect(args: &Args) -> Result<Dac, EtherdreamError> {
+  let ip_address = match args.dac {
+    Some(ip_address) => ip_address,
+    None => find_first_dac()?.ip_address,
+  };
+  Dac::connect(ip_address)
+}
+
+/// Show what's streamed in the terminal, if asked to.
+fn add_preview(args: &Args, dac: &mut Dac) -> Result<(), EtherdreamError> {
+  if args.preview {
+    dac.set_preview(Some(TerminalPreview::new(80, 24).start(io::stdout())?));
+  }
+  Ok(())
+}
+
+fn print_response(args: &Args, message: &str, response: &DacResponse) {
+  if args.json {
+    println!("{}", status_json(&response.status));
+  } else {
+    println!("{}: {}", message, status_string(&response.status));
+  }
+}
+
+fn print_done(args: &Args) {
+  if args.json {
+    println!("{{\"ok\":true}}");
+  }
+}
+
+fn mac_string(address: &[u8; 6]) -> String {
+  let bytes : Vec<String> = address.iter().map(|b| format!("{:02x}", b)).collect();
+  bytes.join(":")
+}
+
+fn playback_state(status: &DacStatus) -> &'static str {
+  match status.playback_state {
+    0 => "idle",
+    1 => "prepared",
+    2 => "playing",
+    _ => "unknown",
+  }
+}
+
+fn light_engine_state(status: &DacStatus) -> &'static str {
+  match status.light_engine_state {
+    0 => "ready",
+    1 => "warmup",
+    2 => "cooldown",
+    3 => "emergency stop",
+    _ => "unknown",
+  }
+}
+
+fn status_string(status: &DacStatus) -> String {
+  format!("light engine {}, playback {}, buffer {}, rate {}, played {}, \
+      flags {:#x}/{:#x}", light_engine_state(status), playback_state(status),
+      status.buffer_fullness, status.point_rate, status.point_count,
+      status.light_engine_flags, status.playback_flags)
+}
+
+fn status_json(status: &DacStatus) -> String {
+  format!("{{\"protocol\":{},\"light_engine_state\":{},\"playback_state\":{},\
+      \"source\":{},\"light_engine_flags\":{},\"playback_flags\":{},\
+      \"source_flags\":{},\"buffer_fullness\":{},\"point_rate\":{},\
+      \"point_count\":{}}}", status.protocol,
+      json_string(light_engine_state(status)), json_string(playback_state(status)),
+      status.source, status.light_engine_flags, status.playback_flags,
+      status.source_flags, status.buffer_fullness, status.point_rate,
+      status.point_count)
+}
+
+fn search_result_json(result: &SearchResult) -> String {
+  let broadcast = &result.broadcast;
+  format!("{{\"mac_address\":{},\"ip_address\":{},\"hw_revision\":{},\
+      \"sw_revision\":{},\"buffer_capacity\":{},\"max_point_rate\":{},\
+      \"status\":{}}}", json_string(&mac_string(&broadcast.mac_address.address)),
+      json_string(&result.ip_address.to_string()), broadcast.hw_revision,
+      broadcast.sw_revision, broadcast.buffer_capacity, broadcast.max_point_rate,
+      status_json(&broadcast.status))
+}
+
+fn json_string(value: &str) -> String {
+  let mut out = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+fn parse_args() -> Args {
+  let mut args = Args {
+    dac: None,
+    json: false,
+    command: String::new(),
+    operand: None,
+    pattern: None,
+    timeout: 2.0,
+    interval: 500,
+    count: None,
+    frame_rate: 30.0,
+    point_rate: DEFAULT_POINT_RATE,
+    size: PatternSettings::default().size,
+    color: PatternSettings::default().color,
+    once: false,
+    duration: None,
+    output: None,
+    width: 800,
+    height: 800,
+    frame: 0,
+    show_blanked: false,
+    glow: false,
+    galvo: None,
+    preview: false,
+  };
+
+  let mut argv = env::args().skip(1);
+  while let Some(arg) = argv.next() {
+    let mut value = |name: &str| argv.next()
+        .unwrap_or_else(|| fail(&format!("{} needs a value", name)));
+    match arg.as_str() {
+      "-h" | "--help" => {
+        println!("{}", USAGE);
+        process::exit(0);
+      },
+      "--dac" => args.dac = Some(number(&arg, &value(&arg))),
+      "--json" => args.json = true,
+      "--timeout" => args.timeout = number(&arg, &value(&arg)),
+      "--interval" => args.interval = number(&arg, &value(&arg)),
+      "--count" => args.count = Some(number(&arg, &value(&arg))),
+      "--frame-rate" => args.frame_rate = number(&arg, &value(&arg)),
+      "--point-rate" => args.point_rate = number(&arg, &value(&arg)),
+      "--size" => args.size = number(&arg, &value(&arg)),
+      "--color" => args.color = color(&value(&arg)),
+      "--once" => args.once = true,
+      "--duration" => args.duration = Some(number(&arg, &value(&arg))),
+      "--output" => args.output = Some(value(&arg)),
+      "--width" => args.width = number(&arg, &value(&arg)),
+      "--height" => args.height = number(&arg, &value(&arg)),
+      "--frame" => args.frame = number(&arg, &value(&arg)),
+      "--show-blanked" => args.show_blanked = true,
+      "--glow" => args.glow = true,
+      "--galvo" => args.galvo = Some(positive(&arg, &value(&arg))),
+      "--preview" => args.preview = true,
+      _ if arg.starts_with('-') => fail(&format!("unknown option: {}", arg)),
+      _ if args.command.is_empty() => args.command = arg,
+      _ if args.operand.is_none() => args.operand = Some(arg),
+      _ => fail(&format!("unexpected argument: {}", arg)),
+    }
+  }
+
+  match args.command.as_str() {
+    "" => fail("no command given"),
+    "discover" | "status" | "estop" | "clear-estop" => {
+      if args.operand.is_some() {
+        fail(&format!("{} takes no arguments", args.command));
+      }
+    },
+    "play" => {
+      if args.operand.is_none() {
+        fail("play needs a file");
+      }
+    },
+    "test-pattern" => {
+      args.pattern = args.operand.as_ref().and_then(|name| Pattern::from_name(name));
+      if args.pattern.is_none() {
+        let names : Vec<&str> = Pattern::ALL.iter().map(|p| p.name()).collect();
+        fail(&format!("test-pattern needs one of: {}", names.join(", ")));
+      }
+    },
+    "render" => {
+      if args.operand.is_none() || args.output.is_none() {
+        fail("render needs a source and --output");
+      }
+    },
+    command => fail(&format!("unknown command: {}", command)),
+  }
+
+  args
+}
+
+fn number<T: FromStr>(option: &str, value: &str) -> T {
+  value.parse().unwrap_or_else(|_| fail(&format!("bad value for {}: {}",
+      option, value)))
+}
+
+/// A number that must be positive and finite, such as a bandwidth.
+fn positive(option: &str, value: &str) -> f64 {
+  let number : f64 = number(option, value);
+  if !number.is_finite() || number <= 0.0 {
+    fail(&format!("{} must be a positive number: {}", option, value));
+  }
+  number
+}
+
+/// Parse an `RRGGBB` hex color into 16-bit channels.
+fn color(value: &str) -> (u16, u16, u16) {
+  let channel = |i: usize| value.get(i .. i + 2)
+      .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+      .map(|c| c as u16 * 257)
+      .unwrap_or_else(|| fail(&format!("bad color: {}", value)));
+  if value.len() != 6 {
+    fail(&format!("bad color: {}", value));
+  }
+  (channel(0), channel(2), channel(4))
+}
+
+fn fail(message: &str) -> ! {
+  eprintln!("etherdream: {}\n\n{}", message, USAGE);
+  process::exit(2);
+}
@@ -0,0 +1,536 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Color processing applied to points as they're sent to the DAC.
+
+use crate::error::EtherdreamError;
+use crate::filter::PointFilter;
+use crate::filter::blanked;
+use crate::filter::is_lit;
+use crate::protocol::COLOR_MAX;
+use crate::protocol::Point;
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+
+/// A color channel of a point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+  Red,
+  Green,
+  Blue,
+  Intensity,
+}
+
+impl Channel {
+  /// Every channel, in the order they're stored.
+  pub const ALL : [Channel; 4] =
+      [Channel::Red, Channel::Green, Channel::Blue, Channel::Intensity];
+
+  fn index(self) -> usize {
+    match self {
+      Channel::Red => 0,
+      Channel::Green => 1,
+      Channel::Blue => 2,
+      Channel::Intensity => 3,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      Channel::Red => "red",
+      Channel::Green => "green",
+      Channel::Blue => "blue",
+      Channel::Intensity => "intensity",
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Channel> {
+    Channel::ALL.iter().cloned().find(|channel| channel.name() == name)
+  }
+}
+
+/// Brightness and power limits applied to every point sent to the DAC.
+/// Each channel is scaled by the master level and its own scale, then capped.
+/// Caps apply last, so they hold no matter what the source sends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorLevels {
+  master: f64,
+  scales: [f64; 4],
+  caps: [u16; 4],
+}
+
+impl Default for ColorLevels {
+  fn default() -> ColorLevels {
+    ColorLevels {
+      master: 1.0,
+      scales: [1.0; 4],
+      caps: [COLOR_MAX; 4],
+    }
+  }
+}
+
+impl ColorLevels {
+  /// The master brightness, from 0.0 to 1.0.
+  pub fn get_master(&self) -> f64 {
+    self.master
+  }
+
+  /// Set the master brightness. Clamped to 0.0 through 1.0.
+  pub fn set_master(&mut self, master: f64) {
+    self.master = master.clamp(0.0, 1.0);
+  }
+
+  /// The scale applied to a channel, from 0.0 to 1.0.
+  pub fn get_scale(&self, channel: Channel) -> f64 {
+    self.scales[channel.index()]
+  }
+
+  /// Set the scale applied to a channel. Clamped to 0.0 through 1.0.
+  pub fn set_scale(&mut self, channel: Channel, scale: f64) {
+    self.scales[channel.index()] = scale.clamp(0.0, 1.0);
+  }
+
+  /// The highest value a channel may be sent.
+  pub fn get_cap(&self, channel: Channel) -> u16 {
+    self.caps[channel.index()]
+  }
+
+  /// Set the highest value a channel may be sent.
+  pub fn set_cap(&mut self, channel: Channel, cap: u16) {
+    self.caps[channel.index()] = cap;
+  }
+
+  /// Whether the levels leave every point unchanged.
+  pub fn is_identity(&self) -> bool {
+    *self == ColorLevels::default()
+  }
+
+  /// A copy of the point at these levels.
+  pub fn apply(&self, point: &Point) -> Point {
+    let level = |channel: Channel, value: u16| {
+      let i = channel.index();
+      let scaled = (value as f64 * self.master * self.scales[i]).round() as u16;
+      scaled.min(self.caps[i])
+    };
+
+    Point {
+      r: level(Channel::Red, point.r),
+      g: level(Channel::Green, point.g),
+      b: level(Channel::Blue, point.b),
+      i: level(Channel::Intensity, point.i),
+      ..*point
+    }
+  }
+}
+
+/// How one channel's brightness levels map to the values sent to the DAC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelCalibration {
+  /// The exponent applied to brightness levels. 1.0 is linear.
+  pub gamma: f64,
+  /// The lowest value sent for a level above zero. Set this to the diode's
+  /// lasing threshold so dim colors don't vanish.
+  pub threshold: u16,
+  /// Brightness curve sampled evenly from level 0.0 to 1.0, interpolated
+  /// between entries. Overrides `gamma` when set.
+  pub table: Option<Vec<u16>>,
+}
+
+impl Default for ChannelCalibration {
+  fn default() -> ChannelCalibration {
+    ChannelCalibration {
+      gamma: 1.0,
+      threshold: 0,
+      table: None,
+    }
+  }
+}
+
+impl ChannelCalibration {
+  /// The value sent to the DAC for a brightness level from 0.0 to 1.0.
+  pub fn convert(&self, level: f64) -> u16 {
+    if level <= 0.0 {
+      return 0;
+    }
+
+    let level = level.min(1.0);
+    let curve = match self.table {
+      Some(ref table) if table.len() > 1 => {
+        let position = level * (table.len() - 1) as f64;
+        let i = (position.floor() as usize).min(table.len() - 2);
+        let t = position - i as f64;
+        let value = table[i] as f64 + (table[i + 1] as f64 - table[i] as f64) * t;
+        value / COLOR_MAX as f64
+      },
+      Some(ref table) if table.len() == 1 => table[0] as f64 / COLOR_MAX as f64,
+      _ => level.powf(self.gamma),
+    };
+
+    let threshold = self.threshold as f64;
+    let value = threshold + (COLOR_MAX as f64 - threshold) * curve;
+    value.round().max(threshold).min(COLOR_MAX as f64) as u16
+  }
+
+  /// The value sent to the DAC for an 8-bit color.
+  pub fn convert_u8(&self, value: u8) -> u16 {
+    self.convert(value as f64 / 255.0)
+  }
+
+  /// The value sent to the DAC for a full-range 16-bit color.
+  pub fn convert_u16(&self, value: u16) -> u16 {
+    self.convert(value as f64 / COLOR_MAX as f64)
+  }
+}
+
+/// Calibration for every channel of a projector. The default is linear, so
+/// 8-bit colors map evenly onto the full 16-bit range.
+///
+/// Profiles are saved as plain text, one `channel.setting = value` per line:
+///
+/// ```text
+/// red.gamma = 2.2
+/// red.threshold = 4000
+/// green.table = 0 12000 30000 65535
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColorCalibration {
+  channels: [ChannelCalibration; 4],
+}
+
+impl ColorCalibration {
+  /// The calibration for a channel.
+  pub fn get(&self, channel: Channel) -> &ChannelCalibration {
+    &self.channels[channel.index()]
+  }
+
+  /// The calibration for a channel, for modification.
+  pub fn get_mut(&mut self, channel: Channel) -> &mut ChannelCalibration {
+    &mut self.channels[channel.index()]
+  }
+
+  /// Replace the calibration for a channel.
+  pub fn set(&mut self, channel: Channel, calibration: ChannelCalibration) {
+    self.channels[channel.index()] = calibration;
+  }
+
+  /// Convert an 8-bit color to calibrated DAC values.
+  pub fn convert_rgb(&self, r: u8, g: u8, b: u8) -> (u16, u16, u16) {
+    (self.get(Channel::Red).convert_u8(r),
+        self.get(Channel::Green).convert_u8(g),
+        self.get(Channel::Blue).convert_u8(b))
+  }
+
+  /// A copy of the point with calibration applied to its full-range colors.
+  pub fn apply(&self, point: &Point) -> Point {
+    Point {
+      r: self.get(Channel::Red).convert_u16(point.r),
+      g: self.get(Channel::Green).convert_u16(point.g),
+      b: self.get(Channel::Blue).convert_u16(point.b),
+      i: self.get(Channel::Intensity).convert_u16(point.i),
+      ..*point
+    }
+  }
+
+  /// Read a profile from a file.
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<ColorCalibration, EtherdreamError> {
+    ColorCalibration::parse(&fs::read_to_string(path)?)
+  }
+
+  /// Write the profile to a file.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EtherdreamError> {
+    fs::write(path, self.to_profile_string())?;
+    Ok(())
+  }
+
+  /// Parse a profile. Blank lines and lines starting with `#` are ignored, and
+  /// channels that aren't mentioned keep the default calibration.
+  pub fn parse(profile: &str) -> Result<ColorCalibration, EtherdreamError> {
+    let mut calibration = ColorCalibration::default();
+
+    for (number, line) in profile.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      let bad = |reason: &str| EtherdreamError::BadCalibration {
+        description: format!("line {}: {}", number + 1, reason),
+      };
+
+      let (key, value) = match line.find('=') {
+        Some(i) => (line[.. i].trim(), line[i + 1 ..].trim()),
+        None => return Err(bad("expected `channel.setting = value`")),
+      };
+
+      let (channel, setting) = match key.find('.') {
+        Some(i) => (&key[.. i], &key[i + 1 ..]),
+        None => return Err(bad("expected `channel.setting`")),
+      };
+
+      let channel = Channel::from_name(channel)
+          .ok_or_else(|| bad("unknown channel"))?;
+      let entry = calibration.get_mut(channel);
+
+      match setting {
+        "gamma" => {
+          let gamma : f64 = value.parse().map_err(|_| bad("bad gamma"))?;
+          if !gamma.is_finite() || gamma <= 0.0 {
+            return Err(bad("gamma must be a positive number"));
+          }
+          entry.gamma = gamma;
+        },
+        "threshold" => {
+          entry.threshold = value.parse().map_err(|_| bad("bad threshold"))?;
+        },
+        "table" => {
+          let table = value.split_whitespace()
+              .map(|v| v.parse())
+              .collect::<Result<Vec<u16>, _>>()
+              .map_err(|_| bad("bad table entry"))?;
+          entry.table = if table.is_empty() { None } else { Some(table) };
+        },
+        _ => return Err(bad("unknown setting")),
+      }
+    }
+
+    Ok(calibration)
+  }
+
+  /// The profile in the format read by `parse`.
+  pub fn to_profile_string(&self) -> String {
+    let mut out = String::new();
+
+    for channel in Channel::ALL.iter() {
+      let entry = self.get(*channel);
+      let name = channel.name();
+      let _ = writeln!(out, "{}.gamma = {}", name, entry.gamma);
+      let _ = writeln!(out, "{}.threshold = {}", name, entry.threshold);
+      if let Some(ref table) = entry.table {
+        let values : Vec<String> = table.iter().map(|v| v.to_string()).collect();
+        let _ = writeln!(out, "{}.table = {}", name, values.join(" "));
+      }
+    }
+
+    out
+  }
+}
+
+/// Calibrates points that already carry 16-bit color, such as those from
+/// `Dac::play_function`.
+impl PointFilter for ColorCalibration {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    for point in points.iter_mut() {
+      *point = self.apply(point);
+    }
+  }
+}
+
+/// Delays the color channels relative to the galvo position, to make up for
+/// the lasers responding faster than the galvos. Blanking can also be
+/// extended so the lasers go dark a few points before each blanked move,
+/// which cuts the tails left at blanking transitions.
+///
+/// Set it with `Dac::set_color_shift` to apply it to every stream, after
+/// filters and before safety zones. Used as a filter of its own, add it after
+/// any filters that change colors but before `SafetyZones`, or it can carry
+/// light into a forbidden zone. Output lags the source by the blanking
+/// extension, in points.
+pub struct ColorShift {
+  delay: usize,
+  blank_extension: usize,
+  history: VecDeque<Point>,
+  started: bool,
+}
+
+impl ColorShift {
+  /// CTOR. Each point takes the color of the point `delay` points before it.
+  pub fn new(delay: usize) -> ColorShift {
+    ColorShift {
+      delay,
+      blank_extension: 0,
+      history: VecDeque::new(),
+      started: false,
+    }
+  }
+
+  /// Also blank this many points ahead of every blanked point.
+  pub fn with_blank_extension(mut self, points: usize) -> ColorShift {
+    self.blank_extension = points;
+    self
+  }
+
+  /// How many points color lags position.
+  pub fn get_delay(&self) -> usize {
+    self.delay
+  }
+
+  /// How many points early blanking begins.
+  pub fn get_blank_extension(&self) -> usize {
+    self.blank_extension
+  }
+}
+
+impl PointFilter for ColorShift {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    let window = self.delay + self.blank_extension + 1;
+    let mut out = Vec::with_capacity(points.len());
+
+    for point in points.drain(..) {
+      if !self.started {
+        // Nothing was lit before the stream started.
+        for _ in 0 .. self.delay {
+          self.history.push_back(blanked(&point));
+        }
+        self.started = true;
+      }
+
+      self.history.push_back(point);
+
+      if self.history.len() < window {
+        continue;
+      }
+
+      let color = self.history[0];
+      let position = self.history[self.delay];
+      let blank_ahead = (1 ..= self.blank_extension)
+          .any(|i| !is_lit(&self.history[i]));
+
+      let shifted = Point {
+        r: color.r,
+        g: color.g,
+        b: color.b,
+        i: color.i,
+        ..position
+      };
+
+      out.push(if blank_ahead { blanked(&shifted) } else { shifted });
+      self.history.pop_front();
+    }
+
+    *points = out;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_default_is_identity() {
+    let levels = ColorLevels::default();
+    let point = levels.apply(&Point::xy_rgb(1, 2, COLOR_MAX, 1000, 0));
+    assert!(levels.is_identity());
+    assert_eq!((COLOR_MAX, 1000, 0), (point.r, point.g, point.b));
+  }
+
+  #[test]
+  fn test_master_and_scale() {
+    let mut levels = ColorLevels::default();
+    levels.set_master(0.5);
+    levels.set_scale(Channel::Green, 0.5);
+
+    let point = levels.apply(&Point::xy_rgb(0, 0, 40000, 40000, 40000));
+    assert_eq!((20000, 10000, 20000), (point.r, point.g, point.b));
+  }
+
+  #[test]
+  fn test_cap_holds_at_color_max() {
+    let mut levels = ColorLevels::default();
+    levels.set_cap(Channel::Blue, 30000);
+    levels.set_master(2.0);
+
+    let point = levels.apply(&Point::xy_rgb(0, 0, COLOR_MAX, COLOR_MAX, COLOR_MAX));
+    assert_eq!((COLOR_MAX, COLOR_MAX, 30000), (point.r, point.g, point.b));
+  }
+
+  #[test]
+  fn test_default_calibration_matches_expand() {
+    let calibration = ColorCalibration::default();
+    for value in 0 ..= 255u8 {
+      assert_eq!(value as u16 * 257, calibration.get(Channel::Red).convert_u8(value));
+    }
+  }
+
+  #[test]
+  fn test_threshold_and_gamma() {
+    let channel = ChannelCalibration { gamma: 2.0, threshold: 5000, table: None };
+    assert_eq!(0, channel.convert_u8(0));
+    assert_eq!(5000, channel.convert(0.0001));
+    assert_eq!(5000 + (60535.0 * 0.25f64).round() as u16, channel.convert(0.5));
+    assert_eq!(COLOR_MAX, channel.convert_u8(255));
+  }
+
+  #[test]
+  fn test_table_interpolates() {
+    let channel = ChannelCalibration {
+      gamma: 1.0,
+      threshold: 0,
+      table: Some(vec![0, 10000, COLOR_MAX]),
+    };
+    assert_eq!(5000, channel.convert(0.25));
+    assert_eq!(10000, channel.convert(0.5));
+    assert_eq!(COLOR_MAX, channel.convert(1.0));
+  }
+
+  #[test]
+  fn test_profile_round_trip() {
+    let mut calibration = ColorCalibration::default();
+    calibration.get_mut(Channel::Red).gamma = 2.2;
+    calibration.get_mut(Channel::Green).threshold = 4000;
+    calibration.get_mut(Channel::Blue).table = Some(vec![0, 30000, COLOR_MAX]);
+
+    let parsed = ColorCalibration::parse(&calibration.to_profile_string()).unwrap();
+    assert_eq!(calibration, parsed);
+  }
+
+  #[test]
+  fn test_profile_errors() {
+    assert!(ColorCalibration::parse("# comment\n\nred.gamma = 2").is_ok());
+    assert!(ColorCalibration::parse("purple.gamma = 2").is_err());
+    assert!(ColorCalibration::parse("red.gamma 2").is_err());
+    assert!(ColorCalibration::parse("red.threshold = -1").is_err());
+    assert!(ColorCalibration::parse("red.gamma = NaN").is_err());
+    assert!(ColorCalibration::parse("red.gamma = inf").is_err());
+    assert!(ColorCalibration::parse("red.gamma = -2.2").is_err());
+    assert!(ColorCalibration::parse("red.gamma = 0").is_err());
+  }
+
+  fn lit(x: i16) -> Point {
+    Point::xy_luma(x, 0, COLOR_MAX)
+  }
+
+  #[test]
+  fn test_color_shift_delays_color() {
+    let mut shift = ColorShift::new(2);
+    let mut points = vec![lit(0), lit(1), Point::xy_blank(2, 0), lit(3), lit(4)];
+
+    shift.filter(&mut points);
+
+    let xs : Vec<i16> = points.iter().map(|p| p.x).collect();
+    let lit : Vec<bool> = points.iter().map(is_lit).collect();
+    assert_eq!(vec![0, 1, 2, 3, 4], xs);
+    assert_eq!(vec![false, false, true, true, false], lit);
+  }
+
+  #[test]
+  fn test_color_shift_extends_blanking() {
+    let mut shift = ColorShift::new(0).with_blank_extension(1);
+    let mut points = vec![lit(0), lit(1), Point::xy_blank(2, 0)];
+    shift.filter(&mut points);
+
+    // Output lags by one point, and blanking starts one point early.
+    assert_eq!(2, points.len());
+    assert!(is_lit(&points[0]));
+    assert!(!is_lit(&points[1]));
+
+    let mut points = vec![lit(3), lit(4)];
+    shift.filter(&mut points);
+
+    let xs : Vec<i16> = points.iter().map(|p| p.x).collect();
+    assert_eq!(vec![2, 3], xs);
+    assert!(!is_lit(&points[0]));
+    assert!(is_lit(&points[1]));
+  }
+}
@@ -0,0 +1,67 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! The connection to a DAC, shared between threads.
+
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::time::Duration;
+
+/// The TCP connection to a DAC. The stream loop, the watchdog and shutdown
+/// guards all send on it, so each holds the lock for a whole command and its
+/// response. Commands never interleave, and nobody reads a response meant for
+/// someone else.
+#[derive(Clone, Debug)]
+pub(crate) struct Connection {
+  stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Connection {
+  /// CTOR.
+  pub fn new(stream: TcpStream) -> Connection {
+    Connection {
+      stream: Arc::new(Mutex::new(stream)),
+    }
+  }
+
+  /// Take the connection for one exchange. A thread that panicked mid-command
+  /// can't be trusted to have left the connection in sync, but a shutdown is
+  /// still worth attempting, so poisoning is ignored.
+  pub fn lock(&self) -> MutexGuard<'_, TcpStream> {
+    self.stream.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Send a one-byte command and read its response.
+  pub fn exchange(&self, command: u8) -> std::io::Result<[u8; 22]> {
+    let mut stream = self.lock();
+    stream.write_all(&[command])?;
+    let mut buf = [0; 22];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+  }
+
+  /// Send a one-byte command and read its response, waiting no longer than
+  /// `timeout` on the network. The connection's own timeouts are put back
+  /// afterwards.
+  pub fn exchange_with_timeout(&self, command: u8, timeout: Duration)
+      -> std::io::Result<[u8; 22]> {
+    let mut stream = self.lock();
+    let read_timeout = stream.read_timeout()?;
+    let write_timeout = stream.write_timeout()?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut buf = [0; 22];
+    let result = stream.write_all(&[command])
+        .and_then(|_| stream.read_exact(&mut buf));
+
+    stream.set_read_timeout(read_timeout)?;
+    stream.set_write_timeout(write_timeout)?;
+    result.map(|_| buf)
+  }
+}
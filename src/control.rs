@@ -0,0 +1,250 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Controls for a stream that work from any thread, such as blackout, pause,
+//! brightness and geometry.
+
+use crate::color::Channel;
+use crate::color::ColorLevels;
+use crate::geometry::Keystone;
+use crate::geometry::Transform;
+use crate::protocol::DacStatus;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+/// Toggles blackout and pause on a `Dac` and adjusts its brightness and
+/// geometry while it streams.
+/// Cheap to clone and may be shared between threads.
+#[derive(Clone, Debug)]
+pub struct OutputControl {
+  control: Arc<StreamControl>,
+}
+
+impl OutputControl {
+  pub(crate) fn new(control: Arc<StreamControl>) -> OutputControl {
+    OutputControl { control }
+  }
+
+  /// Turn the lasers off while the galvos keep tracking the source. Color
+  /// channels are zeroed as points are serialized.
+  pub fn set_blackout(&self, blackout: bool) {
+    self.control.set_blackout(blackout);
+  }
+
+  /// Whether output is blacked out.
+  pub fn is_blackout(&self) -> bool {
+    self.control.is_blackout()
+  }
+
+  /// Freeze output. The source isn't asked for points while paused; blank
+  /// points are sent at the park position instead.
+  pub fn set_paused(&self, paused: bool) {
+    self.control.set_paused(paused);
+  }
+
+  /// Whether output is paused.
+  pub fn is_paused(&self) -> bool {
+    self.control.is_paused()
+  }
+
+  /// Where the beam parks while paused. `None` holds the last position.
+  pub fn set_park_position(&self, position: Option<(i16, i16)>) {
+    self.control.set_park_position(position);
+  }
+
+  /// Where the beam parks while paused. `None` holds the last position.
+  pub fn get_park_position(&self) -> Option<(i16, i16)> {
+    self.control.park_position()
+  }
+
+  /// The brightness and power limits applied to every point.
+  pub fn get_color_levels(&self) -> ColorLevels {
+    self.control.color_levels()
+  }
+
+  /// Replace the brightness and power limits applied to every point.
+  pub fn set_color_levels(&self, levels: ColorLevels) {
+    self.control.set_color_levels(levels);
+  }
+
+  /// Set the master brightness, from 0.0 to 1.0.
+  pub fn set_master_brightness(&self, master: f64) {
+    self.control.update_color_levels(|levels| levels.set_master(master));
+  }
+
+  /// Set the scale applied to one channel, from 0.0 to 1.0.
+  pub fn set_channel_scale(&self, channel: Channel, scale: f64) {
+    self.control.update_color_levels(|levels| levels.set_scale(channel, scale));
+  }
+
+  /// Set the highest value one channel may be sent.
+  pub fn set_channel_cap(&self, channel: Channel, cap: u16) {
+    self.control.update_color_levels(|levels| levels.set_cap(channel, cap));
+  }
+
+  /// The transform applied to every point.
+  pub fn get_transform(&self) -> Transform {
+    self.control.transform()
+  }
+
+  /// Replace the transform applied to every point.
+  pub fn set_transform(&self, transform: Transform) {
+    self.control.set_transform(transform);
+  }
+
+  /// The keystone correction applied to every point.
+  pub fn get_keystone(&self) -> Keystone {
+    self.control.keystone()
+  }
+
+  /// The point rate points are being sent at. A change requested through
+  /// `DacHandle::set_point_rate` shows up here once it's queued to the DAC.
+  pub fn get_point_rate(&self) -> u32 {
+    self.control.current_point_rate()
+  }
+
+  /// Replace the keystone correction applied to every point.
+  pub fn set_keystone(&self, keystone: Keystone) {
+    self.control.set_keystone(keystone);
+  }
+}
+
+/// Requests and settings checked by the stream loop before each write.
+#[derive(Debug, Default)]
+pub(crate) struct StreamControl {
+  stop: AtomicBool,
+  emergency_stop: AtomicBool,
+  shutdown: AtomicBool,
+  paused: AtomicBool,
+  blackout: AtomicBool,
+  park_position: Mutex<Option<(i16, i16)>>,
+  color_levels: Mutex<ColorLevels>,
+  transform: Mutex<Transform>,
+  keystone: Mutex<Keystone>,
+  point_rate: Mutex<Option<u32>>,
+  current_point_rate: AtomicU32,
+  status: Mutex<Option<DacStatus>>,
+}
+
+impl StreamControl {
+  /// Ask the stream to stop playback and return.
+  pub fn request_stop(&self) {
+    self.stop.store(true, Ordering::SeqCst);
+  }
+
+  /// Consume a pending stop request.
+  pub fn take_stop(&self) -> bool {
+    self.stop.swap(false, Ordering::SeqCst)
+  }
+
+  /// Tell the stream the DAC is being emergency stopped, so it should
+  /// return.
+  pub fn request_emergency_stop(&self) {
+    self.emergency_stop.store(true, Ordering::SeqCst);
+  }
+
+  /// Whether the DAC is being emergency stopped.
+  pub fn is_emergency_stopped(&self) -> bool {
+    self.emergency_stop.load(Ordering::SeqCst)
+  }
+
+  /// Consume a pending emergency stop.
+  pub fn take_emergency_stop(&self) -> bool {
+    self.emergency_stop.swap(false, Ordering::SeqCst)
+  }
+
+  /// Ask the stream to shut down according to the DAC's shutdown mode and
+  /// return.
+  pub fn request_shutdown(&self) {
+    self.shutdown.store(true, Ordering::SeqCst);
+  }
+
+  /// Consume a pending shutdown request.
+  pub fn take_shutdown(&self) -> bool {
+    self.shutdown.swap(false, Ordering::SeqCst)
+  }
+
+  pub fn set_paused(&self, paused: bool) {
+    self.paused.store(paused, Ordering::SeqCst);
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused.load(Ordering::SeqCst)
+  }
+
+  pub fn set_park_position(&self, position: Option<(i16, i16)>) {
+    *self.park_position.lock().unwrap() = position;
+  }
+
+  pub fn park_position(&self) -> Option<(i16, i16)> {
+    *self.park_position.lock().unwrap()
+  }
+
+  pub fn set_blackout(&self, blackout: bool) {
+    self.blackout.store(blackout, Ordering::SeqCst);
+  }
+
+  pub fn is_blackout(&self) -> bool {
+    self.blackout.load(Ordering::SeqCst)
+  }
+
+  pub fn color_levels(&self) -> ColorLevels {
+    *self.color_levels.lock().unwrap()
+  }
+
+  pub fn set_color_levels(&self, levels: ColorLevels) {
+    *self.color_levels.lock().unwrap() = levels;
+  }
+
+  pub fn update_color_levels<F>(&self, update: F)
+      where F: FnOnce(&mut ColorLevels) {
+    update(&mut self.color_levels.lock().unwrap());
+  }
+
+  pub fn transform(&self) -> Transform {
+    *self.transform.lock().unwrap()
+  }
+
+  pub fn set_transform(&self, transform: Transform) {
+    *self.transform.lock().unwrap() = transform;
+  }
+
+  pub fn keystone(&self) -> Keystone {
+    *self.keystone.lock().unwrap()
+  }
+
+  pub fn set_keystone(&self, keystone: Keystone) {
+    *self.keystone.lock().unwrap() = keystone;
+  }
+
+  /// Ask the stream to switch to a new point rate.
+  pub fn request_point_rate(&self, point_rate: u32) {
+    *self.point_rate.lock().unwrap() = Some(point_rate);
+  }
+
+  /// Consume a pending point rate change.
+  pub fn take_point_rate(&self) -> Option<u32> {
+    self.point_rate.lock().unwrap().take()
+  }
+
+  /// The point rate points are being sent at.
+  pub fn current_point_rate(&self) -> u32 {
+    self.current_point_rate.load(Ordering::SeqCst)
+  }
+
+  pub fn set_current_point_rate(&self, point_rate: u32) {
+    self.current_point_rate.store(point_rate, Ordering::SeqCst);
+  }
+
+  /// The most recent status reported by the DAC.
+  pub fn status(&self) -> Option<DacStatus> {
+    *self.status.lock().unwrap()
+  }
+
+  pub fn set_status(&self, status: DacStatus) {
+    *self.status.lock().unwrap() = Some(status);
+  }
+}
@@ -2,43 +2,111 @@
 
 //! This module contains the EtherDream hardware interface.
 
-use byteorder::LittleEndian;
-use byteorder::WriteBytesExt;
-use error::EtherdreamError;
-use point::PipelinePoint;
-use point::SimplePoint;
-use protocol::Begin;
-use protocol::COMMAND_PING;
-use protocol::COMMAND_PREPARE;
-use protocol::CommandCode;
-use protocol::DacResponse;
-use protocol::Point;
+use crate::color::ColorCalibration;
+use crate::color::ColorLevels;
+use crate::color::ColorShift;
+use crate::connection::Connection;
+use crate::control::OutputControl;
+use crate::control::StreamControl;
+use crate::error::EtherdreamError;
+use crate::filter::PointFilter;
+use crate::filter::blanked;
+use crate::geometry::Keystone;
+use crate::geometry::Transform;
+use crate::handle::DacHandle;
+use crate::network::COMMUNICATION_PORT;
+use crate::point::PipelinePoint;
+use crate::point::SimplePoint;
+use crate::preview::PreviewTap;
+use crate::protocol::Begin;
+use crate::protocol::COMMAND_CLEAR_EMERGENCY_STOP;
+use crate::protocol::COMMAND_EMERGENCY_STOP;
+use crate::protocol::COMMAND_PING;
+use crate::protocol::COMMAND_PREPARE;
+use crate::protocol::COMMAND_STOP;
+use crate::protocol::CONTROL_RATE_CHANGE;
+use crate::protocol::CommandCode;
+use crate::protocol::DacResponse;
+use crate::protocol::Data;
+use crate::protocol::Point;
+use crate::protocol::QueueRateChange;
+use crate::record::Recorder;
+use crate::safety::SafetyZones;
+use crate::shutdown::ShutdownGuard;
+use crate::shutdown::ShutdownMode;
+use crate::shutdown::send_shutdown;
+use crate::watchdog::Watchdog;
 use std::io::Read;
 use std::io::Write;
 use std::net::IpAddr;
 use std::net::TcpStream;
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
+
+/// The number of points the DAC can hold in its buffer.
+pub(crate) const BUFFER_CAPACITY : u16 = 1799;
+
+/// The point rate used if none is configured.
+pub const DEFAULT_POINT_RATE : u32 = 30_000;
 
 /// An EtherDream DAC.
 /// Controls what we display on the projector.
 pub struct Dac {
   ip_address: IpAddr,
-  stream: TcpStream,
+  connection: Connection,
+  point_rate: u32,
+  greeted: bool,
+  streaming: bool,
+  shutdown_mode: ShutdownMode,
+  watchdog: Option<Duration>,
+  calibration: ColorCalibration,
+  recorder: Option<Recorder>,
+  filters: Vec<Box<dyn PointFilter>>,
+  color_shift: Option<ColorShift>,
+  safety_zones: Option<SafetyZones>,
+  preview: Option<PreviewTap>,
+  control: Arc<StreamControl>,
 }
 
 impl Dac {
   /// CTOR.
   pub fn new(ip_address: IpAddr) -> Dac {
-    let stream = TcpStream::connect((ip_address, 7765u16)).unwrap(); // FIXME
+    Dac::connect(ip_address).unwrap() // FIXME
+  }
 
-    // These should be reasonable timeouts for any arbitrary laser show.
-    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap(); // FIXME
-    stream.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+  /// Connect to the DAC at the address.
+  pub fn connect(ip_address: IpAddr) -> Result<Dac, EtherdreamError> {
+    let stream = TcpStream::connect((ip_address, COMMUNICATION_PORT))?;
+    Dac::from_stream(ip_address, stream)
+  }
 
-    Dac {
-      ip_address: ip_address,
-      stream: stream,
-    }
+  /// Take over a connection to a DAC.
+  fn from_stream(ip_address: IpAddr, stream: TcpStream)
+      -> Result<Dac, EtherdreamError> {
+    // These should be reasonable timeouts for any arbitrary laser show.
+    stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(500)))?;
+
+    let control = Arc::new(StreamControl::default());
+    control.set_current_point_rate(DEFAULT_POINT_RATE);
+
+    Ok(Dac {
+      ip_address,
+      connection: Connection::new(stream),
+      point_rate: DEFAULT_POINT_RATE,
+      greeted: false,
+      streaming: false,
+      shutdown_mode: ShutdownMode::default(),
+      watchdog: None,
+      calibration: ColorCalibration::default(),
+      recorder: None,
+      filters: Vec::new(),
+      color_shift: None,
+      safety_zones: None,
+      preview: None,
+      control,
+    })
   }
 
   /// IP address the DAC lives at.
@@ -46,165 +114,446 @@ impl Dac {
     &self.ip_address
   }
 
-  /// Stream points generated by a function.
-  /// The function takes the number of points it needs to generate.
-  pub fn play_function<F>(&mut self, mut make_points: F)
-      -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<Point> {
-    // Read initial hello message from DAC.
-    let mut response = self.read_response()?;
+  /// The point rate playback begins at.
+  pub fn get_point_rate(&self) -> u32 {
+    self.point_rate
+  }
 
-    self.try_prepare(response);
+  /// Set the point rate playback begins at.
+  pub fn set_point_rate(&mut self, point_rate: u32) {
+    self.point_rate = point_rate;
+    self.control.set_current_point_rate(point_rate);
+  }
 
-    let mut started = false;
+  /// Shared state for controlling streams from other threads.
+  pub(crate) fn control(&self) -> Arc<StreamControl> {
+    self.control.clone()
+  }
 
-    loop {
-      let num_points = 1799 - response.status.buffer_fullness;
-      let points = make_points(num_points);
+  /// The connection, for sending commands from other threads.
+  pub(crate) fn connection(&self) -> Connection {
+    self.connection.clone()
+  }
 
-      let mut cmd : Vec<u8> = Vec::new();
-      cmd.push(0x64); // 'data' command.
-      cmd.write_u16::<LittleEndian>(num_points)?;
+  /// A switch for blackout and pause that works from any thread, including
+  /// while this DAC is streaming.
+  pub fn output_control(&self) -> OutputControl {
+    OutputControl::new(self.control.clone())
+  }
 
-      for point in points {
-        cmd.extend(point.serialize());
-      }
+  /// Turn the lasers off while the galvos keep tracking the source.
+  pub fn set_blackout(&self, blackout: bool) {
+    self.control.set_blackout(blackout);
+  }
 
-      response = self.write_serialized_points(&cmd)?;
+  /// Whether output is blacked out.
+  pub fn is_blackout(&self) -> bool {
+    self.control.is_blackout()
+  }
 
-      if !started {
-        response = self.begin()?;
-        started = true;
-      }
-    }
+  /// Freeze output, sending blank points at the park position.
+  pub fn set_paused(&self, paused: bool) {
+    self.control.set_paused(paused);
   }
 
-  /// Stream points generated by a function.
-  /// The function takes the number of points it needs to generate.
-  pub fn stream_pipeline_points<F>(&mut self, mut make_points: F)
-      -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<PipelinePoint> {
-    // Read initial hello message from DAC.
-    let mut response = self.read_response()?;
+  /// Whether output is paused.
+  pub fn is_paused(&self) -> bool {
+    self.control.is_paused()
+  }
 
-    self.try_prepare(response);
+  /// Where the beam parks while paused. `None` holds the last position.
+  pub fn set_park_position(&self, position: Option<(i16, i16)>) {
+    self.control.set_park_position(position);
+  }
 
-    let mut started = false;
+  /// The brightness and power limits applied to every point.
+  pub fn get_color_levels(&self) -> ColorLevels {
+    self.control.color_levels()
+  }
 
-    loop {
-      let num_points = 1799 - response.status.buffer_fullness;
-      let points = make_points(num_points);
-
-      let mut cmd : Vec<u8> = Vec::new();
-      cmd.push(0x64); // 'data' command.
-      cmd.write_u16::<LittleEndian>(num_points)?;
-
-      for point in points {
-        cmd.write_u16::<LittleEndian>(0)?; // Control
-        cmd.write_i16::<LittleEndian>(point.x as i16)?;
-        cmd.write_i16::<LittleEndian>(point.y as i16)?;
-
-        if point.is_blank {
-          cmd.write_u16::<LittleEndian>(0)?;
-          cmd.write_u16::<LittleEndian>(0)?;
-          cmd.write_u16::<LittleEndian>(0)?;
-        } else {
-          // TODO/FIXME:
-          //cmd.write_u16::<LittleEndian>(point.r as u16)?;
-          //cmd.write_u16::<LittleEndian>(point.g as u16)?;
-          //cmd.write_u16::<LittleEndian>(point.b as u16)?;
-          cmd.write_u16::<LittleEndian>(65535)?;
-          cmd.write_u16::<LittleEndian>(65535)?;
-          cmd.write_u16::<LittleEndian>(65535)?;
-        }
+  /// Replace the brightness and power limits applied to every point. Use
+  /// `output_control` to adjust them from another thread while streaming.
+  pub fn set_color_levels(&self, levels: ColorLevels) {
+    self.control.set_color_levels(levels);
+  }
 
-        cmd.write_u16::<LittleEndian>(0)?; // i
-        cmd.write_u16::<LittleEndian>(0)?; // u1
-        cmd.write_u16::<LittleEndian>(0)?; // u2
-      }
+  /// The transform applied to every point for how the projector is mounted.
+  pub fn get_transform(&self) -> Transform {
+    self.control.transform()
+  }
 
-      response = self.write_serialized_points(&cmd)?;
+  /// Set the transform applied to every point for how the projector is
+  /// mounted. Points are transformed before filters run, so filters such as
+  /// safety zones see final DAC coordinates. Use `output_control` to adjust
+  /// the transform from another thread while streaming.
+  pub fn set_transform(&self, transform: Transform) {
+    self.control.set_transform(transform);
+  }
 
-      if !started {
-        response = self.begin()?;
-        started = true;
-      }
+  /// The keystone correction applied to every point.
+  pub fn get_keystone(&self) -> Keystone {
+    self.control.keystone()
+  }
+
+  /// Set the keystone correction applied to every point. It applies after
+  /// the transform, and before filters run.
+  pub fn set_keystone(&self, keystone: Keystone) {
+    self.control.set_keystone(keystone);
+  }
+
+  /// What is sent to the DAC if it's dropped mid-stream.
+  pub fn get_shutdown_mode(&self) -> ShutdownMode {
+    self.shutdown_mode
+  }
+
+  /// Set what is sent to the DAC if it's dropped mid-stream, or if every
+  /// `DacHandle` to its stream is dropped. Defaults to `ShutdownMode::Stop`.
+  pub fn set_shutdown_mode(&mut self, shutdown_mode: ShutdownMode) {
+    self.shutdown_mode = shutdown_mode;
+  }
+
+  /// A guard that terminates output with the current shutdown mode when it's
+  /// dropped, even if that happens while unwinding from a panic. The guard
+  /// may be dropped on any thread, including while this DAC streams.
+  pub fn shutdown_guard(&self) -> ShutdownGuard {
+    ShutdownGuard::new(self.connection.clone(), self.shutdown_mode)
+  }
+
+  /// How long the point source may take to generate points.
+  pub fn get_watchdog(&self) -> Option<Duration> {
+    self.watchdog
+  }
+
+  /// Set how long the point source may take to generate points. If a call
+  /// runs past the deadline, the DAC is sent an emergency stop right away and
+  /// the stream ends with `EtherdreamError::SourceStalled` once the source
+  /// returns. `None` disables the watchdog, which is the default.
+  pub fn set_watchdog(&mut self, deadline: Option<Duration>) {
+    self.watchdog = deadline;
+  }
+
+  /// How 8-bit colors from `stream_simple_points` are converted to the
+  /// DAC's 16-bit channels.
+  pub fn get_calibration(&self) -> &ColorCalibration {
+    &self.calibration
+  }
+
+  /// Set how 8-bit colors from `stream_simple_points` are converted to the
+  /// DAC's 16-bit channels.
+  ///
+  /// Only `stream_simple_points` uses this. `play_function`,
+  /// `stream_pipeline_points` and `spawn_stream` send their 16-bit colors
+  /// uncalibrated; to calibrate those, add the calibration with `add_filter`
+  /// instead.
+  pub fn set_calibration(&mut self, calibration: ColorCalibration) {
+    self.calibration = calibration;
+  }
+
+  /// Record every command sent and response received from now on. The
+  /// watchdog, shutdown guards and `DacHandle::emergency_stop` send on the
+  /// same connection, but their commands and the responses to them aren't
+  /// recorded, so every recorded response follows its command. `None` stops
+  /// recording.
+  pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+    if let Some(mut recorder) = self.recorder.take() {
+      let _ = recorder.flush();
     }
+    self.recorder = recorder;
+  }
+
+  /// Add a filter to process points on their way to the DAC. Filters apply
+  /// to every stream, in the order they were added.
+  pub fn add_filter<F>(&mut self, filter: F) where F: PointFilter + 'static {
+    self.filters.push(Box::new(filter));
+  }
+
+  /// Remove every filter.
+  pub fn clear_filters(&mut self) {
+    self.filters.clear();
+  }
+
+  /// The color shift applied to every stream, if any.
+  pub fn get_color_shift(&self) -> Option<&ColorShift> {
+    self.color_shift.as_ref()
+  }
+
+  /// Delay colors relative to position on every stream. The shift applies
+  /// after filters and before safety zones, so it can't carry light into a
+  /// zone. `None` removes it.
+  pub fn set_color_shift(&mut self, color_shift: Option<ColorShift>) {
+    self.color_shift = color_shift;
+  }
+
+  /// The safety zones enforced on every stream, if any.
+  pub fn get_safety_zones(&self) -> Option<&SafetyZones> {
+    self.safety_zones.as_ref()
+  }
+
+  /// Enforce safety zones on every stream. They apply last, after filters
+  /// and the color shift, so nothing later can light a forbidden zone.
+  /// `None` removes them.
+  pub fn set_safety_zones(&mut self, safety_zones: Option<SafetyZones>) {
+    self.safety_zones = safety_zones;
+  }
+
+  /// Show every point sent to the DAC on a preview, exactly as sent: after
+  /// filters, the color shift and safety zones, blackout and color levels.
+  /// `None` stops previewing.
+  pub fn set_preview(&mut self, preview: Option<PreviewTap>) {
+    self.preview = preview;
+  }
+
+  /// Run a stream on its own thread. The returned handle can control the
+  /// stream from any thread.
+  pub fn spawn_stream<F>(self, make_points: F)
+      -> Result<DacHandle, EtherdreamError>
+      where F: FnMut(u16) -> Vec<Point> + Send + 'static {
+    DacHandle::spawn(self, Box::new(make_points))
+  }
+
+  /// Stream points generated by a function.
+  /// The function takes the number of points it needs to generate.
+  pub fn play_function<F>(&mut self, make_points: F)
+      -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<Point> {
+    self.stream_points(make_points)
+  }
+
+  /// Stream points generated by a function.
+  /// The function takes the number of points it needs to generate.
+  pub fn stream_pipeline_points<F>(&mut self, mut make_points: F)
+      -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<PipelinePoint> {
+    self.stream_points(|num_points| {
+      make_points(num_points).iter()
+          // TODO/FIXME: Use the point's color.
+          .map(|point| Point::xy_binary(point.x as i16, point.y as i16,
+              !point.is_blank))
+          .collect()
+    })
   }
 
   /// Stream points generated by a function.
   /// The function takes the number of points it needs to generate.
   pub fn stream_simple_points<F>(&mut self, mut make_points: F)
       -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<SimplePoint> {
-    // Read initial hello message from DAC.
-    let mut response = self.read_response()?;
-
-    self.try_prepare(response);
+    let calibration = self.calibration.clone();
+
+    self.stream_points(|num_points| {
+      make_points(num_points).iter()
+          .map(|point| {
+            if point.is_blank {
+              Point::xy_blank(point.x, point.y)
+            } else {
+              let (r, g, b) = calibration.convert_rgb(point.r, point.g, point.b);
+              Point::xy_rgb(point.x, point.y, r, g, b)
+            }
+          })
+          .collect()
+    })
+  }
 
-    let mut started = false;
+  /// The stream loop behind every streaming method. Runs until the stream is
+  /// stopped from a `DacHandle` or an error occurs.
+  fn stream_points<F>(&mut self, make_points: F)
+      -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<Point> {
+    let result = self.run_stream(make_points);
 
-    #[inline(always)]
-    fn expand(color: u8) -> u16 {
-      (color as u16) * 257
+    // A `DacHandle` sends its emergency stop straight away, so the DAC may
+    // refuse a command before the loop notices. That isn't a failure.
+    if self.control.take_emergency_stop() {
+      self.streaming = false;
+      return Ok(());
     }
+    result
+  }
+
+  fn run_stream<F>(&mut self, mut make_points: F)
+      -> Result<(), EtherdreamError> where F: FnMut(u16) -> Vec<Point> {
+    let mut response = self.ping()?;
+
+    self.try_prepare(response)?;
+    self.streaming = true;
+
+    let watchdog = match self.watchdog {
+      Some(deadline) => Some((Watchdog::start(self.connection.clone(), deadline)?,
+          deadline)),
+      None => None,
+    };
+
+    let mut started = false;
+    let mut last_point = Point::xy_blank(0, 0);
+    let mut pending = Vec::new();
 
     loop {
-      let num_points = 1799 - response.status.buffer_fullness;
-      let points = make_points(num_points);
-
-      let mut cmd : Vec<u8> = Vec::new();
-      cmd.push(0x64); // 'data' command.
-      cmd.write_u16::<LittleEndian>(num_points)?;
-
-      for point in points {
-        cmd.write_u16::<LittleEndian>(0)?; // Control
-        cmd.write_i16::<LittleEndian>(point.x)?;
-        cmd.write_i16::<LittleEndian>(point.y)?;
-        if point.is_blank {
-          cmd.write_u16::<LittleEndian>(0)?;
-          cmd.write_u16::<LittleEndian>(0)?;
-          cmd.write_u16::<LittleEndian>(0)?;
+      if self.control.is_emergency_stopped() {
+        return Ok(());
+      }
+
+      if self.control.take_stop() {
+        self.stop()?;
+        return Ok(());
+      }
+
+      if self.control.take_shutdown() {
+        return self.shutdown();
+      }
+
+      let num_points = BUFFER_CAPACITY
+          .saturating_sub(response.status.buffer_fullness) as usize;
+
+      if pending.len() < num_points {
+        let requested = (num_points - pending.len()) as u16;
+
+        let mut points = if self.control.is_paused() {
+          let (x, y) = self.control.park_position()
+              .unwrap_or((last_point.x, last_point.y));
+          vec![Point::xy_blank(x, y); requested as usize]
+        } else if let Some((ref watchdog, deadline)) = watchdog {
+          let started_at = Instant::now();
+          watchdog.arm();
+          let points = make_points(requested);
+          if watchdog.disarm() {
+            self.streaming = false;
+            return Err(EtherdreamError::SourceStalled {
+              deadline,
+              elapsed: started_at.elapsed(),
+            });
+          }
+          points
         } else {
-          cmd.write_u16::<LittleEndian>(expand(point.r))?;
-          cmd.write_u16::<LittleEndian>(expand(point.g))?;
-          cmd.write_u16::<LittleEndian>(expand(point.b))?;
+          make_points(requested)
+        };
+
+        if let Some(point) = points.last() {
+          last_point = *point;
+        }
+
+        let transform = self.control.transform();
+        if !transform.is_identity() {
+          for point in points.iter_mut() {
+            *point = transform.apply(point);
+          }
         }
-        cmd.write_u16::<LittleEndian>(0)?; // i
-        cmd.write_u16::<LittleEndian>(0)?; // u1
-        cmd.write_u16::<LittleEndian>(0)?; // u2
+
+        let keystone = self.control.keystone();
+        if !keystone.is_identity() {
+          for point in points.iter_mut() {
+            *point = keystone.apply(point);
+          }
+        }
+
+        for filter in self.filters.iter_mut() {
+          filter.filter(&mut points);
+        }
+
+        if let Some(ref mut color_shift) = self.color_shift {
+          color_shift.filter(&mut points);
+        }
+
+        if let Some(ref mut safety_zones) = self.safety_zones {
+          safety_zones.filter(&mut points);
+        }
+
+        pending.extend(points);
       }
 
-      response = self.write_serialized_points(&cmd)?;
+      // Filters may add points, so hold back whatever doesn't fit.
+      let count = num_points.min(pending.len());
+      let mut points : Vec<Point> = pending.drain(.. count).collect();
+
+      if !points.is_empty() {
+        if let Some(point_rate) = self.control.take_point_rate() {
+          self.point_rate = point_rate;
+          self.control.set_current_point_rate(point_rate);
+          if started {
+            self.queue_rate_change(point_rate)?;
+            points[0].control |= CONTROL_RATE_CHANGE;
+          }
+        }
+      }
+
+      response = self.write_points(&points)?;
 
       if !started {
         response = self.begin()?;
         started = true;
       }
+
+      self.control.set_status(response.status);
+    }
+  }
+
+  /// Ping the DAC for its current status.
+  pub fn ping(&mut self) -> Result<DacResponse, EtherdreamError> {
+    if !self.greeted {
+      // The DAC sends its status upon connection.
+      self.greeted = true;
+      return self.read_response();
     }
+    self.send(&[COMMAND_PING], CommandCode::Ping)
   }
 
-  fn hello(&mut self) -> Result<DacResponse, EtherdreamError> {
-    let _bytes = self.stream.write(&[COMMAND_PING])?;
-    self.read_response() // FIXME
+  /// Read the status the DAC sends upon connection, if nothing has yet.
+  fn greet(&mut self) -> Result<(), EtherdreamError> {
+    if !self.greeted {
+      self.greeted = true;
+      self.read_response()?;
+    }
+    Ok(())
   }
 
   fn prepare(&mut self) -> Result<DacResponse, EtherdreamError> {
-    let _bytes = self.stream.write(&[COMMAND_PREPARE])?;
-    self.read_expected_response(CommandCode::Prepare)
+    self.send(&[COMMAND_PREPARE], CommandCode::Prepare)
   }
 
   fn begin(&mut self) -> Result<DacResponse, EtherdreamError> {
-    let cmd = Begin { low_water_mark: 0, point_rate: 30_000 };
-    let _bytes = self.stream.write(&cmd.serialize())?;
-    self.read_expected_response(CommandCode::Begin)
+    let cmd = Begin { low_water_mark: 0, point_rate: self.point_rate };
+    self.send(&cmd.serialize(), CommandCode::Begin)
+  }
+
+  fn queue_rate_change(&mut self, point_rate: u32)
+      -> Result<DacResponse, EtherdreamError> {
+    let cmd = QueueRateChange { point_rate };
+    self.send(&cmd.serialize(), CommandCode::QueueRateChange)
+  }
+
+  /// Stop playback and return the DAC to the idle state.
+  fn stop(&mut self) -> Result<DacResponse, EtherdreamError> {
+    let response = self.send(&[COMMAND_STOP], CommandCode::Stop)?;
+    self.streaming = false;
+    Ok(response)
+  }
+
+  /// Put the DAC into the emergency stop state. Output stops until the state
+  /// is cleared.
+  pub fn emergency_stop(&mut self) -> Result<DacResponse, EtherdreamError> {
+    self.greet()?;
+    let response = self.send(&[COMMAND_EMERGENCY_STOP],
+        CommandCode::EmergencyStop)?;
+    self.streaming = false;
+    Ok(response)
+  }
+
+  /// Terminate output according to the shutdown mode.
+  fn shutdown(&mut self) -> Result<(), EtherdreamError> {
+    match self.shutdown_mode {
+      ShutdownMode::Nothing => {},
+      ShutdownMode::Stop => {
+        self.stop()?;
+      },
+      ShutdownMode::EmergencyStop => {
+        self.emergency_stop()?;
+      },
+    }
+    Ok(())
   }
 
   /// Clear emergency stop state.
-  fn clear_emergency_stop(&mut self) -> Result<DacResponse, EtherdreamError> {
-    let cmd = [ 0x63u8 ]; // 'c'
-    let _bytes = self.stream.write(&cmd)?;
-    self.read_response() // FIXME
+  pub fn clear_emergency_stop(&mut self) -> Result<DacResponse, EtherdreamError> {
+    self.greet()?;
+    self.exchange(&[COMMAND_CLEAR_EMERGENCY_STOP]) // FIXME
   }
 
-  fn try_prepare(&mut self, response: DacResponse) {
+  fn try_prepare(&mut self, response: DacResponse) -> Result<(), EtherdreamError> {
     // Documentation for playback_flags:
     // [0]: Emergency stop occurred due to E-Stop packet or invalid command.
     // [1]: Emergency stop occurred due to E-Stop input to projector.
@@ -216,52 +565,57 @@ impl Dac {
     let response = match response.status.playback_flags {
       0x1 | 0x2 | 0x4 | 0x6 => {
         // A previous E-Stop state must be cleared.
-        self.clear_emergency_stop().unwrap() // FIXME
+        self.clear_emergency_stop()?
       },
       _ => response,
     };
 
-    if response.status.playback_flags != 0x0 && response.status.playback_flags != 0x1 {
-      println!("\nBad playback flags, must PREPARE: {}", response.status.playback_flags);
-      println!("\nSend prepare");
-      let resp = self.prepare().unwrap();
-      println!("Response: {:?}", resp);
-      if !resp.is_ack() {
-        println!("Failure!");
-        panic!("Non-ACK received");
-      }
-      return;
-    }
+    // A stream that is still playing (eg. from a previous connection) must
+    // be stopped before it can be prepared again.
+    let response = match response.status.playback_state {
+      0x2 => self.stop()?,
+      _ => response,
+    };
 
-    if response.status.playback_state == 0x2 {
-      println!("\nBad playback_state, must PREPARE: {}", response.status.playback_state);
-      println!("\nSend prepare");
-      let resp = self.prepare().unwrap();
-      println!("Response: {:?}", resp);
-      if !resp.is_ack() {
-        println!("Failure!");
-        panic!("Non-ACK received");
-      }
+    let bad_flags = response.status.playback_flags != 0x0
+        && response.status.playback_flags != 0x1;
+
+    if bad_flags || response.status.playback_state == 0x0 {
+      self.prepare()?;
     }
-  }
 
-  // TODO:
-  // Sends (3 + 18*n) bytes.
-  // fn write_data(&mut self, num_points: u16) -> Result<DacResponse, EtherdreamError>
+    Ok(())
+  }
 
   /// Write a slice of points to the DAC.
-  fn write_serialized_points(&mut self, serialized_points: &[u8])
-                                 -> Result<DacResponse, EtherdreamError> {
-    self.stream.write(&serialized_points)?;
-    self.read_expected_response(CommandCode::Data)
+  /// Blackout and color levels are applied here, so they cover every point
+  /// sent, and the preview sees the points as sent.
+  fn write_points(&mut self, points: &[Point])
+      -> Result<DacResponse, EtherdreamError> {
+    let levels = self.control.color_levels();
+    let adjusted : Vec<Point>;
+    let points = if self.control.is_blackout() {
+      adjusted = points.iter().map(blanked).collect();
+      &adjusted
+    } else if !levels.is_identity() {
+      adjusted = points.iter().map(|p| levels.apply(p)).collect();
+      &adjusted
+    } else {
+      points
+    };
+    if let Some(ref preview) = self.preview {
+      preview.observe(points);
+    }
+    let bytes = Data { points }.serialize();
+    self.send(&bytes, CommandCode::Data)
   }
 
-  /// Read a response from the DAC, and parse error conditions.
-  /// Called after sending a command.
-  fn read_expected_response(&mut self, expected_command: CommandCode)
+  /// Send a command to the DAC, then read the response and parse error
+  /// conditions.
+  fn send(&mut self, bytes: &[u8], expected_command: CommandCode)
       -> Result<DacResponse, EtherdreamError> {
 
-    let response = self.read_response()?;
+    let response = self.exchange(bytes)?;
 
     if !response.acknowledgement.is_ack() {
       return Err(EtherdreamError::ReceivedNack {
@@ -277,9 +631,309 @@ impl Dac {
     Ok(response)
   }
 
+  /// Send a command and read the response to it. The connection is held
+  /// throughout, so shutdown guards and the watchdog can't get in between.
+  fn exchange(&mut self, bytes: &[u8]) -> Result<DacResponse, EtherdreamError> {
+    let connection = self.connection.clone();
+    let mut stream = connection.lock();
+    stream.write_all(bytes)?;
+    self.record_command(bytes);
+
+    let mut buf = [0; 22];
+    stream.read_exact(&mut buf)?;
+    drop(stream);
+    self.record_response(&buf);
+    DacResponse::parse(&buf)
+  }
+
+  /// Read the status the DAC sends upon connection.
   fn read_response(&mut self) -> Result<DacResponse, EtherdreamError> {
     let mut buf = [0; 22];
-    let _size = self.stream.read(&mut buf)?;
+    self.connection.lock().read_exact(&mut buf)?;
+    self.record_response(&buf);
     DacResponse::parse(&buf)
   }
+
+  fn record_command(&mut self, bytes: &[u8]) {
+    if let Some(ref mut recorder) = self.recorder {
+      if let Err(e) = recorder.record_command(bytes) {
+        warn!("Recording failed, so it was stopped: {}", e);
+        self.recorder = None;
+      }
+    }
+  }
+
+  fn record_response(&mut self, buf: &[u8]) {
+    if let Some(ref mut recorder) = self.recorder {
+      if let Err(e) = recorder.record_response(buf) {
+        warn!("Recording failed, so it was stopped: {}", e);
+        self.recorder = None;
+      }
+    }
+  }
+}
+
+impl Drop for Dac {
+  fn drop(&mut self) {
+    if self.streaming {
+      send_shutdown(&self.connection, self.shutdown_mode);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filter::is_lit;
+  use crate::protocol::DacStatus;
+  use crate::record::EventKind;
+  use crate::record::Recording;
+  use crate::safety::Zone;
+  use std::net::TcpListener;
+  use std::sync::Mutex;
+  use std::sync::mpsc;
+  use std::thread::JoinHandle;
+  use std::thread;
+
+  /// Commands the mock DAC answers.
+  const KNOWN_COMMANDS : &[u8] = b"?pbdqs\x00c";
+
+  /// A writer that can be inspected after the recorder owns it.
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn respond(server: &mut TcpStream, ack: u8, command: u8, status: &DacStatus) {
+    let mut response = vec![ack, command];
+    response.extend(status.serialize());
+    let _ = server.write_all(&response);
+  }
+
+  /// Connect to a mock DAC that plays along with the protocol, reading point
+  /// data slowly so writes stay in flight. Returns the commands it received
+  /// once the connection closes.
+  fn mock_dac() -> (Dac, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = TcpStream::connect(address).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    let thread = thread::spawn(move || {
+      let mut status = DacStatus::parse(&[0; 20]).unwrap();
+      let mut log = Vec::new();
+
+      respond(&mut server, b'a', COMMAND_PING, &status);
+
+      loop {
+        let mut command = [0; 1];
+        if server.read_exact(&mut command).is_err() {
+          return log;
+        }
+        let command = command[0];
+        log.push(command);
+
+        let mut ack = b'a';
+        match command {
+          b'd' => {
+            let mut count = [0; 2];
+            server.read_exact(&mut count).unwrap();
+            let count = u16::from_le_bytes(count) as usize;
+            let mut remaining = count * 18;
+            while remaining > 0 {
+              let mut chunk = vec![0; remaining.min(1800)];
+              server.read_exact(&mut chunk).unwrap();
+              remaining -= chunk.len();
+              thread::sleep(Duration::from_millis(1));
+            }
+            if status.playback_state == 0 {
+              ack = b'I';
+            } else {
+              status.buffer_fullness += count as u16;
+            }
+          },
+          b'b' => {
+            server.read_exact(&mut [0; 6]).unwrap();
+            status.playback_state = 2;
+          },
+          b'q' => server.read_exact(&mut [0; 4]).unwrap(),
+          b'p' => status.playback_state = 1,
+          b's' | 0x00 => {
+            status.playback_state = 0;
+            status.buffer_fullness = 0;
+          },
+          b'?' | b'c' => {},
+          _ => ack = b'I',
+        }
+        if status.playback_state == 2 {
+          status.buffer_fullness = status.buffer_fullness.saturating_sub(600);
+        }
+        respond(&mut server, ack, command, &status);
+      }
+    });
+
+    (Dac::from_stream(address.ip(), client).unwrap(), thread)
+  }
+
+  #[test]
+  fn test_guard_waits_for_command_in_flight() {
+    let (dac, server) = mock_dac();
+    let guard = dac.shutdown_guard();
+    let handle = dac.spawn_stream(|num_points| {
+      vec![Point::xy_blank(0, 0); num_points as usize]
+    }).unwrap();
+
+    thread::sleep(Duration::from_millis(60));
+    drop(guard);
+
+    // The stream reads the responses to its own commands, so it only fails
+    // once the DAC refuses points after the stop.
+    let result = handle.join();
+    assert!(matches!(result, Err(EtherdreamError::ReceivedNack { .. })),
+        "{:?}", result);
+    drop(handle);
+
+    let log = server.join().unwrap();
+    assert!(log.contains(&COMMAND_STOP));
+    assert!(log.iter().all(|command| KNOWN_COMMANDS.contains(command)),
+        "{:?}", log);
+  }
+
+  #[test]
+  fn test_handle_emergency_stop_while_source_stalls() {
+    let (dac, server) = mock_dac();
+    let (release, stalled) = mpsc::channel::<()>();
+    let mut calls = 0;
+    let handle = dac.spawn_stream(move |num_points| {
+      calls += 1;
+      if calls > 1 {
+        let _ = stalled.recv_timeout(Duration::from_secs(5));
+      }
+      vec![Point::xy_blank(0, 0); num_points as usize]
+    }).unwrap();
+
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    let response = handle.emergency_stop().unwrap();
+    assert_eq!(CommandCode::EmergencyStop, response.command);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    release.send(()).unwrap();
+    assert!(handle.join().is_ok());
+    drop(handle);
+    assert!(server.join().unwrap().contains(&COMMAND_EMERGENCY_STOP));
+  }
+
+  #[test]
+  fn test_set_source_after_source_panics() {
+    let (dac, server) = mock_dac();
+    let handle = dac.spawn_stream(|_| panic!("source failed")).unwrap();
+    assert!(matches!(handle.join(), Err(EtherdreamError::StreamPanicked)));
+
+    handle.set_source(|num_points| vec![Point::xy_blank(0, 0); num_points as usize]);
+    drop(handle);
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn test_recording_skips_watchdog() {
+    let buffer = SharedBuffer::default();
+    let (mut dac, server) = mock_dac();
+    dac.set_recorder(Some(Recorder::new(Box::new(buffer.clone())).unwrap()));
+    dac.set_watchdog(Some(Duration::from_millis(20)));
+
+    let mut calls = 0;
+    let result = dac.play_function(|num_points| {
+      calls += 1;
+      if calls > 1 {
+        thread::sleep(Duration::from_millis(100));
+      }
+      vec![Point::xy_blank(0, 0); num_points as usize]
+    });
+    assert!(matches!(result, Err(EtherdreamError::SourceStalled { .. })));
+    dac.set_recorder(None);
+    drop(dac);
+    assert!(server.join().unwrap().contains(&COMMAND_EMERGENCY_STOP));
+
+    // The greeting, then each command followed by its response.
+    let bytes = buffer.0.lock().unwrap().clone();
+    let events = Recording::parse(&bytes).unwrap().events;
+    assert_eq!(EventKind::Response, events[0].kind);
+    for pair in events[1 ..].chunks(2) {
+      assert_eq!(EventKind::Command, pair[0].kind);
+      assert_ne!(COMMAND_EMERGENCY_STOP, pair[0].bytes[0]);
+      assert_eq!(Some(pair[0].bytes[0]), pair.get(1).map(|event| event.bytes[1]));
+    }
+  }
+
+  #[test]
+  fn test_color_shift_cannot_light_safety_zones() {
+    let buffer = SharedBuffer::default();
+    let (mut dac, server) = mock_dac();
+    dac.set_recorder(Some(Recorder::new(Box::new(buffer.clone())).unwrap()));
+    dac.set_color_shift(Some(ColorShift::new(1)));
+    dac.set_safety_zones(Some(SafetyZones::new(vec![
+      Zone::forbidden(vec![(-100, -100), (100, -100), (100, 100), (-100, 100)]),
+    ])));
+
+    // Shifted after the zones, each point inside would take the color of the
+    // lit point before it.
+    let handle = dac.spawn_stream(|num_points| {
+      (0 .. num_points)
+          .map(|i| if i % 2 == 0 {
+            Point::xy_binary(1000, 1000, true)
+          } else {
+            Point::xy_binary(0, 0, false)
+          })
+          .collect()
+    }).unwrap();
+
+    thread::sleep(Duration::from_millis(30));
+    handle.stop();
+    let _ = handle.join();
+    drop(handle);
+    server.join().unwrap();
+
+    let bytes = buffer.0.lock().unwrap().clone();
+    let points : Vec<Point> = Recording::parse(&bytes).unwrap().events.iter()
+        .filter(|event| event.kind == EventKind::Command && event.bytes[0] == b'd')
+        .flat_map(|event| event.bytes[3 ..].chunks(18))
+        .map(|bytes| Point::parse(bytes).unwrap())
+        .collect();
+
+    assert!(points.iter().any(is_lit));
+    assert!(points.iter()
+        .filter(|point| point.x.abs() < 100 && point.y.abs() < 100)
+        .all(|point| !is_lit(point)));
+  }
+
+  #[test]
+  fn test_preview_sees_points_as_sent() {
+    let (mut dac, server) = mock_dac();
+    let (tap, receiver) = PreviewTap::channel();
+    dac.set_preview(Some(tap));
+    dac.set_blackout(true);
+
+    let handle = dac.spawn_stream(|num_points| {
+      vec![Point::xy_binary(1000, 1000, true); num_points as usize]
+    }).unwrap();
+
+    thread::sleep(Duration::from_millis(30));
+    handle.stop();
+    let _ = handle.join();
+    drop(handle);
+    server.join().unwrap();
+
+    let points : Vec<Point> = receiver.try_iter().flatten().collect();
+    assert!(!points.is_empty());
+    assert!(points.iter().all(|point| !is_lit(point)));
+  }
 }
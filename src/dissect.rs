@@ -0,0 +1,596 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Decoding captured EtherDream traffic. Reads pcap captures, reassembles
+//! the TCP streams to and from port 7765, and decodes commands, responses
+//! and UDP broadcasts from port 7654 with the `protocol` parsers.
+
+use crate::error::EtherdreamError;
+use crate::network::BROADCAST_PORT;
+use crate::network::COMMUNICATION_PORT;
+use crate::protocol::AckCode;
+use crate::protocol::Begin;
+use crate::protocol::Broadcast;
+use crate::protocol::COMMAND_BEGIN;
+use crate::protocol::COMMAND_CLEAR_EMERGENCY_STOP;
+use crate::protocol::COMMAND_DATA;
+use crate::protocol::COMMAND_EMERGENCY_STOP;
+use crate::protocol::COMMAND_PING;
+use crate::protocol::COMMAND_PREPARE;
+use crate::protocol::COMMAND_QUEUE_RATE_CHANGE;
+use crate::protocol::COMMAND_STOP;
+use crate::protocol::CommandCode;
+use crate::protocol::DacResponse;
+use crate::protocol::Point;
+use crate::protocol::QueueRateChange;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A command sent to the DAC.
+#[derive(Clone, Debug)]
+pub enum Command {
+  Begin(Begin),
+  ClearEmergencyStop,
+  Data(Vec<Point>),
+  EmergencyStop,
+  Ping,
+  Prepare,
+  QueueRateChange(QueueRateChange),
+  Stop,
+}
+
+/// A decoded message.
+#[derive(Clone, Debug)]
+pub enum Message {
+  /// A command sent to the DAC.
+  Command(Command),
+  /// A response from the DAC.
+  Response(DacResponse),
+  /// A UDP broadcast from the DAC.
+  Broadcast(Broadcast),
+  /// Bytes that couldn't be decoded.
+  Malformed {
+    /// Why decoding failed.
+    reason: String,
+    /// The bytes skipped.
+    bytes: Vec<u8>,
+  },
+}
+
+impl Message {
+  fn malformed(reason: &str, bytes: &[u8]) -> Message {
+    Message::Malformed { reason: reason.to_string(), bytes: bytes.to_vec() }
+  }
+}
+
+/// Decode as many commands as possible from the start of a stream sent to
+/// the DAC. Returns the messages and the number of bytes used; the rest are
+/// an incomplete command. Unknown command bytes are reported as malformed
+/// and skipped one at a time until decoding finds its footing again.
+pub fn decode_commands(bytes: &[u8]) -> (Vec<Message>, usize) {
+  let mut messages = Vec::new();
+  let mut offset = 0;
+
+  while offset < bytes.len() {
+    let rest = &bytes[offset ..];
+    let (message, len) = match rest[0] {
+      COMMAND_BEGIN => match Begin::parse(rest) {
+        Ok(begin) => (Message::Command(Command::Begin(begin)), 7),
+        Err(_) => break,
+      },
+      COMMAND_QUEUE_RATE_CHANGE => match QueueRateChange::parse(rest) {
+        Ok(change) => (Message::Command(Command::QueueRateChange(change)), 5),
+        Err(_) => break,
+      },
+      COMMAND_DATA => {
+        if rest.len() < 3 {
+          break;
+        }
+        let count = (rest[1] as usize) | (rest[2] as usize) << 8;
+        let len = 3 + 18 * count;
+        if rest.len() < len {
+          break;
+        }
+        let points = rest[3 .. len].chunks(18)
+            .filter_map(|chunk| Point::parse(chunk).ok())
+            .collect();
+        (Message::Command(Command::Data(points)), len)
+      },
+      COMMAND_PING => (Message::Command(Command::Ping), 1),
+      COMMAND_PREPARE => (Message::Command(Command::Prepare), 1),
+      COMMAND_STOP => (Message::Command(Command::Stop), 1),
+      COMMAND_CLEAR_EMERGENCY_STOP => {
+        (Message::Command(Command::ClearEmergencyStop), 1)
+      },
+      COMMAND_EMERGENCY_STOP | 0xFF => (Message::Command(Command::EmergencyStop), 1),
+      _ => (Message::malformed("unknown command", &rest[.. 1]), 1),
+    };
+
+    messages.push(message);
+    offset += len;
+  }
+
+  (messages, offset)
+}
+
+/// Decode as many responses as possible from the start of a stream sent by
+/// the DAC. Returns the messages and the number of bytes used. Responses
+/// with unknown acknowledgement or command codes are reported as malformed.
+pub fn decode_responses(bytes: &[u8]) -> (Vec<Message>, usize) {
+  let mut messages = Vec::new();
+
+  for chunk in bytes.chunks_exact(22) {
+    let message = match DacResponse::parse(chunk) {
+      Ok(response) => match (response.acknowledgement, response.command) {
+        (AckCode::NackUnknown { .. }, _) => {
+          Message::malformed("unknown acknowledgement", chunk)
+        },
+        (_, CommandCode::CommandUnknown { .. }) => {
+          Message::malformed("response to unknown command", chunk)
+        },
+        _ => Message::Response(response),
+      },
+      Err(_) => Message::malformed("bad response", chunk),
+    };
+    messages.push(message);
+  }
+
+  (messages, bytes.len() / 22 * 22)
+}
+
+/// Decode a broadcast datagram.
+pub fn decode_broadcast(bytes: &[u8]) -> Message {
+  if bytes.len() != 36 {
+    return Message::malformed("broadcast is not 36 bytes", bytes);
+  }
+  match Broadcast::parse(bytes) {
+    Ok(broadcast) => Message::Broadcast(broadcast),
+    Err(_) => Message::malformed("bad broadcast", bytes),
+  }
+}
+
+/// The transport layer of a captured packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+  Tcp {
+    /// Sequence number.
+    seq: u32,
+    /// Whether the SYN flag is set.
+    syn: bool,
+  },
+  Udp,
+}
+
+/// A TCP or UDP packet from a capture.
+#[derive(Clone, Debug)]
+pub struct Packet {
+  /// Capture timestamp.
+  pub time: Duration,
+  /// Sender.
+  pub source: SocketAddr,
+  /// Receiver.
+  pub destination: SocketAddr,
+  /// TCP or UDP.
+  pub transport: Transport,
+  /// The transport payload.
+  pub payload: Vec<u8>,
+}
+
+/// Read the TCP and UDP packets from a classic pcap capture. Ethernet, raw
+/// IP, Linux cooked and BSD loopback captures are understood; other packets
+/// are skipped.
+pub fn read_pcap(bytes: &[u8]) -> Result<Vec<Packet>, EtherdreamError> {
+  if bytes.len() < 24 {
+    return Err(bad_capture("too short for a pcap header"));
+  }
+
+  let magic = [bytes[0], bytes[1], bytes[2], bytes[3]];
+  let (big_endian, nanos) = match magic {
+    [0xD4, 0xC3, 0xB2, 0xA1] => (false, false),
+    [0xA1, 0xB2, 0xC3, 0xD4] => (true, false),
+    [0x4D, 0x3C, 0xB2, 0xA1] => (false, true),
+    [0xA1, 0xB2, 0x3C, 0x4D] => (true, true),
+    _ => return Err(bad_capture("not a pcap file (pcapng isn't supported)")),
+  };
+
+  let read_u32 = |b: &[u8]| {
+    let b = [b[0], b[1], b[2], b[3]];
+    if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) }
+  };
+
+  let link_type = read_u32(&bytes[20 .. 24]);
+  let mut packets = Vec::new();
+  let mut offset = 24;
+
+  while offset + 16 <= bytes.len() {
+    let seconds = read_u32(&bytes[offset .. offset + 4]) as u64;
+    let fraction = read_u32(&bytes[offset + 4 .. offset + 8]) as u64;
+    let len = read_u32(&bytes[offset + 8 .. offset + 12]) as usize;
+    offset += 16;
+
+    let data = match bytes.get(offset .. offset + len) {
+      Some(data) => data,
+      None => return Err(bad_capture("truncated packet")),
+    };
+    offset += len;
+
+    let time = Duration::from_secs(seconds)
+        + if nanos {
+          Duration::from_nanos(fraction)
+        } else {
+          Duration::from_micros(fraction)
+        };
+
+    if let Some(packet) = parse_link(link_type, data, big_endian, time) {
+      packets.push(packet);
+    }
+  }
+
+  Ok(packets)
+}
+
+fn parse_link(link_type: u32, data: &[u8], big_endian: bool, time: Duration)
+    -> Option<Packet> {
+  let ip = match link_type {
+    // BSD loopback: a host-endian address family.
+    0 => {
+      let family = data.get(0 .. 4)?;
+      let family = [family[0], family[1], family[2], family[3]];
+      let family = if big_endian {
+        u32::from_be_bytes(family)
+      } else {
+        u32::from_le_bytes(family)
+      };
+      match family {
+        2 | 24 | 28 | 30 => &data[4 ..],
+        _ => return None,
+      }
+    },
+    // Ethernet, possibly with VLAN tags.
+    1 => {
+      let mut offset = 12;
+      let mut ether_type = read_be16(data.get(offset .. offset + 2)?);
+      while ether_type == 0x8100 || ether_type == 0x88A8 {
+        offset += 4;
+        ether_type = read_be16(data.get(offset .. offset + 2)?);
+      }
+      match ether_type {
+        0x0800 | 0x86DD => data.get(offset + 2 ..)?,
+        _ => return None,
+      }
+    },
+    // Raw IP.
+    101 | 228 | 229 => data,
+    // Linux cooked capture.
+    113 => data.get(16 ..)?,
+    // Linux cooked capture v2.
+    276 => data.get(20 ..)?,
+    _ => return None,
+  };
+
+  parse_ip(ip, time)
+}
+
+fn parse_ip(ip: &[u8], time: Duration) -> Option<Packet> {
+  let version = ip.first()? >> 4;
+  let (protocol, source, destination, payload) = match version {
+    4 => {
+      let header_len = ((ip[0] & 0x0F) as usize) * 4;
+      let total_len = read_be16(ip.get(2 .. 4)?) as usize;
+      let fragment_offset = read_be16(ip.get(6 .. 8)?) & 0x1FFF;
+      if fragment_offset != 0 {
+        return None;
+      }
+      let source = ip.get(12 .. 16)?;
+      let destination = ip.get(16 .. 20)?;
+      (ip[9],
+          IpAddr::V4(Ipv4Addr::new(source[0], source[1], source[2], source[3])),
+          IpAddr::V4(Ipv4Addr::new(destination[0], destination[1],
+              destination[2], destination[3])),
+          ip.get(header_len .. total_len.min(ip.len()))?)
+    },
+    6 => {
+      let payload_len = read_be16(ip.get(4 .. 6)?) as usize;
+      let mut source = [0u8; 16];
+      let mut destination = [0u8; 16];
+      source.copy_from_slice(ip.get(8 .. 24)?);
+      destination.copy_from_slice(ip.get(24 .. 40)?);
+      (ip[6],
+          IpAddr::V6(Ipv6Addr::from(source)),
+          IpAddr::V6(Ipv6Addr::from(destination)),
+          ip.get(40 .. (40 + payload_len).min(ip.len()))?)
+    },
+    _ => return None,
+  };
+
+  let source_port = read_be16(payload.get(0 .. 2)?);
+  let destination_port = read_be16(payload.get(2 .. 4)?);
+
+  let (transport, data) = match protocol {
+    6 => {
+      let seq = payload.get(4 .. 8)?;
+      let seq = u32::from_be_bytes([seq[0], seq[1], seq[2], seq[3]]);
+      let header_len = ((payload.get(12)? >> 4) as usize) * 4;
+      let syn = payload.get(13)? & 0x02 != 0;
+      (Transport::Tcp { seq, syn }, payload.get(header_len ..)?)
+    },
+    17 => (Transport::Udp, payload.get(8 ..)?),
+    _ => return None,
+  };
+
+  Some(Packet {
+    time,
+    source: SocketAddr::new(source, source_port),
+    destination: SocketAddr::new(destination, destination_port),
+    transport,
+    payload: data.to_vec(),
+  })
+}
+
+/// A message decoded from a capture, with where and when it was seen.
+#[derive(Clone, Debug)]
+pub struct Dissected {
+  /// Timestamp of the packet that completed the message.
+  pub time: Duration,
+  /// Sender.
+  pub source: SocketAddr,
+  /// Receiver.
+  pub destination: SocketAddr,
+  /// The message.
+  pub message: Message,
+}
+
+/// One direction of a TCP connection being reassembled.
+#[derive(Default)]
+struct Flow {
+  next_seq: Option<u32>,
+  buffer: Vec<u8>,
+}
+
+/// Reassemble EtherDream traffic from captured packets and decode it.
+/// Retransmitted data is dropped. Where data is missing from the capture,
+/// the gap is reported as malformed and decoding restarts after it.
+pub fn dissect(packets: &[Packet]) -> Vec<Dissected> {
+  let mut flows : HashMap<(SocketAddr, SocketAddr), Flow> = HashMap::new();
+  let mut out = Vec::new();
+  let mut last_time = Duration::from_secs(0);
+
+  for packet in packets {
+    last_time = packet.time;
+    let push = |out: &mut Vec<Dissected>, message| out.push(Dissected {
+      time: packet.time,
+      source: packet.source,
+      destination: packet.destination,
+      message,
+    });
+
+    let seq = match packet.transport {
+      Transport::Udp => {
+        if packet.source.port() == BROADCAST_PORT
+            || packet.destination.port() == BROADCAST_PORT {
+          push(&mut out, decode_broadcast(&packet.payload));
+        }
+        continue;
+      },
+      Transport::Tcp { seq, syn } => {
+        if packet.source.port() != COMMUNICATION_PORT
+            && packet.destination.port() != COMMUNICATION_PORT {
+          continue;
+        }
+        if syn {
+          let flow = flows.entry((packet.source, packet.destination))
+              .or_default();
+          flow.next_seq = Some(seq.wrapping_add(1));
+          flow.buffer.clear();
+          continue;
+        }
+        seq
+      },
+    };
+
+    let flow = flows.entry((packet.source, packet.destination)).or_default();
+    let expected = *flow.next_seq.get_or_insert(seq);
+    let mut payload = &packet.payload[..];
+    let ahead = seq.wrapping_sub(expected) as i32;
+
+    if ahead < 0 {
+      // Retransmission. Keep only the data we haven't seen.
+      let seen = (-(ahead as i64)) as usize;
+      if seen >= payload.len() {
+        continue;
+      }
+      payload = &payload[seen ..];
+    } else if ahead > 0 {
+      let reason = format!("{} bytes missing from capture", ahead);
+      push(&mut out, Message::malformed(&reason, &flow.buffer));
+      flow.buffer.clear();
+    }
+
+    flow.buffer.extend_from_slice(payload);
+    flow.next_seq = Some(seq.wrapping_add(packet.payload.len() as u32));
+
+    let (messages, used) = if packet.destination.port() == COMMUNICATION_PORT {
+      decode_commands(&flow.buffer)
+    } else {
+      decode_responses(&flow.buffer)
+    };
+    flow.buffer.drain(.. used);
+
+    for message in messages {
+      push(&mut out, message);
+    }
+  }
+
+  let mut leftovers : Vec<_> = flows.into_iter()
+      .filter(|(_, flow)| !flow.buffer.is_empty())
+      .collect();
+  leftovers.sort_by_key(|((source, destination), _)| (*source, *destination));
+
+  for ((source, destination), flow) in leftovers {
+    out.push(Dissected {
+      time: last_time,
+      source,
+      destination,
+      message: Message::malformed("incomplete message at end of capture",
+          &flow.buffer),
+    });
+  }
+
+  out
+}
+
+fn read_be16(bytes: &[u8]) -> u16 {
+  ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+fn bad_capture(reason: &str) -> EtherdreamError {
+  EtherdreamError::BadCapture { description: reason.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::Data;
+
+  fn response(command: u8) -> Vec<u8> {
+    let mut bytes = vec![b'a', command];
+    bytes.resize(22, 0);
+    bytes
+  }
+
+  #[test]
+  fn test_decode_commands() {
+    let mut bytes = vec![COMMAND_PREPARE, 0x42];
+    bytes.extend(Begin { low_water_mark: 0, point_rate: 30_000 }.serialize());
+    bytes.extend(Data { points: &[Point::xy_blank(1, 2), Point::xy_blank(3, 4)] }
+        .serialize());
+    bytes.extend(&[COMMAND_DATA, 5, 0, 0]); // Incomplete.
+
+    let (messages, used) = decode_commands(&bytes);
+    assert_eq!(bytes.len() - 4, used);
+    assert_eq!(4, messages.len());
+    assert!(matches!(messages[0], Message::Command(Command::Prepare)));
+    assert!(matches!(messages[1], Message::Malformed { .. }));
+    assert!(matches!(messages[2],
+        Message::Command(Command::Begin(Begin { point_rate: 30_000, .. }))));
+    match messages[3] {
+      Message::Command(Command::Data(ref points)) => {
+        assert_eq!(2, points.len());
+        assert_eq!((3, 4), (points[1].x, points[1].y));
+      },
+      _ => panic!("expected data"),
+    }
+  }
+
+  #[test]
+  fn test_decode_responses() {
+    let mut bytes = response(b'p');
+    bytes.extend(response(0x01));
+    bytes.extend(b"ad");
+
+    let (messages, used) = decode_responses(&bytes);
+    assert_eq!(44, used);
+    assert!(matches!(messages[0], Message::Response(_)));
+    assert!(matches!(messages[1], Message::Malformed { .. }));
+  }
+
+  /// An Ethernet frame holding an IPv4 packet.
+  fn ipv4_frame(protocol: u8, ports: (u16, u16), transport: Vec<u8>) -> Vec<u8> {
+    let mut frame = vec![0; 12];
+    frame.extend(&[0x08, 0x00]);
+    let total = 24 + transport.len();
+    frame.extend(&[0x45, 0, (total >> 8) as u8, total as u8, 0, 0, 0, 0, 64,
+        protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+    let mut header = vec![(ports.0 >> 8) as u8, ports.0 as u8,
+        (ports.1 >> 8) as u8, ports.1 as u8];
+    header.extend(transport);
+    frame.extend(header);
+    frame
+  }
+
+  fn tcp(ports: (u16, u16), seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut transport = seq.to_be_bytes().to_vec();
+    transport.extend(&[0, 0, 0, 0, 0x50, 0x18, 0, 0, 0, 0, 0, 0]);
+    transport.extend(payload);
+    ipv4_frame(6, ports, transport)
+  }
+
+  fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0];
+    bytes.extend(&[0; 8]);
+    bytes.extend(&65535u32.to_le_bytes());
+    bytes.extend(&1u32.to_le_bytes());
+    for (i, frame) in frames.iter().enumerate() {
+      bytes.extend(&(i as u32).to_le_bytes());
+      bytes.extend(&0u32.to_le_bytes());
+      bytes.extend(&(frame.len() as u32).to_le_bytes());
+      bytes.extend(&(frame.len() as u32).to_le_bytes());
+      bytes.extend(frame);
+    }
+    bytes
+  }
+
+  #[test]
+  fn test_dissect_pcap() {
+    let to_dac = (50000, COMMUNICATION_PORT);
+    let from_dac = (COMMUNICATION_PORT, 50000);
+    let begin = Begin { low_water_mark: 0, point_rate: 1000 }.serialize();
+
+    let mut broadcast = vec![0; 16];
+    broadcast.extend(&[0; 20]);
+    let mut udp = vec![0, 44, 0, 0];
+    udp.extend(broadcast);
+
+    let capture = pcap(&[
+      tcp(to_dac, 100, &begin[.. 3]),
+      tcp(to_dac, 103, &begin[3 ..]),
+      tcp(to_dac, 100, &begin[.. 3]), // Retransmission.
+      tcp(from_dac, 7, &response(b'b')),
+      ipv4_frame(17, (BROADCAST_PORT, BROADCAST_PORT), udp),
+      tcp(to_dac, 120, &[COMMAND_PING]), // After a gap.
+    ]);
+
+    let packets = read_pcap(&capture).unwrap();
+    assert_eq!(6, packets.len());
+    assert_eq!(Duration::from_secs(1), packets[1].time);
+
+    let dissected = dissect(&packets);
+    let kinds : Vec<&str> = dissected.iter()
+        .map(|d| match d.message {
+          Message::Command(_) => "command",
+          Message::Response(_) => "response",
+          Message::Broadcast(_) => "broadcast",
+          Message::Malformed { .. } => "malformed",
+        })
+        .collect();
+    assert_eq!(vec!["command", "response", "broadcast", "malformed", "command"],
+        kinds);
+    assert_eq!(Duration::from_secs(1), dissected[0].time);
+    assert_eq!(COMMUNICATION_PORT, dissected[0].destination.port());
+  }
+
+  #[test]
+  fn test_truncated_tcp_header() {
+    // An IPv4 header and the first six bytes of a TCP header.
+    let mut ip = vec![0x45, 0, 0, 26, 0, 0, 0, 0, 64, 6, 0, 0];
+    ip.extend(&[10, 0, 0, 1, 10, 0, 0, 2]);
+    ip.extend(&[0x1E, 0x55, 0xC0, 0x00, 0, 0]);
+    assert_eq!(26, ip.len());
+
+    assert!(parse_ip(&ip, Duration::from_secs(0)).is_none());
+  }
+
+  #[test]
+  fn test_read_pcap_errors() {
+    assert!(read_pcap(&[0; 10]).is_err());
+    assert!(read_pcap(&[0; 24]).is_err());
+
+    let mut capture = pcap(&[tcp((1, COMMUNICATION_PORT), 0, b"?")]);
+    capture.pop();
+    assert!(read_pcap(&capture).is_err());
+  }
+}
@@ -3,13 +3,14 @@
 
 //! Library errors
 
-use protocol::AckCode;
-use protocol::CommandCode;
+use crate::protocol::AckCode;
+use crate::protocol::CommandCode;
 use std::error::Error;
 use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fmt::Result;
 use std::io::Error as IoError;
+use std::time::Duration;
 
 /// Represents all of the errors in the Etherdream library.
 #[derive(Debug)]
@@ -19,6 +20,31 @@ pub enum EtherdreamError {
     /// Description of the error.
     description: String,
   },
+  /// An ILDA file couldn't be parsed.
+  BadIldaFile {
+    /// Description of the error.
+    description: String,
+  },
+  /// A packet capture couldn't be parsed.
+  BadCapture {
+    /// Description of the error.
+    description: String,
+  },
+  /// Too many points were given to send in one write.
+  BatchTooLarge {
+    /// Description of the error.
+    description: String,
+  },
+  /// A color calibration profile couldn't be parsed.
+  BadCalibration {
+    /// Description of the error.
+    description: String,
+  },
+  /// A recording couldn't be parsed.
+  BadRecording {
+    /// Description of the error.
+    description: String,
+  },
   /// Network error.
   IoError {
     /// Cause of the error.
@@ -31,24 +57,46 @@ pub enum EtherdreamError {
     /// The command the NACK was in response to.
     command: CommandCode,
   },
+  /// The point source ran past the watchdog deadline, so the DAC was sent an
+  /// emergency stop.
+  SourceStalled {
+    /// The watchdog deadline.
+    deadline: Duration,
+    /// How long the source actually took.
+    elapsed: Duration,
+  },
+  /// A stream thread panicked.
+  StreamPanicked,
   /// We received a response for the wrong command.
   WrongResponse, // TODO: Encode more information in error?
 }
 
-impl Error for EtherdreamError {
-  fn description(&self) -> &str {
-    match *self {
-      EtherdreamError::BadResponseLength { .. } => "BadResponseLength",
-      EtherdreamError::IoError { .. } => "IoError",
-      EtherdreamError::ReceivedNack { .. } => "ReceivedNack",
-      EtherdreamError::WrongResponse => "WrongResponse",
-    }
-  }
-}
+impl Error for EtherdreamError {}
 
 impl Display for EtherdreamError {
   fn fmt(&self, f: &mut Formatter) -> Result {
-    write!(f, "{}", self.description())
+    match *self {
+      EtherdreamError::BadCalibration { ref description } =>
+          write!(f, "BadCalibration: {}", description),
+      EtherdreamError::BadCapture { ref description } =>
+          write!(f, "BadCapture: {}", description),
+      EtherdreamError::BadIldaFile { ref description } =>
+          write!(f, "BadIldaFile: {}", description),
+      EtherdreamError::BadResponseLength { ref description } =>
+          write!(f, "BadResponseLength: {}", description),
+      EtherdreamError::BadRecording { ref description } =>
+          write!(f, "BadRecording: {}", description),
+      EtherdreamError::BatchTooLarge { ref description } =>
+          write!(f, "BatchTooLarge: {}", description),
+      EtherdreamError::IoError { ref cause } => write!(f, "IoError: {}", cause),
+      EtherdreamError::ReceivedNack { ref code, ref command } =>
+          write!(f, "ReceivedNack: {:?} in response to {:?}", code, command),
+      EtherdreamError::SourceStalled { deadline, elapsed } =>
+          write!(f, "SourceStalled: took {:?}, past the {:?} deadline", elapsed,
+              deadline),
+      EtherdreamError::StreamPanicked => write!(f, "StreamPanicked"),
+      EtherdreamError::WrongResponse => write!(f, "WrongResponse"),
+    }
   }
 }
 
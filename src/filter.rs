@@ -0,0 +1,720 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Point filters process points between the point source and the DAC.
+//! Filters added with `Dac::add_filter` apply to every stream, whichever
+//! streaming method is used.
+
+use crate::protocol::Point;
+use std::collections::VecDeque;
+
+/// A stage that processes points on their way to the DAC.
+pub trait PointFilter: Send {
+  /// Process a batch of points in place. Batches are consecutive, so filters
+  /// may keep state between calls. Filters may add or remove points.
+  fn filter(&mut self, points: &mut Vec<Point>);
+}
+
+impl<F> PointFilter for F where F: FnMut(&mut Vec<Point>) + Send {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    self(points)
+  }
+}
+
+/// Whether a point will emit any light.
+pub fn is_lit(point: &Point) -> bool {
+  point.r != 0 || point.g != 0 || point.b != 0 || point.i != 0
+}
+
+/// A copy of the point with every color channel off.
+pub fn blanked(point: &Point) -> Point {
+  Point { r: 0, g: 0, b: 0, i: 0, ..*point }
+}
+
+/// How many points `VelocityLimiter` holds back at most while looking ahead.
+/// Well under the DAC's buffer, so the first batches of a stream still fill it.
+const MAX_LOOKAHEAD : usize = 512;
+
+/// Keeps the galvos within their limits by inserting points along large
+/// jumps. Inserted points take the color of the point being moved to, so lit
+/// lines stay lit and blanked moves stay blank.
+///
+/// Limits are given in DAC units per second, as quoted for the scanners, and
+/// converted to per-point limits using the point rate.
+///
+/// With an acceleration limit, the change in velocity between points is
+/// limited, including changes of direction: the beam slows into sharp corners
+/// and comes to a stop before reversing. Every point passed in is still output,
+/// with points inserted between them wherever the beam has to go slower than
+/// they are spaced. How fast to arrive at a point depends on the path after
+/// it, so up to `MAX_LOOKAHEAD` points are held back while looking ahead.
+/// Points spaced close to the speed limit leave little room to change speed
+/// between them, so there the limit is kept as closely as the spacing allows.
+pub struct VelocityLimiter {
+  max_step: f64,
+  max_acceleration: Option<f64>,
+  last: Option<Point>,
+  /// How fast the beam is moving at the last point, in units per point.
+  speed: f64,
+  /// How many points the output is behind the planned timing.
+  lag: f64,
+  ahead: VecDeque<Waypoint>,
+  /// How many of the points held back, from the front, won't change speed.
+  settled: usize,
+}
+
+/// A point held back by `VelocityLimiter`, with the line leading to it.
+struct Waypoint {
+  point: Point,
+  length: f64,
+  direction: (f64, f64),
+  /// How fast the beam may pass here, given the turn after it.
+  corner: f64,
+  /// How fast the beam may pass here, given everything held after it.
+  speed: f64,
+}
+
+impl VelocityLimiter {
+  /// CTOR. `max_speed` is in DAC units per second.
+  pub fn new(max_speed: f64, point_rate: u32) -> VelocityLimiter {
+    VelocityLimiter {
+      max_step: (max_speed / point_rate.max(1) as f64).max(1.0),
+      max_acceleration: None,
+      last: None,
+      speed: 0.0,
+      lag: 0.0,
+      ahead: VecDeque::new(),
+      settled: 0,
+    }
+  }
+
+  /// Also limit acceleration, in DAC units per second squared. Jumps then
+  /// ease in and out instead of moving at a constant speed.
+  pub fn with_max_acceleration(mut self, max_acceleration: f64, point_rate: u32)
+      -> VelocityLimiter {
+    let point_rate = point_rate.max(1) as f64;
+    self.max_acceleration =
+        Some((max_acceleration / (point_rate * point_rate)).max(1.0));
+    self
+  }
+
+  /// The largest distance between consecutive points, in DAC units.
+  pub fn max_step(&self) -> f64 {
+    self.max_step
+  }
+
+  /// Split a jump into steps no longer than the maximum step.
+  fn split(&mut self, point: Point, out: &mut Vec<Point>) {
+    let last = self.last.unwrap_or(point);
+    let dx = point.x as f64 - last.x as f64;
+    let dy = point.y as f64 - last.y as f64;
+    let distance = dx.hypot(dy);
+
+    if distance > self.max_step {
+      let count = (distance / self.max_step).ceil();
+      for i in 1 .. count as usize {
+        let t = i as f64 / count;
+        out.push(Point {
+          x: (last.x as f64 + dx * t).round() as i16,
+          y: (last.y as f64 + dy * t).round() as i16,
+          ..point
+        });
+      }
+    }
+
+    out.push(point);
+    self.last = Some(point);
+  }
+
+  /// Hold a point back, and tighten the plan for the points before it.
+  /// `ramp` is the most the speed may change in one step.
+  fn look_ahead(&mut self, point: Point, ramp: f64) {
+    let from = self.ahead.back().map(|waypoint| waypoint.point)
+        .or(self.last)
+        .unwrap_or(point);
+    let dx = point.x as f64 - from.x as f64;
+    let dy = point.y as f64 - from.y as f64;
+    let length = dx.hypot(dy);
+    let direction = if length > 0.0 { (dx / length, dy / length) } else { (0.0, 0.0) };
+
+    let max_step = self.max_step;
+    if let Some(before) = self.ahead.back_mut() {
+      before.corner = corner_speed(before, length, direction, max_step, ramp);
+    }
+
+    // Nothing is known past the newest point, so plan to stop there. Slow
+    // enough for that covers any turn that comes next.
+    self.ahead.push_back(Waypoint {
+      point,
+      length,
+      direction,
+      corner: ramp / 2.0,
+      speed: ramp / 2.0,
+    });
+
+    // Work back while there's room to slow down between points. Holding
+    // another point only ever raises these, so stop once one stays the same.
+    // Once one is as fast as its corner allows, it and those before it are
+    // settled.
+    for i in (0 .. self.ahead.len() - 1).rev() {
+      let next = &self.ahead[i + 1];
+      let speed = if next.length == 0.0 {
+        // Holding still, which the corner already allows for.
+        self.ahead[i].corner
+      } else {
+        self.ahead[i].corner
+            .min((next.speed * next.speed + 2.0 * ramp * next.length).sqrt())
+      };
+      if speed >= self.ahead[i].corner {
+        self.settled = self.settled.max(i + 1);
+      }
+      if speed == self.ahead[i].speed {
+        break;
+      }
+      self.ahead[i].speed = speed;
+    }
+  }
+
+  /// Move to the first point held back, if how fast to arrive there can't
+  /// change any more. Returns whether it moved.
+  fn advance(&mut self, ramp: f64, out: &mut Vec<Point>) -> bool {
+    let settled = match self.ahead.front() {
+      Some(first) => first.length == 0.0 || self.settled > 0,
+      None => return false,
+    };
+    if !settled && self.ahead.len() <= MAX_LOOKAHEAD {
+      return false;
+    }
+
+    let target = self.ahead.pop_front().unwrap();
+    self.settled = self.settled.saturating_sub(1);
+    let last = self.last.unwrap_or(target.point);
+    if target.length == 0.0 {
+      out.push(target.point);
+      self.speed = 0.0;
+      self.lag = 0.0;
+      self.last = Some(target.point);
+      return true;
+    }
+
+    let (steps, speed) = step_lengths(target.length, self.speed, target.speed,
+        self.max_step, ramp, &mut self.lag);
+    let (dx, dy) = target.direction;
+    let mut travelled = 0.0;
+    for step in &steps[.. steps.len() - 1] {
+      travelled += step;
+      out.push(Point {
+        x: (last.x as f64 + dx * travelled).round() as i16,
+        y: (last.y as f64 + dy * travelled).round() as i16,
+        ..target.point
+      });
+    }
+    out.push(target.point);
+
+    self.speed = speed;
+    self.last = Some(target.point);
+    true
+  }
+}
+
+impl PointFilter for VelocityLimiter {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    let mut out = Vec::with_capacity(points.len());
+
+    for point in points.drain(..) {
+      let ramp = match self.max_acceleration {
+        // A step leaving a corner sees both the change in speed and the turn
+        // itself, so each gets half the limit.
+        Some(acceleration) if self.last.is_some() => acceleration / 2.0,
+        _ => {
+          self.split(point, &mut out);
+          continue;
+        },
+      };
+
+      self.look_ahead(point, ramp);
+      while self.advance(ramp, &mut out) {}
+    }
+
+    *points = out;
+  }
+}
+
+/// How fast the beam may pass `at`, so that turning onto the line after it,
+/// `length` long, changes its velocity by no more than `2 * ramp`.
+fn corner_speed(at: &Waypoint, length: f64, direction: (f64, f64), max_step: f64,
+    ramp: f64) -> f64 {
+  if length == 0.0 {
+    // Holding still next.
+    return ramp;
+  }
+  // The line after is at least one step, so no longer than it, and the speed
+  // drops by at most `ramp` onto it.
+  let speed = max_step.min(length + ramp);
+  if at.length == 0.0 {
+    return speed;
+  }
+  let turn = (direction.0 - at.direction.0).hypot(direction.1 - at.direction.1);
+  if turn < 1e-9 {
+    speed
+  } else {
+    speed.min(ramp / turn)
+  }
+}
+
+/// Lengths of the steps along a line, from a speed of `entry` to no faster than
+/// `exit`, speeding up and slowing down by `ramp` per step, and the speed at the
+/// end. The line takes a whole number of steps, so they land exactly on the
+/// end, and `lag` carries the rounding over from line to line so the beam keeps
+/// to the planned speeds on average.
+fn step_lengths(length: f64, entry: f64, exit: f64, max_step: f64, ramp: f64,
+    lag: &mut f64) -> (Vec<f64>, f64) {
+  let profile = SpeedProfile::new(length, entry, exit, max_step, ramp);
+  let duration = profile.duration();
+
+  // Steps spread evenly over the time the line takes.
+  let spread = |count: f64| -> (Vec<f64>, f64) {
+    let mut steps = Vec::with_capacity(count as usize);
+    let mut position = 0.0;
+    for k in 1 .. count as usize {
+      let next = profile.position(k as f64 * duration / count);
+      steps.push(next - position);
+      position = next;
+    }
+    steps.push(length - position);
+    // Rounding can warp the profile a lot on short lines, but the speed at the
+    // end is never far off the last step.
+    let last = length - position;
+    (steps, (profile.exit * duration / count).min(last + ramp / 2.0))
+  };
+
+  // Taking fewer steps than planned speeds them all up, so only do that while
+  // it stays within the limits.
+  let mut count = (duration - *lag).round().max(1.0);
+  let (mut steps, mut speed) = spread(count);
+  if count < duration && (speed > exit || steps.iter().any(|&step| step > max_step)) {
+    count = duration.ceil();
+    let spread = spread(count);
+    steps = spread.0;
+    speed = spread.1;
+  }
+  *lag = (*lag + count - duration).clamp(-0.5, 0.5);
+
+  // When the line is only a few steps long, rounding the count leaves the
+  // first step well off the entry speed. Changing speed evenly along the whole
+  // line spreads that out, if it still arrives slow enough.
+  let change = 2.0 * (length - count * entry) / (count * count);
+  let even : Vec<f64> = (1 ..= count as usize)
+      .map(|k| entry + (k as f64 - 0.5) * change)
+      .collect();
+  let even_speed = entry + count * change;
+  let even_fits = even_speed >= 0.0 && even_speed <= exit
+      && even.iter().all(|&step| step > 0.0 && step <= max_step);
+  if even_fits && change.abs() < largest_change(entry, &steps) {
+    (even, even_speed)
+  } else {
+    (steps, speed)
+  }
+}
+
+/// The largest change in length from one step to the next.
+fn largest_change(entry: f64, steps: &[f64]) -> f64 {
+  steps.iter()
+      .scan(entry, |before, &step| {
+        let change = (step - *before).abs();
+        *before = step;
+        Some(change)
+      })
+      .fold(0.0, f64::max)
+}
+
+/// Speeding up from an entry speed toward the maximum step, cruising, then
+/// slowing to an exit speed, along a line. Speeds are per point, and times are
+/// in points.
+struct SpeedProfile {
+  entry: f64,
+  peak: f64,
+  exit: f64,
+  acceleration: f64,
+  speed_up: f64,
+  cruise: f64,
+  slow_down: f64,
+}
+
+impl SpeedProfile {
+  /// CTOR.
+  fn new(length: f64, entry: f64, exit: f64, max_step: f64, acceleration: f64)
+      -> SpeedProfile {
+    // Arrive no faster than there's room to speed up to, and no slower than
+    // there's room to slow down to.
+    let reachable = (entry * entry + 2.0 * acceleration * length).sqrt();
+    let unavoidable = (entry * entry - 2.0 * acceleration * length).max(0.0).sqrt();
+    let exit = exit.min(reachable).max(unavoidable);
+
+    let peak = ((2.0 * acceleration * length + entry * entry + exit * exit) / 2.0)
+        .sqrt()
+        .min(max_step)
+        .max(entry)
+        .max(exit);
+
+    let speed_up = (peak - entry) / acceleration;
+    let slow_down = (peak - exit) / acceleration;
+    let ramps = (entry + peak) / 2.0 * speed_up + (peak + exit) / 2.0 * slow_down;
+    let cruise = ((length - ramps) / peak).max(0.0);
+
+    SpeedProfile { entry, peak, exit, acceleration, speed_up, cruise, slow_down }
+  }
+
+  /// How long the line takes.
+  fn duration(&self) -> f64 {
+    self.speed_up + self.cruise + self.slow_down
+  }
+
+  /// How far along the line the beam is at the given time.
+  fn position(&self, time: f64) -> f64 {
+    let a = self.acceleration;
+    if time <= self.speed_up {
+      return self.entry * time + a * time * time / 2.0;
+    }
+    let speed_up = (self.entry + self.peak) / 2.0 * self.speed_up;
+    if time <= self.speed_up + self.cruise {
+      return speed_up + self.peak * (time - self.speed_up);
+    }
+    let time = (time - self.speed_up - self.cruise).min(self.slow_down);
+    speed_up + self.peak * self.cruise + self.peak * time - a * time * time / 2.0
+  }
+}
+
+/// Holds the beam still where it would otherwise round things off. Lit
+/// points where the path turns sharply are repeated, so corners come out
+/// square. Around blanked moves, blank points are held where the lit stretch
+/// ends, so the lasers are off before the galvos move, and where the next one
+/// begins, so the galvos settle before the lasers come on.
+///
+/// As a filter, each point is held back until the next arrives to see where
+/// the path turns, so output runs one point behind.
+pub struct DwellInserter {
+  corner_angle: f64,
+  corner_dwell: usize,
+  pre_blank: usize,
+  post_blank: usize,
+  previous: Option<Point>,
+  current: Option<Point>,
+}
+
+impl DwellInserter {
+  /// CTOR. Turns of `corner_angle` radians or more get `corner_dwell` extra
+  /// points. No blanking dwell is added.
+  pub fn new(corner_angle: f64, corner_dwell: usize) -> DwellInserter {
+    DwellInserter {
+      corner_angle,
+      corner_dwell,
+      pre_blank: 0,
+      post_blank: 0,
+      previous: None,
+      current: None,
+    }
+  }
+
+  /// Also hold `pre` blank points where lit output ends, before a blanked
+  /// move, and `post` blank points where lit output begins again.
+  pub fn with_blank_dwell(mut self, pre: usize, post: usize) -> DwellInserter {
+    self.pre_blank = pre;
+    self.post_blank = post;
+    self
+  }
+
+  /// The smallest turn, in radians, treated as a corner.
+  pub fn get_corner_angle(&self) -> f64 {
+    self.corner_angle
+  }
+
+  /// Extra points at each corner.
+  pub fn get_corner_dwell(&self) -> usize {
+    self.corner_dwell
+  }
+
+  /// Blank points held before and after blanked moves.
+  pub fn get_blank_dwell(&self) -> (usize, usize) {
+    (self.pre_blank, self.post_blank)
+  }
+
+  /// Add dwell to a frame that loops, so its last point leads into its
+  /// first. This doesn't touch the state used when filtering.
+  pub fn process_frame(&self, frame: &[Point]) -> Vec<Point> {
+    let mut out = Vec::with_capacity(frame.len());
+    let n = frame.len();
+    for i in 0 .. n {
+      self.expand(Some(&frame[(i + n - 1) % n]), &frame[i], &frame[(i + 1) % n],
+          &mut out);
+    }
+    out
+  }
+
+  /// Output a point, followed by any dwell it needs given the points around
+  /// it.
+  fn expand(&self, previous: Option<&Point>, current: &Point, next: &Point,
+      out: &mut Vec<Point>) {
+    out.push(*current);
+
+    match (is_lit(current), is_lit(next)) {
+      (true, true) => {
+        if let Some(previous) = previous {
+          if turn(previous, current, next) >= self.corner_angle {
+            out.extend(vec![*current; self.corner_dwell]);
+          }
+        }
+      },
+      (true, false) => out.extend(vec![blanked(current); self.pre_blank]),
+      (false, true) => out.extend(vec![blanked(next); self.post_blank]),
+      (false, false) => {},
+    }
+  }
+}
+
+impl PointFilter for DwellInserter {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    let mut out = Vec::with_capacity(points.len());
+
+    for next in points.drain(..) {
+      if let Some(current) = self.current {
+        self.expand(self.previous.as_ref(), &current, &next, &mut out);
+        self.previous = Some(current);
+      }
+      self.current = Some(next);
+    }
+
+    *points = out;
+  }
+}
+
+/// How far the path turns at `current`, in radians. Zero if either side has
+/// no length.
+fn turn(previous: &Point, current: &Point, next: &Point) -> f64 {
+  let ax = current.x as f64 - previous.x as f64;
+  let ay = current.y as f64 - previous.y as f64;
+  let bx = next.x as f64 - current.x as f64;
+  let by = next.y as f64 - current.y as f64;
+  if (ax == 0.0 && ay == 0.0) || (bx == 0.0 && by == 0.0) {
+    return 0.0;
+  }
+  (ax * by - ay * bx).atan2(ax * bx + ay * by).abs()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f64::consts::FRAC_PI_4;
+
+  fn max_gap(points: &[Point]) -> f64 {
+    points.windows(2)
+        .map(|w| (w[1].x as f64 - w[0].x as f64).hypot(w[1].y as f64 - w[0].y as f64))
+        .fold(0.0, f64::max)
+  }
+
+  fn xy(points: &[Point]) -> Vec<(i16, i16, bool)> {
+    points.iter().map(|p| (p.x, p.y, is_lit(p))).collect()
+  }
+
+  #[test]
+  fn test_dwell_at_corners() {
+    let inserter = DwellInserter::new(FRAC_PI_4, 2);
+    let frame = [
+      Point::xy_luma(0, 0, 100),
+      Point::xy_luma(100, 0, 100),
+      Point::xy_luma(200, 0, 100), // Straight on.
+      Point::xy_luma(200, 100, 100), // A right angle at the previous point.
+      Point::xy_luma(210, 200, 100), // Too shallow a turn.
+    ];
+
+    let mut points = frame.to_vec();
+    DwellInserter::new(FRAC_PI_4, 2).filter(&mut points);
+    assert_eq!(vec![
+      (0, 0, true),
+      (100, 0, true),
+      (200, 0, true),
+      (200, 0, true),
+      (200, 0, true),
+      (200, 100, true),
+    ], xy(&points));
+
+    // Looping, the frame also turns at both ends.
+    assert_eq!(5 + 2 * 3, inserter.process_frame(&frame).len());
+  }
+
+  #[test]
+  fn test_dwell_around_blanking() {
+    let inserter = DwellInserter::new(FRAC_PI_4, 0).with_blank_dwell(2, 3);
+    let frame = [
+      Point::xy_luma(0, 0, 100),
+      Point::xy_blank(500, 0),
+      Point::xy_luma(1000, 0, 100),
+    ];
+
+    assert_eq!(vec![
+      (0, 0, true),
+      (0, 0, false),
+      (0, 0, false),
+      (500, 0, false),
+      (1000, 0, false),
+      (1000, 0, false),
+      (1000, 0, false),
+      (1000, 0, true),
+    ], xy(&inserter.process_frame(&frame)));
+
+    // Filtering in batches gives the same points, one behind.
+    let mut filter = DwellInserter::new(FRAC_PI_4, 0).with_blank_dwell(2, 3);
+    let mut out = Vec::new();
+    for batch in [&frame[.. 1], &frame[1 ..], &frame[.. 1]].iter() {
+      let mut points = batch.to_vec();
+      filter.filter(&mut points);
+      out.extend(points);
+    }
+    assert_eq!(xy(&inserter.process_frame(&frame)), xy(&out));
+  }
+
+  #[test]
+  fn test_velocity_limiter_splits_jumps() {
+    // 100 units per point.
+    let mut limiter = VelocityLimiter::new(100_000.0, 1000);
+    let mut points = vec![
+      Point::xy_luma(0, 0, 100),
+      Point::xy_blank(1000, 0),
+      Point::xy_luma(1050, 0, 100),
+    ];
+
+    limiter.filter(&mut points);
+
+    assert_eq!(12, points.len());
+    assert!(max_gap(&points) <= 100.0);
+    assert!(points[1 .. 11].iter().all(|p| !is_lit(p)));
+    assert!(is_lit(&points[11]));
+  }
+
+  #[test]
+  fn test_velocity_limiter_acceleration() {
+    let mut limiter = VelocityLimiter::new(100_000.0, 1000)
+        .with_max_acceleration(10_000_000.0, 1000); // 10 units per point².
+    // Holding at the end tells the limiter where to stop.
+    let mut points = vec![
+      Point::xy_blank(0, 0),
+      Point::xy_blank(1000, 0),
+      Point::xy_blank(1000, 0),
+    ];
+
+    limiter.filter(&mut points);
+
+    assert_eq!(1000, points.last().unwrap().x);
+    assert!(max_gap(&points) <= 100.0);
+    assert!(max_velocity_change(&points) <= 11.0);
+  }
+
+  #[test]
+  fn test_velocity_limiter_out_and_back() {
+    let mut limiter = VelocityLimiter::new(100_000.0, 1000)
+        .with_max_acceleration(10_000_000.0, 1000); // 10 units per point².
+    let out = (0 ..= 10).map(|i| Point::xy_blank(i * 100, 0));
+    let back = (0 .. 10).rev().map(|i| Point::xy_blank(i * 100, 0));
+    let mut points : Vec<Point> = out.chain(back).collect();
+    points.push(Point::xy_blank(0, 0));
+
+    limiter.filter(&mut points);
+
+    // Steps were already within the speed limit, but the beam has to slow to a
+    // stop before turning around.
+    assert!(points.len() > 21);
+    assert!(points.iter().all(|p| p.x >= 0 && p.x <= 1000));
+    assert_eq!(0, points.last().unwrap().x);
+    assert!(max_gap(&points) <= 100.0);
+    // Every point is kept, so how many steps the beam takes between them can
+    // only change a whole step at a time, which can take a little more.
+    assert!(max_velocity_change(&points) <= 15.0);
+  }
+
+  #[test]
+  fn test_velocity_limiter_slows_into_corners() {
+    let mut limiter = VelocityLimiter::new(100_000.0, 1000)
+        .with_max_acceleration(10_000_000.0, 1000);
+    let mut points = vec![
+      Point::xy_blank(0, 0),
+      Point::xy_blank(1000, 0),
+      Point::xy_blank(1000, 1000),
+      Point::xy_blank(1000, 1000),
+    ];
+
+    limiter.filter(&mut points);
+
+    assert_eq!((1000, 1000), (points.last().unwrap().x, points.last().unwrap().y));
+    assert!(max_gap(&points) <= 100.0);
+    assert!(max_velocity_change(&points) <= 11.0);
+  }
+
+  #[test]
+  fn test_velocity_limiter_keeps_every_point() {
+    let mut limiter = VelocityLimiter::new(100_000.0, 1000)
+        .with_max_acceleration(10_000_000.0, 1000);
+    // A zigzag with a different color at each point.
+    let mut input : Vec<Point> = (0 .. 200)
+        .map(|i| Point::xy_rgb(i * 37, (i % 2) * 500, i as u16 * 300, 0, 0))
+        .collect();
+    input.push(input[199]);
+    let mut points = input.clone();
+
+    limiter.filter(&mut points);
+
+    assert!(points.len() > input.len());
+    assert!(contains_in_order(&points, &input));
+    assert_eq!(199 * 37, points.last().unwrap().x);
+    assert!(max_velocity_change(&points) <= 11.0);
+  }
+
+  #[test]
+  fn test_velocity_limiter_passes_smooth_paths_through() {
+    // 1000 units per point, and 11 per point² at 30k pps.
+    let mut limiter = VelocityLimiter::new(30_000_000.0, 30_000)
+        .with_max_acceleration(10_000_000_000.0, 30_000);
+    let circle : Vec<Point> = (0 .. 30_000)
+        .map(|i| {
+          let angle = i as f64 * std::f64::consts::PI * 2.0 / 1000.0;
+          let x = (20_000.0 * angle.cos()).round() as i16;
+          let y = (20_000.0 * angle.sin()).round() as i16;
+          Point::xy_luma(x, y, 100)
+        })
+        .collect();
+
+    // A whole buffer's worth comes straight back out, less the few points
+    // held back to look ahead.
+    let mut output = circle[.. 1799].to_vec();
+    limiter.filter(&mut output);
+    assert!(output.len() >= 1799 - MAX_LOOKAHEAD);
+
+    for batch in circle[1799 ..].chunks(500) {
+      let mut points = batch.to_vec();
+      limiter.filter(&mut points);
+      output.extend(points);
+    }
+
+    // Only starting from standing still needs any points inserted.
+    let held = limiter.ahead.len();
+    assert!(output.len() + held <= circle.len() + 20);
+    assert!(contains_in_order(&output, &circle[.. circle.len() - held]));
+  }
+
+  /// Whether `points` includes every one of `wanted`, in order.
+  fn contains_in_order(points: &[Point], wanted: &[Point]) -> bool {
+    let key = |p: &Point| (p.x, p.y, p.r, p.g, p.b, p.i);
+    let mut points = points.iter();
+    wanted.iter().all(|w| points.any(|p| key(p) == key(w)))
+  }
+
+  /// The largest change in velocity between consecutive steps.
+  fn max_velocity_change(points: &[Point]) -> f64 {
+    points.windows(3)
+        .map(|w| {
+          let (ax, ay) = (w[1].x as f64 - w[0].x as f64, w[1].y as f64 - w[0].y as f64);
+          let (bx, by) = (w[2].x as f64 - w[1].x as f64, w[2].y as f64 - w[1].y as f64);
+          (bx - ax).hypot(by - ay)
+        })
+        .fold(0.0, f64::max)
+  }
+}
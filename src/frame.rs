@@ -0,0 +1,285 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Frame-based playback. Submit whole frames of points and let the streamer
+//! slice them into the batches the DAC asks for.
+
+use crate::dac::Dac;
+use crate::error::EtherdreamError;
+use crate::handle::DacHandle;
+use crate::protocol::Point;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// How many frames may wait to be played before the oldest are dropped.
+pub const DEFAULT_MAX_QUEUED_FRAMES : usize = 2;
+
+/// Streams frames of points. The current frame loops until a new frame is
+/// submitted, and frames only change at frame boundaries to avoid tearing.
+pub struct FrameStreamer {
+  queue: FrameSender,
+  current: Vec<Point>,
+  position: usize,
+  transition: VecDeque<Point>,
+  transition_points: usize,
+}
+
+/// Submits frames to a `FrameStreamer`. Cheap to clone and may be shared
+/// between threads.
+#[derive(Clone, Default)]
+pub struct FrameSender {
+  queue: Arc<Mutex<FrameQueue>>,
+}
+
+/// Frames waiting to be played.
+struct FrameQueue {
+  frames: VecDeque<Vec<Point>>,
+  max_queued: usize,
+}
+
+impl Default for FrameQueue {
+  fn default() -> FrameQueue {
+    FrameQueue {
+      frames: VecDeque::new(),
+      max_queued: DEFAULT_MAX_QUEUED_FRAMES,
+    }
+  }
+}
+
+impl FrameStreamer {
+  /// CTOR. No blanking is inserted between frames.
+  pub fn new() -> FrameStreamer {
+    FrameStreamer {
+      queue: FrameSender::default(),
+      current: Vec::new(),
+      position: 0,
+      transition: VecDeque::new(),
+      transition_points: 0,
+    }
+  }
+
+  /// A sender for submitting frames to this streamer.
+  pub fn sender(&self) -> FrameSender {
+    self.queue.clone()
+  }
+
+  /// Submit a frame. Shorthand for `sender().submit(frame)`.
+  pub fn submit(&self, frame: Vec<Point>) {
+    self.queue.submit(frame);
+  }
+
+  /// The number of blank points interpolated between the last point of one
+  /// frame and the first point of the next.
+  pub fn get_transition_points(&self) -> usize {
+    self.transition_points
+  }
+
+  /// Set the number of blank points interpolated between frames. Zero
+  /// disables the blanked move.
+  pub fn set_transition_points(&mut self, transition_points: usize) {
+    self.transition_points = transition_points;
+  }
+
+  /// How many frames may wait to be played.
+  pub fn get_max_queued_frames(&self) -> usize {
+    self.queue.max_queued()
+  }
+
+  /// Set how many frames may wait to be played. Submitting more drops the
+  /// oldest waiting frames, so a producer faster than playback doesn't add
+  /// latency. At least one frame is always kept.
+  pub fn set_max_queued_frames(&mut self, max_queued: usize) {
+    self.queue.set_max_queued(max_queued.max(1));
+  }
+
+  /// Generate the next `num_points` points. Blank points are sent at the
+  /// origin until the first frame is submitted.
+  pub fn next_points(&mut self, num_points: u16) -> Vec<Point> {
+    let num_points = num_points as usize;
+    let mut points = Vec::with_capacity(num_points);
+
+    if self.current.is_empty() {
+      self.next_frame();
+    }
+
+    while points.len() < num_points {
+      if let Some(point) = self.transition.pop_front() {
+        points.push(point);
+        continue;
+      }
+
+      if self.current.is_empty() {
+        let remaining = num_points - points.len();
+        points.extend(vec![Point::xy_blank(0, 0); remaining]);
+        break;
+      }
+
+      points.push(self.current[self.position]);
+      self.position += 1;
+
+      if self.position >= self.current.len() {
+        self.position = 0;
+        self.next_frame();
+      }
+    }
+
+    points
+  }
+
+  /// Stream frames through the DAC on the current thread.
+  pub fn play(mut self, dac: &mut Dac) -> Result<(), EtherdreamError> {
+    dac.play_function(|num_points| self.next_points(num_points))
+  }
+
+  /// Stream frames through the DAC on its own thread. Keep a `FrameSender`
+  /// around to submit frames once the streamer has moved to the thread.
+  pub fn spawn(mut self, dac: Dac) -> Result<DacHandle, EtherdreamError> {
+    dac.spawn_stream(move |num_points| self.next_points(num_points))
+  }
+
+  /// Switch to the next queued frame, if there is one.
+  fn next_frame(&mut self) {
+    let frame = match self.queue.pop() {
+      Some(frame) => frame,
+      None => return,
+    };
+
+    if let (Some(from), Some(to)) = (self.current.last(), frame.first()) {
+      let steps = self.transition_points;
+      for i in 1 ..= steps {
+        let t = i as f64 / (steps + 1) as f64;
+        let x = from.x as f64 + (to.x as f64 - from.x as f64) * t;
+        let y = from.y as f64 + (to.y as f64 - from.y as f64) * t;
+        self.transition.push_back(Point::xy_blank(x as i16, y as i16));
+      }
+    }
+
+    self.current = frame;
+    self.position = 0;
+  }
+}
+
+impl Default for FrameStreamer {
+  fn default() -> FrameStreamer {
+    FrameStreamer::new()
+  }
+}
+
+impl FrameSender {
+  /// Queue a frame. It plays once the current frame finishes, and loops until
+  /// another frame is submitted. If too many frames are waiting, the oldest
+  /// is dropped. Empty frames are ignored, so the current frame keeps playing.
+  pub fn submit(&self, frame: Vec<Point>) {
+    if frame.is_empty() {
+      return;
+    }
+    let mut queue = self.queue.lock().unwrap();
+    if queue.frames.len() >= queue.max_queued {
+      queue.frames.pop_front();
+    }
+    queue.frames.push_back(frame);
+  }
+
+  /// The number of frames waiting to be played.
+  pub fn queued_frames(&self) -> usize {
+    self.queue.lock().unwrap().frames.len()
+  }
+
+  /// Drop every frame waiting to be played.
+  pub fn clear(&self) {
+    self.queue.lock().unwrap().frames.clear();
+  }
+
+  fn pop(&self) -> Option<Vec<Point>> {
+    self.queue.lock().unwrap().frames.pop_front()
+  }
+
+  fn max_queued(&self) -> usize {
+    self.queue.lock().unwrap().max_queued
+  }
+
+  fn set_max_queued(&self, max_queued: usize) {
+    let mut queue = self.queue.lock().unwrap();
+    queue.max_queued = max_queued;
+    while queue.frames.len() > max_queued {
+      queue.frames.pop_front();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame(xs: &[i16]) -> Vec<Point> {
+    xs.iter().map(|x| Point::xy_luma(*x, 0, 100)).collect()
+  }
+
+  fn xs(points: &[Point]) -> Vec<i16> {
+    points.iter().map(|p| p.x).collect()
+  }
+
+  #[test]
+  fn test_blank_before_first_frame() {
+    let mut streamer = FrameStreamer::new();
+    let points = streamer.next_points(3);
+    assert_eq!(3, points.len());
+    assert!(points.iter().all(|p| p.r == 0 && p.x == 0 && p.y == 0));
+  }
+
+  #[test]
+  fn test_loops_current_frame() {
+    let mut streamer = FrameStreamer::new();
+    streamer.submit(frame(&[1, 2, 3]));
+    assert_eq!(vec![1, 2, 3, 1, 2, 3, 1], xs(&streamer.next_points(7)));
+    assert_eq!(vec![2, 3], xs(&streamer.next_points(2)));
+  }
+
+  #[test]
+  fn test_switches_at_frame_boundary() {
+    let mut streamer = FrameStreamer::new();
+    streamer.submit(frame(&[1, 2, 3]));
+    assert_eq!(vec![1, 2], xs(&streamer.next_points(2)));
+
+    streamer.submit(frame(&[7, 8]));
+    assert_eq!(vec![3, 7, 8, 7], xs(&streamer.next_points(4)));
+  }
+
+  #[test]
+  fn test_transition_points() {
+    let mut streamer = FrameStreamer::new();
+    streamer.set_transition_points(3);
+    streamer.submit(frame(&[0]));
+    streamer.submit(frame(&[400]));
+
+    let points = streamer.next_points(5);
+    assert_eq!(vec![0, 100, 200, 300, 400], xs(&points));
+    assert_eq!(100, points[0].r);
+    assert!(points[1 .. 4].iter().all(|p| p.r == 0));
+    assert_eq!(100, points[4].r);
+  }
+
+  #[test]
+  fn test_queue_keeps_newest_frames() {
+    let mut streamer = FrameStreamer::new();
+    streamer.set_max_queued_frames(2);
+    let sender = streamer.sender();
+    for x in 1 .. 6 {
+      sender.submit(frame(&[x]));
+    }
+    assert_eq!(2, sender.queued_frames());
+    assert_eq!(vec![4, 5, 5], xs(&streamer.next_points(3)));
+  }
+
+  #[test]
+  fn test_empty_frames_ignored() {
+    let mut streamer = FrameStreamer::new();
+    streamer.submit(frame(&[1, 2]));
+    assert_eq!(vec![1], xs(&streamer.next_points(1)));
+
+    streamer.submit(Vec::new());
+    assert_eq!(0, streamer.sender().queued_frames());
+    assert_eq!(vec![2, 1, 2], xs(&streamer.next_points(3)));
+  }
+}
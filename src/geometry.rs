@@ -0,0 +1,356 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Geometric corrections applied to point positions on their way to the DAC.
+
+use crate::filter::blanked;
+use crate::protocol::Point;
+use crate::protocol::X_MAX;
+use crate::protocol::X_MIN;
+use crate::protocol::Y_MAX;
+use crate::protocol::Y_MIN;
+
+/// Place a position in DAC coordinates, saturating at the edges.
+pub fn saturate(x: f64, y: f64) -> (i16, i16) {
+  let x = x.round().max(X_MIN as f64).min(X_MAX as f64);
+  let y = y.round().max(Y_MIN as f64).min(Y_MAX as f64);
+  (x as i16, y as i16)
+}
+
+/// An affine transform for how a projector is mounted. Steps apply in the
+/// order the fields are listed: axes are swapped, then inverted, scaled,
+/// rotated about the origin and finally translated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+  /// Exchange the X and Y axes.
+  pub swap_axes: bool,
+  /// Mirror horizontally.
+  pub invert_x: bool,
+  /// Mirror vertically.
+  pub invert_y: bool,
+  /// Horizontal scale. 1.0 leaves the size unchanged.
+  pub scale_x: f64,
+  /// Vertical scale. 1.0 leaves the size unchanged.
+  pub scale_y: f64,
+  /// Counterclockwise rotation, in radians.
+  pub rotation: f64,
+  /// Horizontal offset, in DAC units.
+  pub translate_x: f64,
+  /// Vertical offset, in DAC units.
+  pub translate_y: f64,
+}
+
+impl Default for Transform {
+  fn default() -> Transform {
+    Transform {
+      swap_axes: false,
+      invert_x: false,
+      invert_y: false,
+      scale_x: 1.0,
+      scale_y: 1.0,
+      rotation: 0.0,
+      translate_x: 0.0,
+      translate_y: 0.0,
+    }
+  }
+}
+
+impl Transform {
+  /// Whether the transform leaves every point unchanged.
+  pub fn is_identity(&self) -> bool {
+    *self == Transform::default()
+  }
+
+  /// Transform a position, without saturating.
+  pub fn apply_xy(&self, x: f64, y: f64) -> (f64, f64) {
+    let (mut x, mut y) = if self.swap_axes { (y, x) } else { (x, y) };
+
+    if self.invert_x {
+      x = -x;
+    }
+    if self.invert_y {
+      y = -y;
+    }
+
+    x *= self.scale_x;
+    y *= self.scale_y;
+
+    let (sin, cos) = self.rotation.sin_cos();
+    let (x, y) = (x * cos - y * sin, x * sin + y * cos);
+
+    (x + self.translate_x, y + self.translate_y)
+  }
+
+  /// A copy of the point moved by the transform, saturated to the DAC's
+  /// coordinate range.
+  pub fn apply(&self, point: &Point) -> Point {
+    let (x, y) = self.apply_xy(point.x as f64, point.y as f64);
+    let (x, y) = saturate(x, y);
+    Point { x, y, ..*point }
+  }
+}
+
+/// Projective correction for a projector aimed at a surface from an angle,
+/// with optional radial correction for pincushion or barrel distortion.
+///
+/// The projective part is a homography that moves the corners of the DAC's
+/// full output range onto four chosen corners, such as ones dragged into place
+/// by a user until a rectangle looks square. Radial correction is applied
+/// after it. Points that land outside the DAC's range are clipped to the edge
+/// and blanked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keystone {
+  /// Maps the unit square onto the corners, row-major with the last entry 1.
+  matrix: [f64; 9],
+  radial: f64,
+}
+
+impl Default for Keystone {
+  fn default() -> Keystone {
+    Keystone {
+      matrix: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+      radial: 0.0,
+    }
+  }
+}
+
+impl Keystone {
+  /// CTOR. `corners` are where the bottom left, bottom right, top right and
+  /// top left corners of the output should land, in DAC coordinates. Returns
+  /// `None` unless they form a convex quadrilateral: if any three lie on a
+  /// line, a corner is bent inward, or the edges cross.
+  pub fn from_corners(corners: [(f64, f64); 4]) -> Option<Keystone> {
+    let unit : Vec<(f64, f64)> = corners.iter()
+        .map(|&(x, y)| to_unit(x, y))
+        .collect();
+
+    // A convex quadrilateral turns the same way at every corner.
+    let turns : Vec<f64> = (0 .. 4)
+        .map(|i| {
+          let (a, b, c) = (unit[i], unit[(i + 1) % 4], unit[(i + 2) % 4]);
+          (b.0 - a.0) * (c.1 - b.1) - (b.1 - a.1) * (c.0 - b.0)
+        })
+        .collect();
+    if !turns.iter().all(|&turn| turn > 1e-12)
+        && !turns.iter().all(|&turn| turn < -1e-12) {
+      return None;
+    }
+
+    let (x0, y0) = unit[0];
+    let (x1, y1) = unit[1];
+    let (x2, y2) = unit[2];
+    let (x3, y3) = unit[3];
+
+    let (dx1, dy1) = (x1 - x2, y1 - y2);
+    let (dx2, dy2) = (x3 - x2, y3 - y2);
+    let (dx3, dy3) = (x0 - x1 + x2 - x3, y0 - y1 + y2 - y3);
+
+    let denominator = dx1 * dy2 - dx2 * dy1;
+    if denominator.abs() < 1e-12 {
+      return None;
+    }
+
+    let g = (dx3 * dy2 - dx2 * dy3) / denominator;
+    let h = (dx1 * dy3 - dx3 * dy1) / denominator;
+
+    let matrix = [
+      x1 - x0 + g * x1, x3 - x0 + h * x3, x0,
+      y1 - y0 + g * y1, y3 - y0 + h * y3, y0,
+      g, h, 1.0,
+    ];
+
+    Some(Keystone { matrix, radial: 0.0 })
+  }
+
+  /// Add radial correction. Positive values push points outward in
+  /// proportion to the square of their distance from the center, bowing
+  /// lines into a pincushion that corrects barrel distortion; negative values
+  /// pull points inward and correct pincushion distortion.
+  pub fn with_radial(mut self, radial: f64) -> Keystone {
+    self.radial = radial;
+    self
+  }
+
+  /// The radial correction coefficient.
+  pub fn get_radial(&self) -> f64 {
+    self.radial
+  }
+
+  /// Whether the correction leaves every point unchanged.
+  pub fn is_identity(&self) -> bool {
+    *self == Keystone::default()
+  }
+
+  /// Correct a position, without clipping.
+  pub fn apply_xy(&self, x: f64, y: f64) -> (f64, f64) {
+    let (u, v) = to_unit(x, y);
+    let m = &self.matrix;
+    let w = m[6] * u + m[7] * v + m[8];
+    let (u, v) = ((m[0] * u + m[1] * v + m[2]) / w, (m[3] * u + m[4] * v + m[5]) / w);
+    let (x, y) = from_unit(u, v);
+
+    if self.radial == 0.0 {
+      return (x, y);
+    }
+
+    let (nx, ny) = (x / X_MAX as f64, y / Y_MAX as f64);
+    let factor = 1.0 + self.radial * (nx * nx + ny * ny);
+    (x * factor, y * factor)
+  }
+
+  /// A copy of the corrected point, clipped to the DAC's coordinate range.
+  pub fn apply(&self, point: &Point) -> Point {
+    let (x, y) = self.apply_xy(point.x as f64, point.y as f64);
+    let in_range = x >= X_MIN as f64 && x <= X_MAX as f64
+        && y >= Y_MIN as f64 && y <= Y_MAX as f64;
+    let (x, y) = saturate(x, y);
+    let point = Point { x, y, ..*point };
+    if in_range { point } else { blanked(&point) }
+  }
+}
+
+/// DAC coordinates to the unit square.
+fn to_unit(x: f64, y: f64) -> (f64, f64) {
+  ((x - X_MIN as f64) / (X_MAX as f64 - X_MIN as f64),
+      (y - Y_MIN as f64) / (Y_MAX as f64 - Y_MIN as f64))
+}
+
+/// The unit square to DAC coordinates.
+fn from_unit(u: f64, v: f64) -> (f64, f64) {
+  (X_MIN as f64 + u * (X_MAX as f64 - X_MIN as f64),
+      Y_MIN as f64 + v * (Y_MAX as f64 - Y_MIN as f64))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f64::consts::FRAC_PI_2;
+
+  fn xy(transform: &Transform, x: i16, y: i16) -> (i16, i16) {
+    let point = transform.apply(&Point::xy_blank(x, y));
+    (point.x, point.y)
+  }
+
+  #[test]
+  fn test_identity() {
+    let transform = Transform::default();
+    assert!(transform.is_identity());
+    assert_eq!((X_MIN, Y_MAX), xy(&transform, X_MIN, Y_MAX));
+  }
+
+  #[test]
+  fn test_swap_invert_rotate() {
+    let transform = Transform { swap_axes: true, invert_x: true, ..Transform::default() };
+    assert_eq!((-200, 100), xy(&transform, 100, 200));
+
+    let transform = Transform { rotation: FRAC_PI_2, ..Transform::default() };
+    assert_eq!((-200, 100), xy(&transform, 100, 200));
+  }
+
+  #[test]
+  fn test_saturates() {
+    let transform = Transform {
+      scale_x: 2.0,
+      translate_y: 1000.0,
+      ..Transform::default()
+    };
+    assert_eq!((X_MAX, Y_MAX), xy(&transform, 20000, 32000));
+    assert_eq!((X_MIN, -31000), xy(&transform, -20000, -32000));
+
+    // Inverting the minimum doesn't overflow.
+    let transform = Transform { invert_x: true, ..Transform::default() };
+    assert_eq!((X_MAX, 0), xy(&transform, X_MIN, 0));
+  }
+
+  const FULL : [(f64, f64); 4] = [
+    (X_MIN as f64, Y_MIN as f64),
+    (X_MAX as f64, Y_MIN as f64),
+    (X_MAX as f64, Y_MAX as f64),
+    (X_MIN as f64, Y_MAX as f64),
+  ];
+
+  #[test]
+  fn test_keystone_full_range_is_identity() {
+    let keystone = Keystone::from_corners(FULL).unwrap();
+    assert_eq!((1234, -5678), xy_keystone(&keystone, 1234, -5678));
+  }
+
+  #[test]
+  fn test_keystone_moves_corners() {
+    let corners = [
+      (-20000.0, -20000.0),
+      (20000.0, -20000.0),
+      (10000.0, 15000.0),
+      (-12000.0, 16000.0),
+    ];
+    let keystone = Keystone::from_corners(corners).unwrap();
+
+    for (corner, target) in FULL.iter().zip(corners.iter()) {
+      let (x, y) = keystone.apply_xy(corner.0, corner.1);
+      assert!((x - target.0).abs() < 0.5 && (y - target.1).abs() < 0.5);
+    }
+
+    // Straight lines stay straight: the midpoint of the bottom edge stays on
+    // the bottom edge.
+    let (_, y) = keystone.apply_xy(0.0, Y_MIN as f64);
+    assert!((y + 20000.0).abs() < 0.5);
+  }
+
+  #[test]
+  fn test_keystone_degenerate() {
+    let corners = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)];
+    assert!(Keystone::from_corners(corners).is_none());
+
+    // The first three corners on a line.
+    let corners = [(-100.0, 0.0), (0.0, 0.0), (100.0, 0.0), (0.0, 100.0)];
+    assert!(Keystone::from_corners(corners).is_none());
+  }
+
+  #[test]
+  fn test_keystone_rejects_bad_quads() {
+    // Edges crossing in a bow tie.
+    let corners = [(-100.0, -100.0), (100.0, 100.0), (100.0, -100.0), (-100.0, 100.0)];
+    assert!(Keystone::from_corners(corners).is_none());
+
+    // The top right corner bent inward.
+    let corners = [(-100.0, -100.0), (100.0, -100.0), (-50.0, -50.0), (-100.0, 100.0)];
+    assert!(Keystone::from_corners(corners).is_none());
+
+    // Mirrored is still convex.
+    let mirrored = [FULL[1], FULL[0], FULL[3], FULL[2]];
+    assert!(Keystone::from_corners(mirrored).is_some());
+  }
+
+  #[test]
+  fn test_keystone_radial_direction() {
+    // Positive bows the middle of an edge in relative to its corners, the
+    // pincushion shape that cancels barrel distortion.
+    let keystone = Keystone::default().with_radial(0.1);
+    let (corner, _) = keystone.apply_xy(10000.0, 10000.0);
+    let (middle, _) = keystone.apply_xy(10000.0, 0.0);
+    assert!(corner > 10000.0 && middle > 10000.0 && middle < corner);
+
+    let keystone = Keystone::default().with_radial(-0.1);
+    let (corner, _) = keystone.apply_xy(10000.0, 10000.0);
+    let (middle, _) = keystone.apply_xy(10000.0, 0.0);
+    assert!(corner < 10000.0 && middle > corner);
+  }
+
+  #[test]
+  fn test_keystone_radial_clips() {
+    let keystone = Keystone::default().with_radial(0.5);
+    assert_eq!((0, 0), xy_keystone(&keystone, 0, 0));
+
+    let point = keystone.apply(&Point::xy_luma(30000, 30000, 100));
+    assert_eq!((X_MAX, Y_MAX), (point.x, point.y));
+    assert_eq!(0, point.i);
+
+    let point = keystone.apply(&Point::xy_luma(10000, 0, 100));
+    assert!(point.x > 10000 && point.i == 100);
+  }
+
+  fn xy_keystone(keystone: &Keystone, x: i16, y: i16) -> (i16, i16) {
+    let point = keystone.apply(&Point::xy_blank(x, y));
+    (point.x, point.y)
+  }
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! A handle for controlling a stream running on its own thread.
+
+use crate::connection::Connection;
+use crate::control::OutputControl;
+use crate::control::StreamControl;
+use crate::dac::Dac;
+use crate::error::EtherdreamError;
+use crate::protocol::COMMAND_EMERGENCY_STOP;
+use crate::protocol::DacResponse;
+use crate::protocol::DacStatus;
+use crate::protocol::Point;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::thread::Builder;
+use std::thread::JoinHandle;
+
+/// A function that generates the requested number of points.
+pub type PointSource = Box<dyn FnMut(u16) -> Vec<Point> + Send>;
+
+/// The thread a stream runs on.
+type StreamThread = JoinHandle<Result<(), EtherdreamError>>;
+
+/// Controls a stream started with `Dac::spawn_stream`.
+/// Handles are cheap to clone and may be shared between threads. Once every
+/// handle is dropped, the stream shuts down according to the DAC's
+/// `ShutdownMode`.
+#[derive(Clone)]
+pub struct DacHandle {
+  control: Arc<StreamControl>,
+  connection: Connection,
+  source: Arc<Mutex<PointSource>>,
+  thread: Arc<Mutex<Option<StreamThread>>>,
+  _shutdown: Arc<ShutdownOnDrop>,
+}
+
+/// Shuts the stream down when the last handle goes away.
+struct ShutdownOnDrop {
+  control: Arc<StreamControl>,
+}
+
+impl Drop for ShutdownOnDrop {
+  fn drop(&mut self) {
+    self.control.request_shutdown();
+  }
+}
+
+impl DacHandle {
+  /// Start streaming from the source on a new thread.
+  pub(crate) fn spawn(mut dac: Dac, source: PointSource)
+      -> Result<DacHandle, EtherdreamError> {
+    let control = dac.control();
+    let connection = dac.connection();
+    let source = Arc::new(Mutex::new(source));
+    let thread_source = source.clone();
+
+    let thread = Builder::new()
+        .name("etherdream-stream".to_string())
+        .spawn(move || {
+          dac.play_function(|num_points| {
+            let mut make_points = thread_source.lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            make_points(num_points)
+          })
+        })?;
+
+    Ok(DacHandle {
+      control: control.clone(),
+      connection,
+      source,
+      thread: Arc::new(Mutex::new(Some(thread))),
+      _shutdown: Arc::new(ShutdownOnDrop { control }),
+    })
+  }
+
+  /// Replace the function generating points. Takes effect with the next
+  /// batch of points. This works even if the last source panicked.
+  pub fn set_source<F>(&self, make_points: F)
+      where F: FnMut(u16) -> Vec<Point> + Send + 'static {
+    *self.source.lock().unwrap_or_else(PoisonError::into_inner) =
+        Box::new(make_points);
+  }
+
+  /// Switch to a new point rate. The change is queued with the next batch of
+  /// points, so it takes effect once the DAC plays that batch.
+  pub fn set_point_rate(&self, point_rate: u32) {
+    self.control.request_point_rate(point_rate);
+  }
+
+  /// Blackout and pause controls for the stream.
+  pub fn output_control(&self) -> OutputControl {
+    OutputControl::new(self.control.clone())
+  }
+
+  /// Freeze output. The source isn't asked for points while paused; blank
+  /// points are sent at the park position instead.
+  pub fn set_paused(&self, paused: bool) {
+    self.control.set_paused(paused);
+  }
+
+  /// Whether output is paused.
+  pub fn is_paused(&self) -> bool {
+    self.control.is_paused()
+  }
+
+  /// Where the beam parks while paused. `None` holds the last position.
+  pub fn set_park_position(&self, position: Option<(i16, i16)>) {
+    self.control.set_park_position(position);
+  }
+
+  /// Turn the lasers off while the galvos keep tracking the source.
+  pub fn set_blackout(&self, blackout: bool) {
+    self.control.set_blackout(blackout);
+  }
+
+  /// Whether output is blacked out.
+  pub fn is_blackout(&self) -> bool {
+    self.control.is_blackout()
+  }
+
+  /// Put the DAC into the emergency stop state and end the stream. The
+  /// command is sent from the calling thread as soon as the DAC's response
+  /// to any command in flight arrives, so a stalled source can't hold it up.
+  pub fn emergency_stop(&self) -> Result<DacResponse, EtherdreamError> {
+    self.control.request_emergency_stop();
+    let response = self.connection.exchange(COMMAND_EMERGENCY_STOP)?;
+    DacResponse::parse(&response)
+  }
+
+  /// The most recent status reported by the DAC, if any.
+  pub fn status(&self) -> Option<DacStatus> {
+    self.control.status()
+  }
+
+  /// Stop playback and end the stream. Use `join` to wait for it to finish.
+  pub fn stop(&self) {
+    self.control.request_stop();
+  }
+
+  /// Wait for the stream to end and return its result. Only the first call
+  /// sees the result; later calls return immediately.
+  pub fn join(&self) -> Result<(), EtherdreamError> {
+    let thread = self.thread.lock().unwrap().take();
+    match thread {
+      None => Ok(()),
+      Some(thread) => match thread.join() {
+        Ok(result) => result,
+        Err(_) => Err(EtherdreamError::StreamPanicked),
+      },
+    }
+  }
+}
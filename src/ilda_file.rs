@@ -0,0 +1,739 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Loading, playing and writing ILDA (`.ild`) animation files.
+
+use crate::control::StreamControl;
+use crate::dac::BUFFER_CAPACITY;
+use crate::dac::DEFAULT_POINT_RATE;
+use crate::dac::Dac;
+use crate::error::EtherdreamError;
+use crate::filter::is_lit;
+use crate::handle::DacHandle;
+use crate::protocol::Point;
+use ilda::data::COLOR_PALETTE_SIZE;
+use ilda::data::ColorPalette;
+use ilda::data::HEADER_SIZE;
+use ilda::data::INDEXED_2D_DATA_SIZE;
+use ilda::data::INDEXED_3D_DATA_SIZE;
+use ilda::data::IndexedPoint2d;
+use ilda::data::IndexedPoint3d;
+use ilda::data::TRUE_COLOR_2D_DATA_SIZE;
+use ilda::data::TRUE_COLOR_3D_DATA_SIZE;
+use ilda::data::TrueColorPoint2d;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// "ILDA" in ASCII. Every header starts with it.
+const MAGIC : &[u8; 4] = b"ILDA";
+
+/// Status bit marking the last point of a frame.
+const STATUS_LAST_POINT : u8 = 0x80;
+
+/// Status bit marking a blanked point.
+const STATUS_BLANKING : u8 = 0x40;
+
+/// The ILDA standard palette, used by indexed frames until the file supplies
+/// its own.
+const DEFAULT_PALETTE : [(u8, u8, u8); 64] = [
+  (255, 0, 0), (255, 16, 0), (255, 32, 0), (255, 48, 0),
+  (255, 64, 0), (255, 80, 0), (255, 96, 0), (255, 112, 0),
+  (255, 128, 0), (255, 144, 0), (255, 160, 0), (255, 176, 0),
+  (255, 192, 0), (255, 208, 0), (255, 224, 0), (255, 240, 0),
+  (255, 255, 0), (224, 255, 0), (192, 255, 0), (160, 255, 0),
+  (128, 255, 0), (96, 255, 0), (64, 255, 0), (32, 255, 0),
+  (0, 255, 0), (0, 255, 36), (0, 255, 73), (0, 255, 109),
+  (0, 255, 146), (0, 255, 182), (0, 255, 219), (0, 255, 255),
+  (0, 227, 255), (0, 198, 255), (0, 170, 255), (0, 142, 255),
+  (0, 113, 255), (0, 85, 255), (0, 56, 255), (0, 28, 255),
+  (0, 0, 255), (32, 0, 255), (64, 0, 255), (96, 0, 255),
+  (128, 0, 255), (160, 0, 255), (192, 0, 255), (224, 0, 255),
+  (255, 0, 255), (255, 32, 255), (255, 64, 255), (255, 96, 255),
+  (255, 128, 255), (255, 160, 255), (255, 192, 255), (255, 224, 255),
+  (255, 255, 255), (255, 224, 224), (255, 192, 192), (255, 160, 160),
+  (255, 128, 128), (255, 96, 96), (255, 64, 64), (255, 32, 32),
+];
+
+/// A frame from an ILDA file.
+#[derive(Clone, Debug, Default)]
+pub struct IldaFrame {
+  /// The frame name from the header.
+  pub name: Option<String>,
+  /// The company name from the header.
+  pub company_name: Option<String>,
+  /// The projector the frame is meant for.
+  pub projector: u8,
+  /// The frame's points. The Z coordinate of 3D frames is dropped.
+  pub points: Vec<Point>,
+}
+
+/// The true color formats files can be written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IldaFormat {
+  /// Format 4, with a Z coordinate of zero.
+  TrueColor3d,
+  /// Format 5.
+  TrueColor2d,
+}
+
+/// The frames of an ILDA file. Formats 0, 1, 4 and 5 can be read, along with
+/// color palettes (format 2) for the indexed formats. Files are written in
+/// format 4 or 5.
+#[derive(Clone, Debug, Default)]
+pub struct IldaFile {
+  /// The frames, in file order.
+  pub frames: Vec<IldaFrame>,
+}
+
+impl IldaFile {
+  /// Frames made from lists of points, with no names.
+  pub fn from_frames(frames: Vec<Vec<Point>>) -> IldaFile {
+    IldaFile {
+      frames: frames.into_iter()
+          .map(|points| IldaFrame { points, ..IldaFrame::default() })
+          .collect(),
+    }
+  }
+
+  /// Frames cut from a recorded stream of points. A frame ends at each of
+  /// the `boundaries`, given as indices into `points`, and at the end of the
+  /// stream.
+  pub fn from_stream(points: &[Point], boundaries: &[usize]) -> IldaFile {
+    let mut frames = Vec::new();
+    let mut start = 0;
+
+    for end in boundaries.iter().chain(Some(&points.len())) {
+      let end = (*end).min(points.len());
+      if end > start {
+        frames.push(points[start .. end].to_vec());
+        start = end;
+      }
+    }
+
+    IldaFile::from_frames(frames)
+  }
+
+  /// Read an ILDA file from disk.
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<IldaFile, EtherdreamError> {
+    IldaFile::parse(&fs::read(path)?)
+  }
+
+  /// Parse the contents of an ILDA file. Reading stops at the end-of-file
+  /// header, or at the end of the data if there isn't one.
+  pub fn parse(bytes: &[u8]) -> Result<IldaFile, EtherdreamError> {
+    let mut frames = Vec::new();
+    let mut palette : Vec<(u8, u8, u8)> = DEFAULT_PALETTE.to_vec();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+      let header = match bytes.get(offset .. offset + HEADER_SIZE) {
+        Some(header) => header,
+        None => return Err(bad_file(offset, "truncated header")),
+      };
+
+      if &header[0 .. 4] != MAGIC {
+        return Err(bad_file(offset, "missing ILDA signature"));
+      }
+
+      let format = header[7];
+      let count = read_u16(&header[24 .. 26]) as usize;
+      offset += HEADER_SIZE;
+
+      if count == 0 {
+        break;
+      }
+
+      let record_size = match format {
+        0 => INDEXED_3D_DATA_SIZE,
+        1 => INDEXED_2D_DATA_SIZE,
+        2 => COLOR_PALETTE_SIZE,
+        4 => TRUE_COLOR_3D_DATA_SIZE,
+        5 => TRUE_COLOR_2D_DATA_SIZE,
+        _ => return Err(bad_file(offset - HEADER_SIZE, "unsupported format")),
+      };
+
+      let records = match bytes.get(offset .. offset + record_size * count) {
+        Some(records) => records,
+        None => return Err(bad_file(offset, "truncated records")),
+      };
+      offset += records.len();
+
+      let lookup = |index: i8| {
+        let index = index as u8 as usize;
+        palette.get(index).cloned().unwrap_or((255, 255, 255))
+      };
+
+      let points : Vec<Point> = match format {
+        0 => IndexedPoint3d::read_bytes(records)
+            .map_err(|_| bad_file(offset, "bad records"))?
+            .iter()
+            .map(|p| to_point(p.x, p.y, p.status_code, lookup(p.color_index)))
+            .collect(),
+        1 => IndexedPoint2d::read_bytes(records)
+            .map_err(|_| bad_file(offset, "bad records"))?
+            .iter()
+            .map(|p| to_point(p.x, p.y, p.status_code, lookup(p.color_index)))
+            .collect(),
+        2 => {
+          palette = ColorPalette::read_bytes(records)
+              .map_err(|_| bad_file(offset, "bad records"))?
+              .iter()
+              .map(|c| (c.r, c.g, c.b))
+              .collect();
+          continue;
+        },
+        // The ilda crate reads the colors of every format 4 point from the
+        // first record, so these are decoded here.
+        4 => records.chunks(TRUE_COLOR_3D_DATA_SIZE)
+            .map(|r| to_point(read_u16(&r[0 .. 2]) as i16,
+                read_u16(&r[2 .. 4]) as i16, r[6] as i8, (r[9], r[8], r[7])))
+            .collect(),
+        _ => TrueColorPoint2d::read_bytes(records)
+            .map_err(|_| bad_file(offset, "bad records"))?
+            .iter()
+            .map(|p| to_point(p.x, p.y, p.status_code, (p.r, p.g, p.b)))
+            .collect(),
+      };
+
+      frames.push(IldaFrame {
+        name: read_name(&header[8 .. 16]),
+        company_name: read_name(&header[16 .. 24]),
+        projector: header[30],
+        points,
+      });
+    }
+
+    Ok(IldaFile { frames })
+  }
+
+  /// Write the frames to disk.
+  pub fn save<P: AsRef<Path>>(&self, path: P, format: IldaFormat)
+      -> Result<(), EtherdreamError> {
+    fs::write(path, self.to_bytes(format)?)?;
+    Ok(())
+  }
+
+  /// Encode the frames, followed by the end-of-file header. Empty frames are
+  /// written as a single blanked point, since a header without records marks
+  /// the end of the file. Frames may hold at most 65535 points.
+  pub fn to_bytes(&self, format: IldaFormat) -> Result<Vec<u8>, EtherdreamError> {
+    if self.frames.len() > u16::MAX as usize {
+      return Err(EtherdreamError::BadIldaFile {
+        description: "more than 65535 frames".to_string(),
+      });
+    }
+
+    let (format_code, record_size) = match format {
+      IldaFormat::TrueColor3d => (4, TRUE_COLOR_3D_DATA_SIZE),
+      IldaFormat::TrueColor2d => (5, TRUE_COLOR_2D_DATA_SIZE),
+    };
+
+    let total = self.frames.len() as u16;
+    let mut bytes = Vec::new();
+
+    for (number, frame) in self.frames.iter().enumerate() {
+      let blank = [Point::xy_blank(0, 0)];
+      let points = if frame.points.is_empty() { &blank[..] } else { &frame.points[..] };
+
+      if points.len() > u16::MAX as usize {
+        return Err(EtherdreamError::BadIldaFile {
+          description: format!("frame {} has more than 65535 points", number),
+        });
+      }
+
+      write_header(&mut bytes, format_code, frame, points.len() as u16,
+          number as u16, total);
+      bytes.reserve(points.len() * record_size);
+
+      for (i, point) in points.iter().enumerate() {
+        let mut status = 0;
+        if i + 1 == points.len() {
+          status |= STATUS_LAST_POINT;
+        }
+
+        let (r, g, b) = if is_lit(point) {
+          to_rgb8(point)
+        } else {
+          status |= STATUS_BLANKING;
+          (0, 0, 0)
+        };
+
+        write_u16(&mut bytes, point.x as u16);
+        write_u16(&mut bytes, point.y as u16);
+        if format == IldaFormat::TrueColor3d {
+          write_u16(&mut bytes, 0);
+        }
+        bytes.extend_from_slice(&[status, b, g, r]);
+      }
+    }
+
+    let end = IldaFrame::default();
+    write_header(&mut bytes, format_code, &end, 0, 0, total);
+    Ok(bytes)
+  }
+}
+
+/// How an `IldaPlayer` behaves once it reaches the last frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayMode {
+  /// Start over from the first frame.
+  Loop,
+  /// Stop the stream after the last frame.
+  Once,
+}
+
+/// Plays ILDA frames through a `Dac`. Each frame is drawn repeatedly for one
+/// frame period, so the animation runs at the frame rate regardless of how
+/// many points each frame holds.
+pub struct IldaPlayer {
+  frames: Vec<Vec<Point>>,
+  frame_rate: f64,
+  point_rate: u32,
+  mode: PlayMode,
+  range: Range<usize>,
+  frame: usize,
+  position: usize,
+  points_left: usize,
+  last: Point,
+  finished: bool,
+}
+
+impl IldaPlayer {
+  /// CTOR. Plays every frame in a loop at 30 frames per second and the
+  /// default point rate.
+  pub fn new(file: IldaFile) -> IldaPlayer {
+    let frames : Vec<Vec<Point>> = file.frames.into_iter()
+        .map(|frame| frame.points)
+        .collect();
+
+    IldaPlayer {
+      range: 0 .. frames.len(),
+      frames,
+      frame_rate: 30.0,
+      point_rate: DEFAULT_POINT_RATE,
+      mode: PlayMode::Loop,
+      frame: 0,
+      position: 0,
+      points_left: 0,
+      last: Point::xy_blank(0, 0),
+      finished: false,
+    }
+  }
+
+  /// Frames shown per second.
+  pub fn get_frame_rate(&self) -> f64 {
+    self.frame_rate
+  }
+
+  /// Set the frames shown per second.
+  pub fn set_frame_rate(&mut self, frame_rate: f64) {
+    self.frame_rate = frame_rate;
+  }
+
+  /// Points sent per second. Playback sets the DAC to this rate.
+  pub fn get_point_rate(&self) -> u32 {
+    self.point_rate
+  }
+
+  /// Set the points sent per second.
+  pub fn set_point_rate(&mut self, point_rate: u32) {
+    self.point_rate = point_rate;
+  }
+
+  /// Whether playback loops or stops after the last frame.
+  pub fn get_mode(&self) -> PlayMode {
+    self.mode
+  }
+
+  /// Set whether playback loops or stops after the last frame.
+  pub fn set_mode(&mut self, mode: PlayMode) {
+    self.mode = mode;
+  }
+
+  /// The frames played, by index.
+  pub fn get_frame_range(&self) -> Range<usize> {
+    self.range.clone()
+  }
+
+  /// Play only some of the frames. The range is clipped to the frames in the
+  /// file, and playback restarts from its first frame.
+  pub fn set_frame_range(&mut self, range: Range<usize>) {
+    let end = range.end.min(self.frames.len());
+    self.range = range.start.min(end) .. end;
+    self.rewind();
+  }
+
+  /// Restart from the first frame in the range.
+  pub fn rewind(&mut self) {
+    self.frame = self.range.start;
+    self.position = 0;
+    self.points_left = 0;
+    self.finished = false;
+  }
+
+  /// Whether a single-shot player has shown its last frame.
+  pub fn is_finished(&self) -> bool {
+    self.finished
+  }
+
+  /// Generate the next `num_points` points. Once finished, blank points are
+  /// sent where the last frame ended.
+  pub fn next_points(&mut self, num_points: u16) -> Vec<Point> {
+    let num_points = num_points as usize;
+    let mut points = Vec::with_capacity(num_points);
+    let mut empty_frames = 0;
+
+    while points.len() < num_points && !self.finished {
+      // A whole pass over the range without a point would loop forever.
+      if empty_frames >= self.range.len().max(1) {
+        break;
+      }
+
+      let frame = match self.frames.get(self.frame) {
+        Some(frame) if self.range.contains(&self.frame) => frame,
+        _ => {
+          self.finished = true;
+          break;
+        },
+      };
+
+      if self.points_left == 0 && self.position == 0 {
+        self.points_left = self.frame_points(frame.len());
+      }
+
+      if frame.is_empty() {
+        self.points_left = 0;
+        empty_frames += 1;
+      } else {
+        empty_frames = 0;
+        self.last = frame[self.position];
+        points.push(self.last);
+        self.position = (self.position + 1) % frame.len();
+        self.points_left = self.points_left.saturating_sub(1);
+      }
+
+      // Only move on once the frame is drawn in full.
+      if self.points_left == 0 && self.position == 0 {
+        self.advance();
+      }
+    }
+
+    if points.len() < num_points {
+      let remaining = num_points - points.len();
+      points.extend(vec![Point::xy_blank(self.last.x, self.last.y); remaining]);
+    }
+
+    points
+  }
+
+  /// Stream on the current thread. Single-shot playback returns once the
+  /// last frame has played out of the DAC's buffer.
+  pub fn play(mut self, dac: &mut Dac) -> Result<(), EtherdreamError> {
+    dac.set_point_rate(self.point_rate);
+    let control = dac.control();
+    let mut drain = 0;
+    dac.play_function(|num_points| self.next_stream_points(num_points,
+        &control, &mut drain))
+  }
+
+  /// Stream on a new thread. Single-shot playback stops the stream once the
+  /// last frame has played out of the DAC's buffer.
+  pub fn spawn(mut self, mut dac: Dac) -> Result<DacHandle, EtherdreamError> {
+    dac.set_point_rate(self.point_rate);
+    let control = dac.control();
+    let mut drain = 0;
+    dac.spawn_stream(move |num_points| self.next_stream_points(num_points,
+        &control, &mut drain))
+  }
+
+  /// `next_points` for a stream. After finishing, a full buffer of blank
+  /// points is sent so the last frame plays out before the stream stops.
+  fn next_stream_points(&mut self, num_points: u16, control: &Arc<StreamControl>,
+      drain: &mut usize) -> Vec<Point> {
+    let points = self.next_points(num_points);
+    if self.finished {
+      if *drain >= BUFFER_CAPACITY as usize {
+        control.request_stop();
+      }
+      *drain += points.len();
+    }
+    points
+  }
+
+  /// How many points to draw a frame of the given length for, in whole
+  /// passes over the frame.
+  fn frame_points(&self, len: usize) -> usize {
+    if len == 0 || self.frame_rate <= 0.0 {
+      return len;
+    }
+    let period = self.point_rate as f64 / self.frame_rate;
+    let passes = (period / len as f64).round().max(1.0) as usize;
+    passes * len
+  }
+
+  fn advance(&mut self) {
+    self.frame += 1;
+    if self.frame >= self.range.end {
+      match self.mode {
+        PlayMode::Loop if !self.range.is_empty() => self.frame = self.range.start,
+        _ => self.finished = true,
+      }
+    }
+  }
+}
+
+fn to_point(x: i16, y: i16, status: i8, color: (u8, u8, u8)) -> Point {
+  if status as u8 & STATUS_BLANKING != 0 {
+    Point::xy_blank(x, y)
+  } else {
+    Point::xy_rgb(x, y, color.0 as u16 * 257, color.1 as u16 * 257,
+        color.2 as u16 * 257)
+  }
+}
+
+/// 8-bit color for a lit point. Points lit only through the intensity
+/// channel are written as white at that intensity.
+fn to_rgb8(point: &Point) -> (u8, u8, u8) {
+  let narrow = |value: u16| ((value as u32 * 255 + 32767) / 65535) as u8;
+  if point.r == 0 && point.g == 0 && point.b == 0 {
+    let i = narrow(point.i);
+    (i, i, i)
+  } else {
+    (narrow(point.r), narrow(point.g), narrow(point.b))
+  }
+}
+
+fn write_header(bytes: &mut Vec<u8>, format_code: u8, frame: &IldaFrame,
+    count: u16, number: u16, total: u16) {
+  bytes.extend_from_slice(MAGIC);
+  bytes.extend_from_slice(&[0, 0, 0, format_code]);
+  write_name(bytes, frame.name.as_ref());
+  write_name(bytes, frame.company_name.as_ref());
+  write_u16(bytes, count);
+  write_u16(bytes, number);
+  write_u16(bytes, total);
+  bytes.extend_from_slice(&[frame.projector, 0]);
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: Option<&String>) {
+  let mut field = [0u8; 8];
+  if let Some(name) = name {
+    for (byte, c) in field.iter_mut().zip(name.bytes()) {
+      *byte = c;
+    }
+  }
+  bytes.extend_from_slice(&field);
+}
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+  bytes.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+  ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+fn read_name(bytes: &[u8]) -> Option<String> {
+  let name : String = bytes.iter()
+      .take_while(|b| **b != 0)
+      .map(|b| *b as char)
+      .collect();
+  let name = name.trim_end().to_string();
+  if name.is_empty() { None } else { Some(name) }
+}
+
+fn bad_file(offset: usize, reason: &str) -> EtherdreamError {
+  EtherdreamError::BadIldaFile {
+    description: format!("byte {}: {}", offset, reason),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn header(format: u8, name: &str, count: u16) -> Vec<u8> {
+    let mut bytes = b"ILDA\0\0\0".to_vec();
+    bytes.push(format);
+    let mut name = name.as_bytes().to_vec();
+    name.resize(8, 0);
+    bytes.extend(name);
+    bytes.extend(b"etherdrm");
+    bytes.extend(&[(count >> 8) as u8, count as u8, 0, 0, 0, 1, 0, 0]);
+    bytes
+  }
+
+  fn xy(x: i16, y: i16) -> Vec<u8> {
+    vec![(x >> 8) as u8, x as u8, (y >> 8) as u8, y as u8]
+  }
+
+  #[test]
+  fn test_parse_true_color() {
+    let mut bytes = header(4, "cube", 2);
+    bytes.extend(xy(100, -100));
+    bytes.extend(&[0, 0, 0, 10, 20, 30]);
+    bytes.extend(xy(-32768, 32767));
+    bytes.extend(&[0, 0, STATUS_LAST_POINT | STATUS_BLANKING, 1, 2, 3]);
+    bytes.extend(header(5, "flat", 1));
+    bytes.extend(xy(5, 6));
+    bytes.extend(&[STATUS_LAST_POINT, 0, 0, 255]);
+    bytes.extend(header(5, "", 0));
+
+    let file = IldaFile::parse(&bytes).unwrap();
+    assert_eq!(2, file.frames.len());
+    assert_eq!(Some("cube".to_string()), file.frames[0].name);
+    assert_eq!(Some("etherdrm".to_string()), file.frames[0].company_name);
+
+    let p = file.frames[0].points[0];
+    assert_eq!((100, -100, 30 * 257, 20 * 257, 10 * 257), (p.x, p.y, p.r, p.g, p.b));
+    let p = file.frames[0].points[1];
+    assert_eq!((-32768, 32767, 0), (p.x, p.y, p.r));
+    let p = file.frames[1].points[0];
+    assert_eq!((5, 6, 65535, 0), (p.x, p.y, p.r, p.b));
+  }
+
+  #[test]
+  fn test_parse_indexed_with_palette() {
+    let mut bytes = header(1, "", 2);
+    bytes.extend(xy(1, 2));
+    bytes.extend(&[0, 24]); // Green in the default palette.
+    bytes.extend(xy(3, 4));
+    bytes.extend(&[STATUS_LAST_POINT, 0]);
+    bytes.extend(header(2, "", 1));
+    bytes.extend(&[0, 0, 255]);
+    bytes.extend(header(0, "", 1));
+    bytes.extend(xy(7, 8));
+    bytes.extend(&[0, 0, STATUS_LAST_POINT, 0]);
+
+    let file = IldaFile::parse(&bytes).unwrap();
+    assert_eq!(2, file.frames.len());
+
+    let p = file.frames[0].points[0];
+    assert_eq!((0, 65535, 0), (p.r, p.g, p.b));
+    let p = file.frames[0].points[1];
+    assert_eq!((65535, 0, 0), (p.r, p.g, p.b));
+    let p = file.frames[1].points[0];
+    assert_eq!((7, 8, 0, 0, 65535), (p.x, p.y, p.r, p.g, p.b));
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert!(IldaFile::parse(b"ILDA").is_err());
+    assert!(IldaFile::parse(&header(3, "", 1)).is_err());
+
+    let mut bytes = header(5, "", 2);
+    bytes.extend(xy(1, 2));
+    assert!(IldaFile::parse(&bytes).is_err());
+
+    let mut bytes = header(5, "", 0);
+    bytes[0] = b'X';
+    assert!(IldaFile::parse(&bytes).is_err());
+  }
+
+  fn file(frames: &[&[i16]]) -> IldaFile {
+    IldaFile {
+      frames: frames.iter()
+          .map(|xs| IldaFrame {
+            points: xs.iter().map(|x| Point::xy_luma(*x, 0, 100)).collect(),
+            ..IldaFrame::default()
+          })
+          .collect(),
+    }
+  }
+
+  fn xs(points: &[Point]) -> Vec<i16> {
+    points.iter().map(|p| p.x).collect()
+  }
+
+  #[test]
+  fn test_player_repeats_frames_for_frame_period() {
+    let mut player = IldaPlayer::new(file(&[&[1, 2], &[3, 4, 5]]));
+    player.set_point_rate(60);
+    player.set_frame_rate(10.0); // Six points per frame.
+
+    assert_eq!(vec![1, 2, 1, 2, 1, 2, 3, 4, 5, 3, 4, 5, 1, 2],
+        xs(&player.next_points(14)));
+  }
+
+  #[test]
+  fn test_player_once_with_range() {
+    let mut player = IldaPlayer::new(file(&[&[1], &[2], &[3], &[4]]));
+    player.set_point_rate(2);
+    player.set_frame_rate(1.0);
+    player.set_mode(PlayMode::Once);
+    player.set_frame_range(1 .. 3);
+
+    let points = player.next_points(6);
+    assert_eq!(vec![2, 2, 3, 3, 3, 3], xs(&points));
+    assert!(player.is_finished());
+    assert!(points[4 ..].iter().all(|p| p.i == 0));
+  }
+
+  #[test]
+  fn test_player_loops_empty_frames() {
+    let mut player = IldaPlayer::new(IldaFile::from_frames(vec![vec![]]));
+    let points = player.next_points(10);
+    assert_eq!(10, points.len());
+    assert!(points.iter().all(|p| p.i == 0));
+    assert!(!player.is_finished());
+  }
+
+  #[test]
+  fn test_write_round_trip() {
+    let mut file = IldaFile::from_frames(vec![
+      vec![
+        Point::xy_rgb(-32768, 32767, 65535, 257 * 10, 0),
+        Point::xy_blank(100, -100),
+        Point::xy_luma(5, 5, 257 * 128),
+      ],
+      vec![],
+    ]);
+    file.frames[0].name = Some("longframename".to_string());
+    file.frames[0].projector = 2;
+
+    for format in [IldaFormat::TrueColor3d, IldaFormat::TrueColor2d].iter() {
+      let bytes = file.to_bytes(*format).unwrap();
+      let parsed = IldaFile::parse(&bytes).unwrap();
+
+      assert_eq!(2, parsed.frames.len());
+      assert_eq!(Some("longfram".to_string()), parsed.frames[0].name);
+      assert_eq!(2, parsed.frames[0].projector);
+
+      let p = parsed.frames[0].points[0];
+      assert_eq!((-32768, 32767, 65535, 2570, 0), (p.x, p.y, p.r, p.g, p.b));
+      let p = parsed.frames[0].points[1];
+      assert_eq!((100, -100, false), (p.x, p.y, is_lit(&p)));
+      let p = parsed.frames[0].points[2];
+      assert_eq!((257 * 128, 257 * 128), (p.r, p.b));
+
+      assert_eq!(1, parsed.frames[1].points.len());
+      assert!(!is_lit(&parsed.frames[1].points[0]));
+    }
+  }
+
+  #[test]
+  fn test_write_header_and_status_bits() {
+    let file = IldaFile::from_frames(vec![
+      vec![Point::xy_luma(0, 0, 100), Point::xy_blank(0, 0)],
+    ]);
+    let bytes = file.to_bytes(IldaFormat::TrueColor2d).unwrap();
+
+    assert_eq!(32 + 2 * 8 + 32, bytes.len());
+    assert_eq!(b"ILDA", &bytes[0 .. 4]);
+    assert_eq!(5, bytes[7]);
+    assert_eq!(&[0, 2, 0, 0, 0, 1], &bytes[24 .. 30]);
+    assert_eq!(0, bytes[32 + 4]);
+    assert_eq!(STATUS_LAST_POINT | STATUS_BLANKING, bytes[32 + 8 + 4]);
+    assert_eq!(&[0, 0, 0, 0, 0, 1], &bytes[48 + 24 .. 48 + 30]);
+  }
+
+  #[test]
+  fn test_from_stream() {
+    let points : Vec<Point> = (0 .. 5).map(|x| Point::xy_blank(x, 0)).collect();
+    let file = IldaFile::from_stream(&points, &[2, 2, 4]);
+    let lens : Vec<usize> = file.frames.iter().map(|f| f.points.len()).collect();
+    assert_eq!(vec![2, 2, 1], lens);
+  }
+}
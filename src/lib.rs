@@ -11,20 +11,39 @@
 #![deny(unused_imports)]
 #![deny(unused_qualifications)]
 
-extern crate byteorder;
-extern crate net2;
+#[macro_use] extern crate log;
 extern crate point as pointlib;
 
+mod connection;
 mod error;
+mod watchdog;
 
+#[cfg(feature = "async")]
+pub mod async_dac;
+pub mod color;
+pub mod control;
 pub mod dac;
+pub mod dissect;
+pub mod filter;
+pub mod frame;
+pub mod geometry;
+pub mod handle;
+pub mod ilda_file;
 pub mod network;
+pub mod optimize;
+pub mod patterns;
+pub mod preview;
 pub mod protocol;
+pub mod record;
+pub mod render;
+pub mod safety;
+pub mod shutdown;
+pub mod simulate;
 
 pub mod point {
-  pub use pointlib::PipelinePoint;
-  pub use pointlib::SimplePoint;
-  pub use protocol::Point;
+  pub use crate::pointlib::PipelinePoint;
+  pub use crate::pointlib::SimplePoint;
+  pub use crate::protocol::Point;
 }
 
-pub use error::EtherdreamError;
+pub use crate::error::EtherdreamError;
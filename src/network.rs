@@ -3,10 +3,14 @@
 
 //! This module contains network-related functions unrelated to DAC control.
 
-use error::EtherdreamError;
+use crate::error::EtherdreamError;
+use crate::protocol::Broadcast;
 use net2::UdpBuilder;
-use protocol::Broadcast;
+use std::io::ErrorKind;
 use std::net::IpAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::time::Instant;
 
 /// The primary port for communications with the EtherDream.
 pub const COMMUNICATION_PORT : u16 = 7765;
@@ -27,10 +31,7 @@ pub struct SearchResult {
 /// Blocking function that will return the first EtherDream DAC it finds
 /// via listening for UDP broadcasts.
 pub fn find_first_dac() -> Result<SearchResult, EtherdreamError> {
-  let udp = UdpBuilder::new_v4()?;
-  udp.reuse_address(true)?;
-
-  let socket = udp.bind(("0.0.0.0", BROADCAST_PORT))?;
+  let socket = bind_broadcast_socket()?;
 
   let mut buf = [0u8; 128];
   let result = socket.recv_from(&mut buf)?;
@@ -39,7 +40,54 @@ pub fn find_first_dac() -> Result<SearchResult, EtherdreamError> {
 
   Ok(SearchResult {
     ip_address : result.1.ip(),
-    broadcast: broadcast,
+    broadcast,
   })
 }
 
+/// Blocking function that listens for UDP broadcasts for the given time and
+/// returns every EtherDream DAC heard from, in the order they were found.
+/// DACs broadcast once a second, so listen for at least that long.
+pub fn find_dacs(timeout: Duration) -> Result<Vec<SearchResult>, EtherdreamError> {
+  let socket = bind_broadcast_socket()?;
+  let deadline = Instant::now() + timeout;
+  let mut results : Vec<SearchResult> = Vec::new();
+
+  let mut buf = [0u8; 128];
+  loop {
+    let remaining = match deadline.checked_duration_since(Instant::now()) {
+      Some(remaining) if remaining > Duration::from_millis(0) => remaining,
+      _ => break,
+    };
+    socket.set_read_timeout(Some(remaining))?;
+
+    let (len, address) = match socket.recv_from(&mut buf) {
+      Ok(received) => received,
+      Err(ref e) if e.kind() == ErrorKind::WouldBlock
+          || e.kind() == ErrorKind::TimedOut => break,
+      Err(e) => return Err(e.into()),
+    };
+
+    // Ignore anything on the port that isn't a broadcast.
+    let broadcast = match Broadcast::parse(&buf[.. len]) {
+      Ok(broadcast) => broadcast,
+      Err(_) => continue,
+    };
+
+    let known = results.iter()
+        .any(|r| r.broadcast.mac_address == broadcast.mac_address);
+    if !known {
+      results.push(SearchResult { ip_address: address.ip(), broadcast });
+    }
+  }
+
+  Ok(results)
+}
+
+/// Bind a UDP socket to the broadcast port. Other programs may listen on the
+/// same port at the same time.
+pub(crate) fn bind_broadcast_socket() -> Result<UdpSocket, EtherdreamError> {
+  let udp = UdpBuilder::new_v4()?;
+  udp.reuse_address(true)?;
+  Ok(udp.bind(("0.0.0.0", BROADCAST_PORT))?)
+}
+
@@ -0,0 +1,343 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Ordering the shapes in a frame to keep blanked travel short.
+//!
+//! Every unit the beam travels with the lasers off is time not spent drawing,
+//! so a frame of many separate shapes flickers less when they're drawn in a
+//! good order and direction. The optimizer finds one with a nearest-neighbour
+//! tour improved by 2-opt, then joins the shapes with blanked moves.
+
+use crate::protocol::Point;
+
+/// Where a polyline sits in a route, and which way it's drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Leg {
+  /// The polyline's index in the input.
+  pub index: usize,
+  /// Whether an open polyline is drawn from its last point to its first.
+  pub reversed: bool,
+  /// The vertex a closed polyline starts and ends at. Zero for open ones.
+  pub start: usize,
+}
+
+/// The order to draw polylines in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+  /// The polylines, in drawing order. Empty polylines are left out.
+  pub legs: Vec<Leg>,
+  /// The total blanked travel in DAC units, including the move from the end
+  /// of the last polyline back to the start of the first.
+  pub travel: f64,
+}
+
+/// Orders polylines and joins them into a frame.
+///
+/// A polyline whose last point is at its first is closed, and may be started
+/// at any vertex. Open polylines may be drawn in either direction. Lit lines
+/// come out exactly as given, so add a `VelocityLimiter` if they have long
+/// segments.
+#[derive(Clone, Debug)]
+pub struct PathOptimizer {
+  blank_step: f64,
+  dwell: usize,
+  max_passes: usize,
+}
+
+impl Default for PathOptimizer {
+  fn default() -> PathOptimizer {
+    PathOptimizer::new()
+  }
+}
+
+impl PathOptimizer {
+  /// CTOR. Blanked moves step at most 1000 units per point and dwell for 4
+  /// points at each end.
+  pub fn new() -> PathOptimizer {
+    PathOptimizer {
+      blank_step: 1000.0,
+      dwell: 4,
+      max_passes: 20,
+    }
+  }
+
+  /// The longest step between points of a blanked move, in DAC units.
+  pub fn with_blank_step(mut self, blank_step: f64) -> PathOptimizer {
+    self.blank_step = blank_step.max(1.0);
+    self
+  }
+
+  /// Blank points held at each end of a blanked move, so the lasers are off
+  /// before the galvos move and the galvos settle before the lasers come on.
+  pub fn with_dwell(mut self, dwell: usize) -> PathOptimizer {
+    self.dwell = dwell;
+    self
+  }
+
+  /// Limit how many times 2-opt sweeps the route looking for improvements.
+  pub fn with_max_passes(mut self, max_passes: usize) -> PathOptimizer {
+    self.max_passes = max_passes;
+    self
+  }
+
+  /// The longest step between points of a blanked move.
+  pub fn get_blank_step(&self) -> f64 {
+    self.blank_step
+  }
+
+  /// Blank points held at each end of a blanked move.
+  pub fn get_dwell(&self) -> usize {
+    self.dwell
+  }
+
+  /// Order the polylines, then join them into a frame that ends back where
+  /// it starts, so it can loop.
+  pub fn optimize(&self, polylines: &[Vec<Point>]) -> Vec<Point> {
+    let route = self.plan(polylines);
+    self.join(polylines, &route)
+  }
+
+  /// Find a short route through the polylines.
+  pub fn plan(&self, polylines: &[Vec<Point>]) -> Route {
+    let mut remaining : Vec<usize> = (0 .. polylines.len())
+        .filter(|&i| !polylines[i].is_empty())
+        .collect();
+    if remaining.is_empty() {
+      return Route { legs: Vec::new(), travel: 0.0 };
+    }
+
+    // Nearest neighbour, starting from the first polyline as given.
+    let first = remaining.remove(0);
+    let mut legs = vec![Leg { index: first, reversed: false, start: 0 }];
+    while !remaining.is_empty() {
+      let from = exit(polylines, legs.last().unwrap());
+      let mut best = None;
+      for (position, &index) in remaining.iter().enumerate() {
+        for leg in options(polylines, index) {
+          let cost = distance(from, entry(polylines, &leg));
+          if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+            best = Some((cost, position, leg));
+          }
+        }
+      }
+      let (_, position, leg) = best.unwrap();
+      remaining.remove(position);
+      legs.push(leg);
+    }
+
+    self.two_opt(polylines, &mut legs);
+
+    let travel = travel(polylines, &legs);
+    Route { legs, travel }
+  }
+
+  /// Draw the polylines along a route, joined by blanked moves.
+  pub fn join(&self, polylines: &[Vec<Point>], route: &Route) -> Vec<Point> {
+    let mut points = Vec::new();
+    for leg in route.legs.iter() {
+      let polyline = &polylines[leg.index];
+      if let Some(last) = points.last().cloned() {
+        self.blank_move(&mut points, &last, entry(polylines, leg));
+      }
+      if leg.reversed {
+        points.extend(polyline.iter().rev());
+      } else if leg.start > 0 {
+        points.extend(&polyline[leg.start ..]);
+        points.extend(&polyline[1 ..= leg.start]);
+      } else {
+        points.extend(polyline);
+      }
+    }
+
+    if let (Some(last), Some(leg)) = (points.last().cloned(), route.legs.first()) {
+      self.blank_move(&mut points, &last, entry(polylines, leg));
+    }
+    points
+  }
+
+  /// Reverse stretches of the route while that shortens it.
+  fn two_opt(&self, polylines: &[Vec<Point>], legs: &mut [Leg]) {
+    let n = legs.len();
+    for _ in 0 .. self.max_passes {
+      let mut improved = false;
+      for i in 1 .. n {
+        for j in i + 1 .. n {
+          let before = exit(polylines, &legs[i - 1]);
+          let after = entry(polylines, &legs[(j + 1) % n]);
+          let current = distance(before, entry(polylines, &legs[i]))
+              + distance(exit(polylines, &legs[j]), after);
+          // Reversed, leg j leads off from where it used to end, and leg i
+          // leads back from where it used to start.
+          let changed = distance(before, exit(polylines, &legs[j]))
+              + distance(entry(polylines, &legs[i]), after);
+
+          if changed + 1e-9 < current {
+            legs[i ..= j].reverse();
+            for leg in legs[i ..= j].iter_mut() {
+              if !is_closed(&polylines[leg.index]) {
+                leg.reversed = !leg.reversed;
+              }
+            }
+            improved = true;
+          }
+        }
+      }
+      if !improved {
+        break;
+      }
+    }
+  }
+
+  /// Dwell at the start, travel blanked, and dwell at the end.
+  fn blank_move(&self, points: &mut Vec<Point>, from: &Point, to: &Point) {
+    let hold = |points: &mut Vec<Point>, point: &Point, count: usize| {
+      points.extend(vec![Point::xy_blank(point.x, point.y); count]);
+    };
+
+    hold(points, from, self.dwell);
+    let steps = (distance(from, to) / self.blank_step).ceil() as i32;
+    for step in 1 .. steps {
+      let x = from.x as i32 + (to.x as i32 - from.x as i32) * step / steps;
+      let y = from.y as i32 + (to.y as i32 - from.y as i32) * step / steps;
+      points.push(Point::xy_blank(x as i16, y as i16));
+    }
+    hold(points, to, self.dwell.max(1));
+  }
+}
+
+/// Whether a polyline ends where it starts.
+fn is_closed(polyline: &[Point]) -> bool {
+  polyline.len() > 2 && {
+    let (first, last) = (&polyline[0], &polyline[polyline.len() - 1]);
+    first.x == last.x && first.y == last.y
+  }
+}
+
+/// Every way to draw a polyline.
+fn options(polylines: &[Vec<Point>], index: usize) -> Vec<Leg> {
+  let polyline = &polylines[index];
+  if is_closed(polyline) {
+    (0 .. polyline.len() - 1)
+        .map(|start| Leg { index, reversed: false, start })
+        .collect()
+  } else {
+    vec![
+      Leg { index, reversed: false, start: 0 },
+      Leg { index, reversed: true, start: 0 },
+    ]
+  }
+}
+
+fn entry<'a>(polylines: &'a [Vec<Point>], leg: &Leg) -> &'a Point {
+  let polyline = &polylines[leg.index];
+  if leg.reversed { &polyline[polyline.len() - 1] } else { &polyline[leg.start] }
+}
+
+fn exit<'a>(polylines: &'a [Vec<Point>], leg: &Leg) -> &'a Point {
+  let polyline = &polylines[leg.index];
+  if leg.reversed {
+    &polyline[0]
+  } else if is_closed(polyline) {
+    &polyline[leg.start]
+  } else {
+    &polyline[polyline.len() - 1]
+  }
+}
+
+fn travel(polylines: &[Vec<Point>], legs: &[Leg]) -> f64 {
+  (0 .. legs.len())
+      .map(|i| distance(exit(polylines, &legs[i]),
+          entry(polylines, &legs[(i + 1) % legs.len()])))
+      .sum()
+}
+
+fn distance(a: &Point, b: &Point) -> f64 {
+  (b.x as f64 - a.x as f64).hypot(b.y as f64 - a.y as f64)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filter::is_lit;
+  use crate::protocol::COLOR_MAX;
+
+  fn line(from: (i16, i16), to: (i16, i16)) -> Vec<Point> {
+    vec![
+      Point::xy_luma(from.0, from.1, COLOR_MAX),
+      Point::xy_luma(to.0, to.1, COLOR_MAX),
+    ]
+  }
+
+  #[test]
+  fn test_reorders_and_reverses() {
+    // Four dashes along a line, scrambled and some backwards.
+    let polylines = vec![
+      line((0, 0), (1000, 0)),
+      line((5000, 0), (4000, 0)),
+      line((2000, 0), (3000, 0)),
+      line((7000, 0), (6000, 0)),
+    ];
+    let route = PathOptimizer::new().plan(&polylines);
+
+    let order : Vec<(usize, bool)> = route.legs.iter()
+        .map(|leg| (leg.index, leg.reversed))
+        .collect();
+    assert_eq!(vec![(0, false), (2, false), (1, true), (3, true)], order);
+    assert_eq!(3000.0 + 7000.0, route.travel);
+  }
+
+  #[test]
+  fn test_two_opt_untangles() {
+    // Dots along a line, which nearest neighbour from the first visits
+    // zigzagging back and forth.
+    let polylines : Vec<Vec<Point>> = [0, 1000, -1500, 4000, -7000].iter()
+        .map(|&x| vec![Point::xy_luma(x, 0, COLOR_MAX)])
+        .collect();
+    let optimizer = PathOptimizer::new();
+    let unimproved = optimizer.clone().with_max_passes(0).plan(&polylines);
+    let improved = optimizer.plan(&polylines);
+    assert_eq!(27000.0, unimproved.travel);
+    assert_eq!(22000.0, improved.travel);
+  }
+
+  #[test]
+  fn test_closed_polylines_start_anywhere() {
+    let square = vec![
+      Point::xy_luma(0, 0, COLOR_MAX),
+      Point::xy_luma(1000, 0, COLOR_MAX),
+      Point::xy_luma(1000, 1000, COLOR_MAX),
+      Point::xy_luma(0, 1000, COLOR_MAX),
+      Point::xy_luma(0, 0, COLOR_MAX),
+    ];
+    let polylines = vec![line((3000, 1000), (2000, 1000)), square];
+    let route = PathOptimizer::new().plan(&polylines);
+
+    assert_eq!(Leg { index: 1, reversed: false, start: 2 }, route.legs[1]);
+    assert_eq!(1000.0 + 2000.0, route.travel);
+  }
+
+  #[test]
+  fn test_join() {
+    let polylines = vec![line((0, 0), (1000, 0)), Vec::new(), line((5000, 0), (3000, 0))];
+    let optimizer = PathOptimizer::new().with_blank_step(500.0).with_dwell(2);
+    let points = optimizer.optimize(&polylines);
+
+    let lit : Vec<(i16, i16)> = points.iter()
+        .filter(|p| is_lit(p))
+        .map(|p| (p.x, p.y))
+        .collect();
+    assert_eq!(vec![(0, 0), (1000, 0), (3000, 0), (5000, 0)], lit);
+
+    // Blanked moves take steps no longer than the blank step.
+    for pair in points.windows(2).filter(|pair| !is_lit(&pair[1])) {
+      let step = distance(&pair[0], &pair[1]);
+      assert!(step <= 500.0, "step {}", step);
+    }
+
+    // It ends back at the start, blanked, after dwelling.
+    let last = points[points.len() - 1];
+    assert_eq!((0, 0), (last.x, last.y));
+    assert!(!is_lit(&last));
+    assert!(PathOptimizer::new().optimize(&[]).is_empty());
+  }
+}
@@ -0,0 +1,361 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Test patterns for aligning projectors and checking scanner performance.
+//!
+//! Each pattern is one frame of points that starts and ends at the same
+//! place, so it can loop, for example through a `FrameStreamer`. Lines are
+//! sampled for the point rate they'll be played at, so a pattern draws at the
+//! same speed whatever the rate.
+
+use crate::dac::DEFAULT_POINT_RATE;
+use crate::geometry::saturate;
+use crate::protocol::COLOR_MAX;
+use crate::protocol::Point;
+use crate::protocol::X_MAX;
+use crate::protocol::Y_MAX;
+use std::f64::consts::PI;
+
+/// How fast lit lines are drawn, in DAC units per second: the full width in
+/// ten milliseconds.
+const LINE_SPEED : f64 = 6_553_500.0;
+
+/// How fast blanked moves are made, in DAC units per second.
+const BLANK_SPEED : f64 = 2.0 * LINE_SPEED;
+
+/// How long the beam holds still at corners and around blanked moves, in
+/// seconds.
+const DWELL : f64 = 0.000_2;
+
+/// Lines in each direction of the grid.
+const GRID_LINES : usize = 5;
+
+/// Rungs of the scan-speed ladder. Each is drawn twice as fast as the one
+/// above, from a quarter of the usual line speed upwards.
+const LADDER_RUNGS : i32 = 6;
+
+/// A test pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+  /// After the ILDA test pattern: a square with an inscribed circle and a
+  /// center cross. When the scanners are tuned for the point rate, the circle
+  /// touches the middle of each side and the cross sits at the center.
+  IldaTestPattern,
+  /// A horizontal and a vertical line through the center.
+  Crosshair,
+  /// Evenly spaced horizontal and vertical lines.
+  Grid,
+  /// Stacked lines in red, green, blue, yellow, cyan, magenta and white.
+  ColorBars,
+  /// The outline of the pattern area.
+  BoundingBox,
+  /// Horizontal lines drawn at increasing speeds, top to bottom. Lines the
+  /// scanners can't keep up with come out short and rounded.
+  ScanLadder,
+}
+
+impl Pattern {
+  /// Every pattern.
+  pub const ALL : [Pattern; 6] = [
+    Pattern::IldaTestPattern,
+    Pattern::Crosshair,
+    Pattern::Grid,
+    Pattern::ColorBars,
+    Pattern::BoundingBox,
+    Pattern::ScanLadder,
+  ];
+
+  /// The pattern's name, as used on the command line.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Pattern::IldaTestPattern => "ilda",
+      Pattern::Crosshair => "crosshair",
+      Pattern::Grid => "grid",
+      Pattern::ColorBars => "color-bars",
+      Pattern::BoundingBox => "bounding-box",
+      Pattern::ScanLadder => "scan-ladder",
+    }
+  }
+
+  /// Look a pattern up by name.
+  pub fn from_name(name: &str) -> Option<Pattern> {
+    Pattern::ALL.iter().cloned().find(|pattern| pattern.name() == name)
+  }
+
+  /// Generate one frame of the pattern.
+  pub fn frame(&self, settings: &PatternSettings) -> Vec<Point> {
+    let mut pen = Pen::new(settings);
+    match *self {
+      Pattern::IldaTestPattern => {
+        pen.rectangle(1.0, 1.0);
+        pen.circle(1.0);
+        pen.move_to(-0.25, 0.0);
+        pen.line_to(0.25, 0.0);
+        pen.move_to(0.0, -0.25);
+        pen.line_to(0.0, 0.25);
+      },
+      Pattern::Crosshair => {
+        pen.move_to(-1.0, 0.0);
+        pen.line_to(1.0, 0.0);
+        pen.move_to(0.0, -1.0);
+        pen.line_to(0.0, 1.0);
+      },
+      Pattern::Grid => {
+        // Alternate directions to keep blanked moves short.
+        for i in 0 .. GRID_LINES {
+          let y = 1.0 - 2.0 * i as f64 / (GRID_LINES - 1) as f64;
+          let (from, to) = if i % 2 == 0 { (-1.0, 1.0) } else { (1.0, -1.0) };
+          pen.move_to(from, y);
+          pen.line_to(to, y);
+        }
+        for i in 0 .. GRID_LINES {
+          let x = -1.0 + 2.0 * i as f64 / (GRID_LINES - 1) as f64;
+          let (from, to) = if i % 2 == 0 { (-1.0, 1.0) } else { (1.0, -1.0) };
+          pen.move_to(x, from);
+          pen.line_to(x, to);
+        }
+      },
+      Pattern::ColorBars => {
+        let (r, g, b) = settings.color;
+        let colors = [
+          (r, 0, 0),
+          (0, g, 0),
+          (0, 0, b),
+          (r, g, 0),
+          (0, g, b),
+          (r, 0, b),
+          (r, g, b),
+        ];
+        for (i, color) in colors.iter().enumerate() {
+          let y = 1.0 - 2.0 * i as f64 / (colors.len() - 1) as f64;
+          let (from, to) = if i % 2 == 0 { (-1.0, 1.0) } else { (1.0, -1.0) };
+          pen.color = *color;
+          pen.move_to(from, y);
+          pen.line_to(to, y);
+        }
+      },
+      Pattern::BoundingBox => {
+        pen.rectangle(1.0, 1.0);
+      },
+      Pattern::ScanLadder => {
+        for rung in 0 .. LADDER_RUNGS {
+          let y = 1.0 - 2.0 * rung as f64 / (LADDER_RUNGS - 1) as f64;
+          pen.speed = LINE_SPEED * 2f64.powi(rung - 2);
+          pen.move_to(-1.0, y);
+          pen.line_to(1.0, y);
+          pen.line_to(-1.0, y);
+        }
+      },
+    }
+    pen.finish()
+  }
+}
+
+/// How a pattern is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PatternSettings {
+  /// How much of the DAC's range the pattern covers, from 0.0 to 1.0.
+  pub size: f64,
+  /// The red, green and blue levels of lit points. Intensity is set to the
+  /// brightest of the three.
+  pub color: (u16, u16, u16),
+  /// The point rate the pattern will be played at.
+  pub point_rate: u32,
+}
+
+impl Default for PatternSettings {
+  fn default() -> PatternSettings {
+    PatternSettings {
+      size: 0.8,
+      color: (COLOR_MAX, COLOR_MAX, COLOR_MAX),
+      point_rate: DEFAULT_POINT_RATE,
+    }
+  }
+}
+
+/// Draws in pattern coordinates, where -1.0 to 1.0 spans the pattern area.
+struct Pen {
+  points: Vec<Point>,
+  scale: (f64, f64),
+  point_rate: f64,
+  dwell: usize,
+  speed: f64,
+  color: (u16, u16, u16),
+  position: (f64, f64),
+}
+
+impl Pen {
+  fn new(settings: &PatternSettings) -> Pen {
+    let size = settings.size.clamp(0.0, 1.0);
+    let point_rate = settings.point_rate.max(1) as f64;
+    Pen {
+      points: Vec::new(),
+      scale: (size * X_MAX as f64, size * Y_MAX as f64),
+      point_rate,
+      dwell: (DWELL * point_rate).ceil().max(1.0) as usize,
+      speed: LINE_SPEED,
+      color: settings.color,
+      position: (0.0, 0.0),
+    }
+  }
+
+  /// Blanked move, dwelling before and after. The frame starts at the
+  /// first move.
+  fn move_to(&mut self, x: f64, y: f64) {
+    if self.points.is_empty() {
+      self.position = (x, y);
+      self.hold(false);
+      return;
+    }
+    if self.position == (x, y) {
+      return;
+    }
+    self.hold(false);
+    self.travel(x, y, BLANK_SPEED, false);
+    self.hold(false);
+  }
+
+  /// Lit line, dwelling at the end so the corner is sharp.
+  fn line_to(&mut self, x: f64, y: f64) {
+    let speed = self.speed;
+    self.travel(x, y, speed, true);
+    self.hold(true);
+  }
+
+  fn rectangle(&mut self, half_width: f64, half_height: f64) {
+    self.move_to(-half_width, -half_height);
+    self.line_to(half_width, -half_height);
+    self.line_to(half_width, half_height);
+    self.line_to(-half_width, half_height);
+    self.line_to(-half_width, -half_height);
+  }
+
+  /// A circle about the center, starting and ending on the right.
+  fn circle(&mut self, radius: f64) {
+    self.move_to(radius, 0.0);
+    let circumference = 2.0 * PI * radius * self.scale.0.max(self.scale.1);
+    let steps = self.steps(circumference, self.speed);
+    for step in 1 ..= steps {
+      let angle = 2.0 * PI * step as f64 / steps as f64;
+      self.push(radius * angle.cos(), radius * angle.sin(), true);
+    }
+    self.hold(true);
+  }
+
+  /// Return to the start, so the frame can loop.
+  fn finish(mut self) -> Vec<Point> {
+    if let Some(first) = self.points.first() {
+      let x = first.x as f64 / self.scale.0.max(1.0);
+      let y = first.y as f64 / self.scale.1.max(1.0);
+      self.move_to(x, y);
+    }
+    self.points
+  }
+
+  fn travel(&mut self, x: f64, y: f64, speed: f64, lit: bool) {
+    let (x0, y0) = self.position;
+    let distance = ((x - x0) * self.scale.0).hypot((y - y0) * self.scale.1);
+    let steps = self.steps(distance, speed);
+    for step in 1 ..= steps {
+      let t = step as f64 / steps as f64;
+      self.push(x0 + (x - x0) * t, y0 + (y - y0) * t, lit);
+    }
+  }
+
+  /// Repeat the current position.
+  fn hold(&mut self, lit: bool) {
+    let (x, y) = self.position;
+    for _ in 0 .. self.dwell {
+      self.push(x, y, lit);
+    }
+  }
+
+  /// Points needed to cover a distance in DAC units at a speed.
+  fn steps(&self, distance: f64, speed: f64) -> usize {
+    (distance / (speed / self.point_rate)).ceil().max(1.0) as usize
+  }
+
+  fn push(&mut self, x: f64, y: f64, lit: bool) {
+    self.position = (x, y);
+    let (dac_x, dac_y) = saturate(x * self.scale.0, y * self.scale.1);
+    let point = if lit {
+      let (r, g, b) = self.color;
+      Point { i: r.max(g).max(b), ..Point::xy_rgb(dac_x, dac_y, r, g, b) }
+    } else {
+      Point::xy_blank(dac_x, dac_y)
+    };
+    self.points.push(point);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::filter::is_lit;
+
+  #[test]
+  fn test_names() {
+    for pattern in Pattern::ALL.iter() {
+      assert_eq!(Some(*pattern), Pattern::from_name(pattern.name()));
+    }
+    assert_eq!(None, Pattern::from_name("nope"));
+  }
+
+  #[test]
+  fn test_frames_stay_in_bounds_and_loop() {
+    let settings = PatternSettings { size: 0.5, ..PatternSettings::default() };
+    let limit = (X_MAX as f64 * 0.5).ceil() as i16;
+
+    for pattern in Pattern::ALL.iter() {
+      let frame = pattern.frame(&settings);
+      assert!(frame.iter().any(is_lit), "{:?}", pattern);
+      assert!(frame.iter().all(|p| p.x.abs() <= limit && p.y.abs() <= limit));
+
+      let (first, last) = (frame[0], frame[frame.len() - 1]);
+      assert_eq!((first.x, first.y), (last.x, last.y), "{:?}", pattern);
+      assert!(!is_lit(&last));
+
+      // No jump is bigger than a blanked move's step, except on the ladder's
+      // deliberately fast rungs.
+      let speed = match *pattern {
+        Pattern::ScanLadder => LINE_SPEED * 2f64.powi(LADDER_RUNGS - 3),
+        _ => BLANK_SPEED,
+      };
+      let max_step = speed / settings.point_rate as f64 + 1.0;
+      for pair in frame.windows(2) {
+        let step = (pair[1].x as f64 - pair[0].x as f64)
+            .hypot(pair[1].y as f64 - pair[0].y as f64);
+        assert!(step <= max_step, "{:?} steps {}", pattern, step);
+      }
+    }
+  }
+
+  #[test]
+  fn test_sampled_for_point_rate() {
+    let slow = PatternSettings { point_rate: 10_000, ..PatternSettings::default() };
+    let fast = PatternSettings { point_rate: 40_000, ..PatternSettings::default() };
+    let slow = Pattern::BoundingBox.frame(&slow).len();
+    let fast = Pattern::BoundingBox.frame(&fast).len();
+    assert!(fast > slow * 3 && fast < slow * 5);
+  }
+
+  #[test]
+  fn test_color_bars() {
+    let settings = PatternSettings { color: (100, 200, 300), ..PatternSettings::default() };
+    let frame = Pattern::ColorBars.frame(&settings);
+    let mut colors : Vec<(u16, u16, u16, u16)> = frame.iter()
+        .filter(|p| is_lit(p))
+        .map(|p| (p.r, p.g, p.b, p.i))
+        .collect();
+    colors.dedup();
+    assert_eq!(vec![
+      (100, 0, 0, 100),
+      (0, 200, 0, 200),
+      (0, 0, 300, 300),
+      (100, 200, 0, 200),
+      (0, 200, 300, 300),
+      (100, 0, 300, 300),
+      (100, 200, 300, 300),
+    ], colors);
+  }
+}
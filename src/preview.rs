@@ -0,0 +1,390 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! A rough preview of a point stream in a terminal, for checking output over
+//! SSH without looking at the projector.
+//!
+//! A `PreviewTap` set with `Dac::set_preview` copies each batch sent to the
+//! DAC to a thread that draws the points played since the previous refresh.
+//! Copies are handed over without waiting, and dropped if the drawing thread
+//! falls behind, so the preview never holds up the stream.
+
+use crate::error::EtherdreamError;
+use crate::filter::PointFilter;
+use crate::filter::is_lit;
+use crate::protocol::Point;
+use crate::protocol::X_MAX;
+use crate::protocol::X_MIN;
+use crate::protocol::Y_MAX;
+use crate::protocol::Y_MIN;
+use crate::render::point_color;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::sync_channel;
+use std::thread::Builder;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Batches waiting to be drawn before further batches are dropped.
+const QUEUED_BATCHES : usize = 64;
+
+/// The most points drawn in one refresh.
+const MAX_POINTS : usize = 100_000;
+
+/// Characters used to draw.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Glyphs {
+  /// Braille patterns, each showing 2 by 4 dots in one color.
+  Braille,
+  /// Half blocks, each showing 1 by 2 dots with a color for each.
+  Blocks,
+}
+
+/// Draws points as text.
+#[derive(Clone, Debug)]
+pub struct TerminalPreview {
+  columns: usize,
+  rows: usize,
+  glyphs: Glyphs,
+  refresh: Duration,
+  color: bool,
+}
+
+impl TerminalPreview {
+  /// CTOR. Draws braille in color ten times a second.
+  pub fn new(columns: usize, rows: usize) -> TerminalPreview {
+    TerminalPreview {
+      columns: columns.max(1),
+      rows: rows.max(1),
+      glyphs: Glyphs::Braille,
+      refresh: Duration::from_millis(100),
+      color: true,
+    }
+  }
+
+  /// Draw with other characters.
+  pub fn with_glyphs(mut self, glyphs: Glyphs) -> TerminalPreview {
+    self.glyphs = glyphs;
+    self
+  }
+
+  /// Redraw at another interval.
+  pub fn with_refresh(mut self, refresh: Duration) -> TerminalPreview {
+    self.refresh = refresh;
+    self
+  }
+
+  /// Turn ANSI colors on or off. Without them, anything lit is drawn.
+  pub fn with_color(mut self, color: bool) -> TerminalPreview {
+    self.color = color;
+    self
+  }
+
+  /// The width in characters.
+  pub fn get_columns(&self) -> usize {
+    self.columns
+  }
+
+  /// The height in characters.
+  pub fn get_rows(&self) -> usize {
+    self.rows
+  }
+
+  /// The interval between redraws.
+  pub fn get_refresh(&self) -> Duration {
+    self.refresh
+  }
+
+  /// Draw lit segments between the points as lines of text, each ending in
+  /// a newline.
+  pub fn draw(&self, points: &[Point]) -> String {
+    let (dots_x, dots_y) = match self.glyphs {
+      Glyphs::Braille => (2, 4),
+      Glyphs::Blocks => (1, 2),
+    };
+    let mut canvas = Canvas::new(self.columns * dots_x, self.rows * dots_y);
+    for (i, point) in points.iter().enumerate() {
+      if is_lit(point) {
+        let from = if i == 0 { point } else { &points[i - 1] };
+        canvas.line(from, point);
+      }
+    }
+
+    let mut out = String::new();
+    for row in 0 .. self.rows {
+      for column in 0 .. self.columns {
+        match self.glyphs {
+          Glyphs::Braille => self.braille_cell(&canvas, column, row, &mut out),
+          Glyphs::Blocks => self.block_cell(&canvas, column, row, &mut out),
+        }
+      }
+      if self.color {
+        out.push_str("\x1b[0m");
+      }
+      out.push('\n');
+    }
+    out
+  }
+
+  /// Start drawing on a new thread. Each refresh moves the cursor home and
+  /// redraws, so the writer should be a terminal. The thread stops once every
+  /// tap is dropped, or if writing fails.
+  pub fn start<W>(self, writer: W) -> Result<PreviewTap, EtherdreamError>
+      where W: Write + Send + 'static {
+    let (sender, receiver) = sync_channel(QUEUED_BATCHES);
+    Builder::new()
+        .name("etherdream-preview".to_string())
+        .spawn(move || self.run(receiver, writer))?;
+    Ok(PreviewTap { sender })
+  }
+
+  fn run<W: Write>(&self, receiver: Receiver<Vec<Point>>, mut writer: W) {
+    if writer.write_all(b"\x1b[2J").is_err() {
+      return;
+    }
+
+    let mut points = Vec::new();
+    let mut next = Instant::now() + self.refresh;
+    loop {
+      let timeout = next.saturating_duration_since(Instant::now());
+      match receiver.recv_timeout(timeout) {
+        Ok(batch) => {
+          if points.len() < MAX_POINTS {
+            points.extend(batch);
+          }
+          if Instant::now() < next {
+            continue;
+          }
+        },
+        Err(RecvTimeoutError::Timeout) => {},
+        Err(RecvTimeoutError::Disconnected) => return,
+      }
+
+      next = (next + self.refresh).max(Instant::now());
+      if points.is_empty() {
+        continue; // Keep showing the last frame.
+      }
+
+      let frame = format!("\x1b[H{}", self.draw(&points));
+      points.clear();
+      if writer.write_all(frame.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+      }
+    }
+  }
+
+  fn braille_cell(&self, canvas: &Canvas, column: usize, row: usize,
+      out: &mut String) {
+    // Dot bits, by row then column, in the Unicode braille block.
+    const BITS : [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+    let mut bits = 0;
+    let mut color = [0.0f64; 3];
+    for (dy, row_bits) in BITS.iter().enumerate() {
+      for (dx, bit) in row_bits.iter().enumerate() {
+        let dot = canvas.get(column * 2 + dx, row * 4 + dy);
+        if dot.iter().any(|&c| c > 0.0) {
+          bits |= bit;
+          for channel in 0 .. 3 {
+            color[channel] = color[channel].max(dot[channel]);
+          }
+        }
+      }
+    }
+
+    if bits == 0 {
+      out.push(' ');
+      return;
+    }
+    if self.color {
+      out.push_str(&format!("\x1b[38;2;{}m", ansi_rgb(color)));
+    }
+    out.push(char::from_u32(0x2800 + bits).unwrap_or(' '));
+  }
+
+  fn block_cell(&self, canvas: &Canvas, column: usize, row: usize,
+      out: &mut String) {
+    let top = canvas.get(column, row * 2);
+    let bottom = canvas.get(column, row * 2 + 1);
+    let lit = |dot: [f64; 3]| dot.iter().any(|&c| c > 0.0);
+
+    let glyph = match (lit(top), lit(bottom)) {
+      (false, false) => ' ',
+      (true, false) => '\u{2580}',
+      (false, true) => '\u{2584}',
+      (true, true) => '\u{2580}',
+    };
+    if self.color {
+      out.push_str(&format!("\x1b[38;2;{}m", ansi_rgb(if lit(top) { top } else { bottom })));
+      if lit(top) && lit(bottom) {
+        out.push_str(&format!("\x1b[48;2;{}m", ansi_rgb(bottom)));
+      } else {
+        out.push_str("\x1b[49m");
+      }
+    } else if lit(top) && lit(bottom) {
+      out.push('\u{2588}');
+      return;
+    }
+    out.push(glyph);
+  }
+}
+
+/// Feeds points to a running `TerminalPreview`. Give it to
+/// `Dac::set_preview` to see exactly what is sent, or call `observe` from
+/// anything else that produces points, such as an emulator. It can also be
+/// added as a filter, to see points at that stage of the pipeline, before the
+/// `Dac`'s color shift, safety zones, blackout and color levels. Cheap to
+/// clone.
+#[derive(Clone)]
+pub struct PreviewTap {
+  sender: SyncSender<Vec<Point>>,
+}
+
+impl PreviewTap {
+  /// Hand a copy of the points to the preview, without waiting.
+  pub fn observe(&self, points: &[Point]) {
+    let _ = self.sender.try_send(points.to_vec());
+  }
+
+  /// A tap feeding the returned receiver instead of a drawing thread.
+  #[cfg(test)]
+  pub(crate) fn channel() -> (PreviewTap, Receiver<Vec<Point>>) {
+    let (sender, receiver) = sync_channel(QUEUED_BATCHES);
+    (PreviewTap { sender }, receiver)
+  }
+}
+
+impl PointFilter for PreviewTap {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    self.observe(points);
+  }
+}
+
+/// Dots lit with the brightest color drawn on them.
+struct Canvas {
+  width: usize,
+  height: usize,
+  dots: Vec<[f64; 3]>,
+}
+
+impl Canvas {
+  fn new(width: usize, height: usize) -> Canvas {
+    Canvas { width, height, dots: vec![[0.0; 3]; width * height] }
+  }
+
+  fn get(&self, x: usize, y: usize) -> [f64; 3] {
+    self.dots[y * self.width + x]
+  }
+
+  fn line(&mut self, from: &Point, to: &Point) {
+    let (x0, y0) = self.to_dot(from);
+    let (x1, y1) = self.to_dot(to);
+    let color = point_color(to);
+    let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0) as usize;
+
+    for step in 0 ..= steps {
+      let t = step as f64 / steps as f64;
+      let x = (x0 + (x1 - x0) * t).round() as usize;
+      let y = (y0 + (y1 - y0) * t).round() as usize;
+      let dot = &mut self.dots[y * self.width + x];
+      for channel in 0 .. 3 {
+        dot[channel] = dot[channel].max(color[channel]);
+      }
+    }
+  }
+
+  /// DAC coordinates to dot coordinates, with positive Y up.
+  fn to_dot(&self, point: &Point) -> (f64, f64) {
+    let u = (point.x as f64 - X_MIN as f64) / (X_MAX as f64 - X_MIN as f64);
+    let v = (Y_MAX as f64 - point.y as f64) / (Y_MAX as f64 - Y_MIN as f64);
+    (u * (self.width - 1) as f64, v * (self.height - 1) as f64)
+  }
+}
+
+/// A color as `r;g;b` for an ANSI escape, brightened so dim colors show.
+fn ansi_rgb(color: [f64; 3]) -> String {
+  let max = color[0].max(color[1]).max(color[2]).max(1e-9);
+  let scale = |c: f64| ((c / max).sqrt() * 255.0).round() as u8;
+  format!("{};{};{}", scale(color[0]), scale(color[1]), scale(color[2]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::COLOR_MAX;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  fn horizontal_line() -> Vec<Point> {
+    vec![
+      Point::xy_blank(X_MIN, Y_MAX),
+      Point::xy_blank(X_MIN, 0),
+      Point::xy_rgb(X_MIN, 0, COLOR_MAX, 0, 0),
+      Point::xy_rgb(X_MAX, 0, COLOR_MAX, 0, 0),
+    ]
+  }
+
+  #[test]
+  fn test_draw_braille() {
+    let preview = TerminalPreview::new(4, 3).with_color(false);
+    let text = preview.draw(&horizontal_line());
+    let lines : Vec<&str> = text.lines().collect();
+    assert_eq!(3, lines.len());
+    assert_eq!("    ", lines[0]);
+    assert_eq!(4, lines[1].chars().filter(|&c| c != ' ').count());
+    assert_eq!("    ", lines[2]);
+
+    // The blanked move from the top left isn't drawn.
+    let text = TerminalPreview::new(4, 3).draw(&horizontal_line());
+    assert!(text.contains("\x1b[38;2;255;0;0m"));
+  }
+
+  #[test]
+  fn test_draw_blocks() {
+    let preview = TerminalPreview::new(3, 2)
+        .with_glyphs(Glyphs::Blocks)
+        .with_color(false);
+    let points = [Point::xy_luma(X_MIN, Y_MAX, COLOR_MAX); 2];
+    assert_eq!("\u{2580}  \n   \n", preview.draw(&points));
+  }
+
+  /// A writer that can be inspected after the preview thread owns it.
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_tap_never_blocks() {
+    let buffer = SharedBuffer::default();
+    let mut tap = TerminalPreview::new(8, 4)
+        .with_refresh(Duration::from_millis(10))
+        .start(buffer.clone())
+        .unwrap();
+
+    // Far more batches than can queue, and the points pass through as is.
+    let started = Instant::now();
+    for _ in 0 .. QUEUED_BATCHES * 10 {
+      let mut points = horizontal_line();
+      tap.filter(&mut points);
+      assert_eq!(4, points.len());
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !buffer.0.lock().unwrap().windows(3).any(|w| w == b"\x1b[H") {
+      assert!(Instant::now() < deadline, "preview never drew");
+      std::thread::sleep(Duration::from_millis(5));
+    }
+  }
+}
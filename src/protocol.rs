@@ -9,7 +9,7 @@
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
-use error::EtherdreamError;
+use crate::error::EtherdreamError;
 use std::io::Cursor;
 
 /// The highest value that can be specified for a single color channel.
@@ -27,12 +27,25 @@ pub const Y_MIN : i16 = -32768;
 
 /// Byte designating the 'begin' command.
 pub const COMMAND_BEGIN : u8   = 0x62;
+/// Byte designating the 'clear emergency stop' command.
+pub const COMMAND_CLEAR_EMERGENCY_STOP : u8 = 0x63;
 /// Byte designating the 'data' command.
 pub const COMMAND_DATA : u8    = 0x64;
+/// Byte designating the 'emergency stop' command.
+/// The DAC also treats 0xFF as an emergency stop.
+pub const COMMAND_EMERGENCY_STOP : u8 = 0x00;
 /// Byte designating the 'ping' command.
 pub const COMMAND_PING : u8    = 0x3F;
 /// Byte designating the 'prepare' command.
 pub const COMMAND_PREPARE : u8 = 0x70;
+/// Byte designating the 'queue rate change' command.
+pub const COMMAND_QUEUE_RATE_CHANGE : u8 = 0x71;
+/// Byte designating the 'stop' command.
+pub const COMMAND_STOP : u8    = 0x73;
+
+/// Point control bit. When a point with this bit set is played, the DAC
+/// switches to the next rate queued with the 'queue rate change' command.
+pub const CONTROL_RATE_CHANGE : u16 = 0x8000;
 
 /// Ack byte
 pub const RESPONSE_ACK: u8         = 0x61;
@@ -72,7 +85,7 @@ impl DacResponse {
     Ok(DacResponse {
       acknowledgement: AckCode::parse(bytes[0]),
       command: CommandCode::parse(bytes[1]),
-      status: status,
+      status,
     })
   }
 
@@ -96,9 +109,13 @@ pub enum AckCode {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CommandCode {
   Begin,
+  ClearEmergencyStop,
   Data,
+  EmergencyStop,
   Ping,
   Prepare,
+  QueueRateChange,
+  Stop,
   CommandUnknown { code: u8 },
   // TODO: More.
 }
@@ -116,10 +133,7 @@ impl AckCode {
 
   /// Whether or not the code is a successful ACK.
   pub fn is_ack(&self) -> bool {
-    match *self {
-      AckCode::Ack => true,
-      _ => false,
-    }
+    matches!(*self, AckCode::Ack)
   }
 }
 
@@ -127,9 +141,13 @@ impl CommandCode {
   pub fn parse(byte: u8) -> CommandCode {
     match byte {
       COMMAND_BEGIN => CommandCode::Begin,
+      COMMAND_CLEAR_EMERGENCY_STOP => CommandCode::ClearEmergencyStop,
       COMMAND_DATA => CommandCode::Data,
+      COMMAND_EMERGENCY_STOP | 0xFF => CommandCode::EmergencyStop,
       COMMAND_PING => CommandCode::Ping,
       COMMAND_PREPARE => CommandCode::Prepare,
+      COMMAND_QUEUE_RATE_CHANGE => CommandCode::QueueRateChange,
+      COMMAND_STOP => CommandCode::Stop,
       _ => CommandCode::CommandUnknown { code: byte },
     }
   }
@@ -147,12 +165,12 @@ pub struct DacStatus {
    *
    *  - 0: Ready.
    *  - 1: Warmup. In the case where the DAC is also used for thermal
-   *       control of laser apparatus, this is the state that is
-   *       entered after power-up.
+   *    control of laser apparatus, this is the state that is
+   *    entered after power-up.
    *  - 2: Cooldown. Lasers are off but thermal control is still active
    *  - 3: Emergency stop. An emergency stop has been triggered, either
-   *       by an E-stop input on the DAC, an E-stop command over the
-   *       network, or a fault such as over-temperature.
+   *    by an E-stop input on the DAC, an E-stop command over the
+   *    network, or a fault such as over-temperature.
    *
    *  (Since thermal control is not implemented yet, it is not defined
    *  how transitions to and from the "Warmup" and "Cooldown" states
@@ -173,10 +191,10 @@ pub struct DacStatus {
    * playback system is in one of the following states:
    *
    *   - 0: Idle. This is the default state. No points may be added to
-   *        the buffer. No output is generated; all analog outputs are
-   *        at 0v, and the shutter is controlled by the data source.
+   *     the buffer. No output is generated; all analog outputs are
+   *     at 0v, and the shutter is controlled by the data source.
    *   - 1: Prepared. The buffer will accept points. The output is the
-   *        same as in the Idle state.
+   *     same as in the Idle state.
    *   - 2: Playing. Points are being sent to the output.
    *
    * See playback_flags for additional information.
@@ -187,7 +205,7 @@ pub struct DacStatus {
    * The currently-selected data source is specified in the source field:
    *
    *   - 0: Network streaming (the protocol defined in the rest of this
-   *        document).
+   *     document).
    *   - 1: ILDA playback from SD card.
    *   - 2: Internal abstract generator.
    */
@@ -268,11 +286,12 @@ impl DacStatus {
 
   // TODO: Unsafe; remove?
   pub fn serialize(&self) -> Vec<u8> {
-    let mut v = Vec::new();
-    v.push(self.protocol);
-    v.push(self.light_engine_state);
-    v.push(self.playback_state);
-    v.push(self.source);
+    let mut v = vec![
+      self.protocol,
+      self.light_engine_state,
+      self.playback_state,
+      self.source,
+    ];
     v.write_u16::<LittleEndian>(self.light_engine_flags).unwrap();
     v.write_u16::<LittleEndian>(self.playback_flags).unwrap();
     v.write_u16::<LittleEndian>(self.source_flags).unwrap();
@@ -369,6 +388,23 @@ pub struct Begin {
 }
 
 impl Begin {
+  /// Parse a Begin command from raw bytes, including the command byte.
+  /// Begin commands are 7 bytes.
+  pub fn parse(bytes: &[u8]) -> Result<Begin, EtherdreamError> {
+    if bytes.len() < 7 {
+      return Err(EtherdreamError::BadResponseLength {
+        description: format!("Begin is {} bytes; must be no fewer than 7.",
+          bytes.len()),
+      });
+    }
+
+    let mut reader = Cursor::new(&bytes[1..7]);
+    Ok(Begin {
+      low_water_mark : reader.read_u16::<LittleEndian>()?,
+      point_rate     : reader.read_u32::<LittleEndian>()?,
+    })
+  }
+
   pub fn serialize(&self) -> Vec<u8> {
     let mut v = Vec::new();
     v.push(COMMAND_BEGIN); // 'b'
@@ -378,6 +414,59 @@ impl Begin {
   }
 }
 
+/** Queue rate change command. */
+#[derive(Clone, Copy, Debug)]
+pub struct QueueRateChange {
+  /// The rate to switch to at the next point flagged with
+  /// `CONTROL_RATE_CHANGE`.
+  pub point_rate: u32,
+}
+
+impl QueueRateChange {
+  /// Parse a queue rate change command from raw bytes, including the command
+  /// byte. Queue rate change commands are 5 bytes.
+  pub fn parse(bytes: &[u8]) -> Result<QueueRateChange, EtherdreamError> {
+    if bytes.len() < 5 {
+      return Err(EtherdreamError::BadResponseLength {
+        description: format!("QueueRateChange is {} bytes; must be no fewer than 5.",
+          bytes.len()),
+      });
+    }
+
+    let mut reader = Cursor::new(&bytes[1..5]);
+    Ok(QueueRateChange {
+      point_rate: reader.read_u32::<LittleEndian>()?,
+    })
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut v = vec![COMMAND_QUEUE_RATE_CHANGE]; // 'q'
+    v.write_u32::<LittleEndian>(self.point_rate).unwrap();
+    v
+  }
+}
+
+/** Data command. Queues points in the DAC's buffer. */
+#[derive(Clone, Copy, Debug)]
+pub struct Data<'a> {
+  /// The points to send. The count is sent as a `u16`, so at most
+  /// `u16::MAX`.
+  pub points: &'a [Point],
+}
+
+impl<'a> Data<'a> {
+  /// Serialize the command. Sends (3 + 18*n) bytes.
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut v = Vec::with_capacity(3 + 18 * self.points.len());
+    v.push(COMMAND_DATA); // 'd'
+    v.write_u16::<LittleEndian>(self.points.len() as u16).unwrap();
+    for point in self.points {
+      v.extend(point.serialize());
+    }
+    v
+  }
+}
+
 // TODO: Docs, tests.
 /** 18-byte point data for a single point. */
 #[derive(Clone, Copy, Debug)]
@@ -398,11 +487,11 @@ impl Point {
   pub fn xy_rgb(x: i16, y: i16, r: u16, g: u16, b: u16) -> Point {
     Point {
       control: 0,
-      x: x,
-      y: y,
-      r: r,
-      g: g,
-      b: b,
+      x,
+      y,
+      r,
+      g,
+      b,
       i: 0,
       u1: 0,
       u2: 0,
@@ -414,8 +503,8 @@ impl Point {
   pub fn xy_luma(x: i16, y: i16, luminance: u16) -> Point {
     Point {
       control: 0,
-      x: x,
-      y: y,
+      x,
+      y,
       r: luminance,
       g: luminance,
       b: luminance,
@@ -437,6 +526,29 @@ impl Point {
     Point::xy_rgb(x, y, c, c, c)
   }
 
+  /// Parse a Point from raw bytes. Points are 18 bytes.
+  pub fn parse(bytes: &[u8]) -> Result<Point, EtherdreamError> {
+    if bytes.len() < 18 {
+      return Err(EtherdreamError::BadResponseLength {
+        description: format!("Point is {} bytes; must be no fewer than 18.",
+          bytes.len()),
+      });
+    }
+
+    let mut reader = Cursor::new(&bytes[0..18]);
+    Ok(Point {
+      control : reader.read_u16::<LittleEndian>()?,
+      x       : reader.read_i16::<LittleEndian>()?,
+      y       : reader.read_i16::<LittleEndian>()?,
+      r       : reader.read_u16::<LittleEndian>()?,
+      g       : reader.read_u16::<LittleEndian>()?,
+      b       : reader.read_u16::<LittleEndian>()?,
+      i       : reader.read_u16::<LittleEndian>()?,
+      u1      : reader.read_u16::<LittleEndian>()?,
+      u2      : reader.read_u16::<LittleEndian>()?,
+    })
+  }
+
   pub fn serialize(&self) -> Vec<u8> {
     // NB: Website documentation is incorrect about byte order: the "rgb" color
     // channels each come before "i".
@@ -534,6 +646,30 @@ mod tests {
     assert_eq!(67305985, broadcast.max_point_rate);
   }
 
+  #[test]
+  fn test_data_serialize() {
+    let points = [
+      Point::xy_rgb(1, -1, 2, 3, 4),
+      Point::xy_blank(5, 6),
+    ];
+
+    let bytes = Data { points: &points }.serialize();
+    assert_eq!(3 + 18 * 2, bytes.len());
+    assert_eq!(COMMAND_DATA, bytes[0]);
+    assert_eq!([2, 0], bytes[1..3]);
+    assert_eq!(points[0].serialize()[..], bytes[3..21]);
+    assert_eq!(points[1].serialize()[..], bytes[21..39]);
+  }
+
+  #[test]
+  fn test_command_code_parse() {
+    assert_eq!(CommandCode::Stop, CommandCode::parse(0x73));
+    assert_eq!(CommandCode::EmergencyStop, CommandCode::parse(0x00));
+    assert_eq!(CommandCode::EmergencyStop, CommandCode::parse(0xFF));
+    assert_eq!(CommandCode::CommandUnknown { code: 0x01 },
+        CommandCode::parse(0x01));
+  }
+
   #[test]
   fn test_point_xy_rgb() {
     let point = Point::xy_rgb(10_000, -10_000, 32, 128, 1028);
@@ -583,5 +719,28 @@ mod tests {
     assert_eq!(0, point.g);
     assert_eq!(0, point.b);
   }
-}
 
+  #[test]
+  fn test_point_parse_round_trip() {
+    let mut point = Point::xy_rgb(-32768, 32767, 1, 2, 3);
+    point.control = CONTROL_RATE_CHANGE;
+    point.i = 4;
+    let parsed = Point::parse(&point.serialize()).unwrap();
+    assert_eq!(point.serialize(), parsed.serialize());
+    assert!(Point::parse(&[0; 17]).is_err());
+  }
+
+  #[test]
+  fn test_command_parse_round_trip() {
+    let begin = Begin::parse(&Begin { low_water_mark: 7, point_rate: 30_000 }
+        .serialize()).unwrap();
+    assert_eq!((7, 30_000), (begin.low_water_mark, begin.point_rate));
+
+    let change = QueueRateChange::parse(&QueueRateChange { point_rate: 12_345 }
+        .serialize()).unwrap();
+    assert_eq!(12_345, change.point_rate);
+
+    assert!(Begin::parse(&[COMMAND_BEGIN, 0, 0]).is_err());
+    assert!(QueueRateChange::parse(&[COMMAND_QUEUE_RATE_CHANGE]).is_err());
+  }
+}
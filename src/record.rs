@@ -0,0 +1,405 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Recording the traffic between a `Dac` and the hardware, and replaying it.
+//!
+//! Recordings start with the bytes `EDREC` and a version byte. Each event
+//! follows as a kind byte, the microseconds since the previous event and the
+//! payload length as LEB128 varints, then the payload exactly as it went over
+//! the wire.
+
+use crate::error::EtherdreamError;
+use crate::network::COMMUNICATION_PORT;
+use crate::protocol::DacResponse;
+use std::fs::File;
+use std::fs;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Identifies a recording file.
+const MAGIC : &[u8; 5] = b"EDREC";
+
+/// The recording format version.
+const VERSION : u8 = 1;
+
+/// How long a replay waits for each response.
+const RESPONSE_TIMEOUT : Duration = Duration::from_millis(500);
+
+const KIND_COMMAND : u8 = 0;
+const KIND_RESPONSE : u8 = 1;
+
+/// Which way an event went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+  /// A command sent to the DAC.
+  Command,
+  /// A response received from the DAC.
+  Response,
+}
+
+/// A command or response, as it went over the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+  /// When it happened, relative to the start of the recording.
+  pub time: Duration,
+  /// Which way it went.
+  pub kind: EventKind,
+  /// The raw bytes.
+  pub bytes: Vec<u8>,
+}
+
+impl Event {
+  /// The response, if this is one.
+  pub fn response(&self) -> Option<DacResponse> {
+    match self.kind {
+      EventKind::Response => DacResponse::parse(&self.bytes).ok(),
+      EventKind::Command => None,
+    }
+  }
+}
+
+/// Writes events to a recording as they happen. Attach one to a `Dac` with
+/// `Dac::set_recorder`.
+pub struct Recorder {
+  writer: Box<dyn Write + Send>,
+  started: Instant,
+  last: Duration,
+}
+
+impl Recorder {
+  /// Record to a new file, replacing any file at the path.
+  pub fn create<P: AsRef<Path>>(path: P) -> Result<Recorder, EtherdreamError> {
+    let file = File::create(path)?;
+    Recorder::new(Box::new(BufWriter::new(file)))
+  }
+
+  /// Record to any writer.
+  pub fn new(mut writer: Box<dyn Write + Send>) -> Result<Recorder, EtherdreamError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    Ok(Recorder {
+      writer,
+      started: Instant::now(),
+      last: Duration::from_secs(0),
+    })
+  }
+
+  /// Record a command sent to the DAC.
+  pub fn record_command(&mut self, bytes: &[u8]) -> Result<(), EtherdreamError> {
+    self.record(KIND_COMMAND, bytes)
+  }
+
+  /// Record a response received from the DAC.
+  pub fn record_response(&mut self, bytes: &[u8]) -> Result<(), EtherdreamError> {
+    self.record(KIND_RESPONSE, bytes)
+  }
+
+  /// Write buffered events out.
+  pub fn flush(&mut self) -> Result<(), EtherdreamError> {
+    self.writer.flush()?;
+    Ok(())
+  }
+
+  fn record(&mut self, kind: u8, bytes: &[u8]) -> Result<(), EtherdreamError> {
+    let now = self.started.elapsed();
+    let delta = now.checked_sub(self.last).unwrap_or_default();
+    self.last = now;
+
+    let mut header = vec![kind];
+    write_varint(&mut header, delta.as_micros() as u64);
+    write_varint(&mut header, bytes.len() as u64);
+    self.writer.write_all(&header)?;
+    self.writer.write_all(bytes)?;
+    Ok(())
+  }
+}
+
+/// A recorded session.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+  /// The events, in the order they happened.
+  pub events: Vec<Event>,
+}
+
+impl Recording {
+  /// Read a recording from disk.
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Recording, EtherdreamError> {
+    Recording::parse(&fs::read(path)?)
+  }
+
+  /// Parse a recording. A final event cut short, as happens if the program
+  /// recording was killed, is dropped.
+  pub fn parse(bytes: &[u8]) -> Result<Recording, EtherdreamError> {
+    if bytes.len() < MAGIC.len() + 1 || &bytes[.. MAGIC.len()] != MAGIC {
+      return Err(bad_recording("not a recording"));
+    }
+    if bytes[MAGIC.len()] != VERSION {
+      return Err(bad_recording("unsupported version"));
+    }
+
+    let mut events = Vec::new();
+    let mut offset = MAGIC.len() + 1;
+    let mut time = Duration::from_secs(0);
+
+    while offset < bytes.len() {
+      let kind = match bytes[offset] {
+        KIND_COMMAND => EventKind::Command,
+        KIND_RESPONSE => EventKind::Response,
+        _ => return Err(bad_recording("unknown event kind")),
+      };
+      offset += 1;
+
+      let delta = match read_varint(bytes, &mut offset) {
+        Some(delta) => delta,
+        None => break,
+      };
+      let len = match read_varint(bytes, &mut offset) {
+        Some(len) => len as usize,
+        None => break,
+      };
+      let payload = match bytes.get(offset .. offset.saturating_add(len)) {
+        Some(payload) => payload,
+        None => break,
+      };
+      offset += len;
+
+      time += Duration::from_micros(delta);
+      events.push(Event { time, kind, bytes: payload.to_vec() });
+    }
+
+    Ok(Recording { events })
+  }
+}
+
+/// Re-sends a recorded session with its original timing.
+pub struct Replayer {
+  recording: Recording,
+}
+
+impl Replayer {
+  /// CTOR.
+  pub fn new(recording: Recording) -> Replayer {
+    Replayer { recording }
+  }
+
+  /// Replay to the DAC, or an emulator, at the address. Fails if a response
+  /// takes longer than half a second.
+  pub fn replay(&self, ip_address: IpAddr)
+      -> Result<Vec<DacResponse>, EtherdreamError> {
+    let mut stream = TcpStream::connect((ip_address, COMMUNICATION_PORT))?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    stream.set_write_timeout(Some(RESPONSE_TIMEOUT))?;
+    self.replay_to(&mut stream)
+  }
+
+  /// Replay over any connection. Each recorded command is sent at its
+  /// original time, and a response is read wherever the recording has one,
+  /// including the greeting sent on connection. Returns the responses
+  /// received, for comparing against the recording.
+  ///
+  /// Reads wait as long as the connection lets them, so give it a read
+  /// timeout; a timed out read fails with `ErrorKind::TimedOut`.
+  pub fn replay_to<S>(&self, stream: &mut S)
+      -> Result<Vec<DacResponse>, EtherdreamError> where S: Read + Write {
+    let started = Instant::now();
+    let mut responses = Vec::new();
+
+    for event in self.recording.events.iter() {
+      match event.kind {
+        EventKind::Command => {
+          if let Some(wait) = event.time.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+          }
+          stream.write_all(&event.bytes)?;
+        },
+        EventKind::Response => {
+          let mut buf = [0; 22];
+          stream.read_exact(&mut buf).map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+              std::io::Error::new(ErrorKind::TimedOut, "no response from the DAC")
+            },
+            _ => e,
+          })?;
+          responses.push(DacResponse::parse(&buf)?);
+        },
+      }
+    }
+
+    Ok(responses)
+  }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+  loop {
+    let byte = (value & 0x7F) as u8;
+    value >>= 7;
+    if value == 0 {
+      out.push(byte);
+      return;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<u64> {
+  let mut value = 0u64;
+  for shift in (0 .. 64).step_by(7) {
+    let byte = *bytes.get(*offset)?;
+    *offset += 1;
+    value |= ((byte & 0x7F) as u64) << shift;
+    if byte & 0x80 == 0 {
+      return Some(value);
+    }
+  }
+  None
+}
+
+fn bad_recording(reason: &str) -> EtherdreamError {
+  EtherdreamError::BadRecording { description: reason.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use std::net::TcpListener;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  /// A writer that can be inspected after the recorder owns it.
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn response(command: u8) -> Vec<u8> {
+    let mut bytes = vec![b'a', command];
+    bytes.resize(22, 0);
+    bytes
+  }
+
+  #[test]
+  fn test_varint() {
+    for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX].iter() {
+      let mut bytes = Vec::new();
+      write_varint(&mut bytes, *value);
+      let mut offset = 0;
+      assert_eq!(Some(*value), read_varint(&bytes, &mut offset));
+      assert_eq!(bytes.len(), offset);
+    }
+  }
+
+  #[test]
+  fn test_record_and_parse() {
+    let buffer = SharedBuffer::default();
+    let mut recorder = Recorder::new(Box::new(buffer.clone())).unwrap();
+    recorder.record_response(&response(b'?')).unwrap();
+    recorder.record_command(b"p").unwrap();
+    recorder.record_response(&response(b'p')).unwrap();
+
+    let mut bytes = buffer.0.lock().unwrap().clone();
+    bytes.push(KIND_COMMAND); // A truncated event.
+
+    let recording = Recording::parse(&bytes).unwrap();
+    assert_eq!(3, recording.events.len());
+    assert_eq!(EventKind::Command, recording.events[1].kind);
+    assert_eq!(b"p".to_vec(), recording.events[1].bytes);
+    assert!(recording.events[1].time <= recording.events[2].time);
+    assert!(recording.events[2].response().unwrap().acknowledgement.is_ack());
+
+    assert!(Recording::parse(b"EDREC\x02").is_err());
+    assert!(Recording::parse(b"nope").is_err());
+  }
+
+  /// A connection that reads canned bytes and collects what's written.
+  struct Loopback {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+  }
+
+  impl Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      self.input.read(buf)
+    }
+  }
+
+  impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_replay_keeps_order_and_timing() {
+    let event = |ms, kind, bytes: Vec<u8>| Event {
+      time: Duration::from_millis(ms),
+      kind,
+      bytes,
+    };
+    let recording = Recording {
+      events: vec![
+        event(0, EventKind::Response, response(b'?')),
+        event(0, EventKind::Command, b"p".to_vec()),
+        event(1, EventKind::Response, response(b'p')),
+        event(30, EventKind::Command, b"s".to_vec()),
+        event(31, EventKind::Response, response(b's')),
+      ],
+    };
+
+    let mut input = response(b'?');
+    input.extend(response(b'p'));
+    input.extend(response(b's'));
+    let mut stream = Loopback { input: Cursor::new(input), output: Vec::new() };
+
+    let started = Instant::now();
+    let responses = Replayer::new(recording).replay_to(&mut stream).unwrap();
+
+    assert!(started.elapsed() >= Duration::from_millis(30));
+    assert_eq!(b"ps".to_vec(), stream.output);
+    assert_eq!(3, responses.len());
+  }
+
+  #[test]
+  fn test_replay_times_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (_silent, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+
+    let recording = Recording {
+      events: vec![Event {
+        time: Duration::from_millis(0),
+        kind: EventKind::Response,
+        bytes: response(b'?'),
+      }],
+    };
+
+    match Replayer::new(recording).replay_to(&mut stream) {
+      Err(EtherdreamError::IoError { cause }) => {
+        assert_eq!(ErrorKind::TimedOut, cause.kind());
+      },
+      result => panic!("{:?}", result),
+    }
+  }
+}
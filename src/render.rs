@@ -0,0 +1,386 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Rendering points as a simulated laser image, for previewing output
+//! without a projector.
+//!
+//! The beam spends the same time travelling to each point, so every segment
+//! deposits the same amount of light however long it is: short segments and
+//! repeated points come out bright, long fast jumps come out faint, just as
+//! they do on a wall. Light adds up, so overlapping red and green make yellow.
+
+use crate::error::EtherdreamError;
+use crate::filter::is_lit;
+use crate::protocol::COLOR_MAX;
+use crate::protocol::Point;
+use crate::protocol::X_MAX;
+use crate::protocol::X_MIN;
+use crate::protocol::Y_MAX;
+use crate::protocol::Y_MIN;
+use png::BitDepth;
+use png::ColorType;
+use png::Encoder;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Error as IoError;
+use std::io::Write;
+use std::path::Path;
+
+/// An 8-bit RGB image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+  /// Width in pixels.
+  pub width: u32,
+  /// Height in pixels.
+  pub height: u32,
+  /// Red, green and blue bytes for each pixel, row by row from the top.
+  pub data: Vec<u8>,
+}
+
+impl Image {
+  /// The color of a pixel.
+  pub fn get_pixel(&self, x: u32, y: u32) -> (u8, u8, u8) {
+    let i = 3 * (y * self.width + x) as usize;
+    (self.data[i], self.data[i + 1], self.data[i + 2])
+  }
+
+  /// Write the image to a PNG file, replacing any file at the path.
+  pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), EtherdreamError> {
+    let file = File::create(path)?;
+    self.write_png(BufWriter::new(file))
+  }
+
+  /// Encode the image as PNG.
+  pub fn to_png(&self) -> Result<Vec<u8>, EtherdreamError> {
+    let mut bytes = Vec::new();
+    self.write_png(&mut bytes)?;
+    Ok(bytes)
+  }
+
+  fn write_png<W: Write>(&self, writer: W) -> Result<(), EtherdreamError> {
+    let mut encoder = Encoder::new(writer, self.width, self.height);
+    encoder.set_color(ColorType::Rgb);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&self.data).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+  }
+}
+
+/// Draws point streams into an image.
+///
+/// Each call to `render` adds a frame. With persistence, light from earlier
+/// frames fades instead of disappearing, like the afterglow of a fast
+/// animation on a real surface.
+pub struct Renderer {
+  width: u32,
+  height: u32,
+  exposure: f64,
+  blanked: Option<f64>,
+  persistence: f64,
+  glow: Option<(u32, f64)>,
+  light: Vec<[f64; 3]>,
+}
+
+impl Renderer {
+  /// CTOR. Blanked moves are hidden, and there is no persistence or glow.
+  pub fn new(width: u32, height: u32) -> Renderer {
+    let width = width.max(1);
+    let height = height.max(1);
+    Renderer {
+      width,
+      height,
+      exposure: 4.0,
+      blanked: None,
+      persistence: 0.0,
+      glow: None,
+      light: vec![[0.0; 3]; (width * height) as usize],
+    }
+  }
+
+  /// Scale how much light each segment deposits. Higher values make faint
+  /// lines brighter and bright ones saturate sooner.
+  pub fn with_exposure(mut self, exposure: f64) -> Renderer {
+    self.exposure = exposure.max(0.0);
+    self
+  }
+
+  /// Show blanked moves as gray lines of the given brightness, from 0.0 to
+  /// 1.0, to see where the beam travels while it's off.
+  pub fn with_blanked(mut self, brightness: f64) -> Renderer {
+    self.blanked = Some(brightness.clamp(0.0, 1.0));
+    self
+  }
+
+  /// Keep a share of the previous frames' light, from 0.0 (none) to 1.0
+  /// (everything), each time a frame is rendered.
+  pub fn with_persistence(mut self, persistence: f64) -> Renderer {
+    self.persistence = persistence.clamp(0.0, 1.0);
+    self
+  }
+
+  /// Add a halo around lines, blurred over `radius` pixels and scaled by
+  /// `strength`.
+  pub fn with_glow(mut self, radius: u32, strength: f64) -> Renderer {
+    self.glow = if radius > 0 && strength > 0.0 { Some((radius, strength)) } else { None };
+    self
+  }
+
+  /// How much light each segment deposits.
+  pub fn get_exposure(&self) -> f64 {
+    self.exposure
+  }
+
+  /// Image width in pixels.
+  pub fn get_width(&self) -> u32 {
+    self.width
+  }
+
+  /// Image height in pixels.
+  pub fn get_height(&self) -> u32 {
+    self.height
+  }
+
+  /// The glow radius and strength, if glow is on.
+  pub fn get_glow(&self) -> Option<(u32, f64)> {
+    self.glow
+  }
+
+  /// How much of the previous frames' light is kept.
+  pub fn get_persistence(&self) -> f64 {
+    self.persistence
+  }
+
+  /// Remove all light.
+  pub fn clear(&mut self) {
+    for pixel in self.light.iter_mut() {
+      *pixel = [0.0; 3];
+    }
+  }
+
+  /// Add a frame. Each segment is drawn in the color of the point it leads
+  /// to, as the DAC changes color and position together.
+  pub fn render(&mut self, points: &[Point]) {
+    let persistence = self.persistence;
+    for pixel in self.light.iter_mut() {
+      for channel in pixel.iter_mut() {
+        *channel *= persistence;
+      }
+    }
+
+    for (i, point) in points.iter().enumerate() {
+      let from = if i == 0 { point } else { &points[i - 1] };
+      if is_lit(point) {
+        let color = point_color(point);
+        let exposure = self.exposure;
+        self.segment(from, point, [color[0] * exposure, color[1] * exposure,
+            color[2] * exposure], true);
+      } else if let Some(brightness) = self.blanked {
+        self.segment(from, point, [brightness; 3], false);
+      }
+    }
+  }
+
+  /// The image so far.
+  pub fn image(&self) -> Image {
+    let glow = self.glow.map(|(radius, strength)| {
+      (blur(&self.light, self.width as usize, self.height as usize,
+          radius as usize), strength)
+    });
+
+    let mut data = Vec::with_capacity(self.light.len() * 3);
+    for (i, pixel) in self.light.iter().enumerate() {
+      for channel in 0 .. 3 {
+        let mut value = pixel[channel];
+        if let Some((ref halo, strength)) = glow {
+          value += halo[i][channel] * strength;
+        }
+        // Saturate softly, as film and eyes do.
+        let value = 1.0 - (-value).exp();
+        data.push((value * 255.0).round() as u8);
+      }
+    }
+
+    Image { width: self.width, height: self.height, data }
+  }
+
+  /// Draw a segment. Lit segments spread one point's worth of light along
+  /// their length; guide lines for blanked moves are evenly bright instead.
+  fn segment(&mut self, from: &Point, to: &Point, color: [f64; 3], spread: bool) {
+    let (x0, y0) = self.to_pixel(from);
+    let (x1, y1) = self.to_pixel(to);
+    let steps = (x1 - x0).hypot(y1 - y0).ceil().max(1.0) as usize;
+    let share = if spread { 1.0 / steps as f64 } else { 1.0 };
+
+    for step in 1 ..= steps {
+      let t = step as f64 / steps as f64;
+      self.splat(x0 + (x1 - x0) * t, y0 + (y1 - y0) * t,
+          [color[0] * share, color[1] * share, color[2] * share]);
+    }
+  }
+
+  /// Add light at a position, shared between the four nearest pixels.
+  fn splat(&mut self, x: f64, y: f64, color: [f64; 3]) {
+    let (fx, fy) = (x.floor(), y.floor());
+    let (dx, dy) = (x - fx, y - fy);
+    let corners = [
+      (fx, fy, (1.0 - dx) * (1.0 - dy)),
+      (fx + 1.0, fy, dx * (1.0 - dy)),
+      (fx, fy + 1.0, (1.0 - dx) * dy),
+      (fx + 1.0, fy + 1.0, dx * dy),
+    ];
+    for &(px, py, weight) in corners.iter() {
+      if px < 0.0 || py < 0.0 || px >= self.width as f64 || py >= self.height as f64 {
+        continue;
+      }
+      let pixel = &mut self.light[py as usize * self.width as usize + px as usize];
+      for channel in 0 .. 3 {
+        pixel[channel] += color[channel] * weight;
+      }
+    }
+  }
+
+  /// DAC coordinates to pixel coordinates, with positive Y up.
+  fn to_pixel(&self, point: &Point) -> (f64, f64) {
+    let u = (point.x as f64 - X_MIN as f64) / (X_MAX as f64 - X_MIN as f64);
+    let v = (Y_MAX as f64 - point.y as f64) / (Y_MAX as f64 - Y_MIN as f64);
+    (u * (self.width - 1) as f64, v * (self.height - 1) as f64)
+  }
+}
+
+/// A point's color, from 0.0 to 1.0 per channel. Projectors with a single
+/// color take it from the intensity channel, so that shows as white.
+pub(crate) fn point_color(point: &Point) -> [f64; 3] {
+  let max = COLOR_MAX as f64;
+  if point.r == 0 && point.g == 0 && point.b == 0 {
+    let i = point.i as f64 / max;
+    [i, i, i]
+  } else {
+    [point.r as f64 / max, point.g as f64 / max, point.b as f64 / max]
+  }
+}
+
+/// Box blur, run twice in each direction to approximate a Gaussian.
+fn blur(light: &[[f64; 3]], width: usize, height: usize, radius: usize)
+    -> Vec<[f64; 3]> {
+  let mut out = light.to_vec();
+  for _ in 0 .. 2 {
+    out = blur_pass(&out, width, height, radius, 1, width);
+    out = blur_pass(&out, height, width, radius, width, 1);
+  }
+  out
+}
+
+/// Blur along lines of `length` pixels `stride` apart, with `count` lines
+/// starting `step` apart.
+fn blur_pass(light: &[[f64; 3]], length: usize, count: usize, radius: usize,
+    stride: usize, step: usize) -> Vec<[f64; 3]> {
+  let mut out = vec![[0.0; 3]; light.len()];
+  let scale = 1.0 / (2 * radius + 1) as f64;
+
+  for line in 0 .. count {
+    let start = line * step;
+    let index = |i: usize| start + i * stride;
+    let mut sum = [0.0; 3];
+
+    for i in 0 ..= radius.min(length - 1) {
+      for channel in 0 .. 3 {
+        sum[channel] += light[index(i)][channel];
+      }
+    }
+
+    for i in 0 .. length {
+      for channel in 0 .. 3 {
+        out[index(i)][channel] = sum[channel] * scale;
+      }
+      if i + radius + 1 < length {
+        for channel in 0 .. 3 {
+          sum[channel] += light[index(i + radius + 1)][channel];
+        }
+      }
+      if i >= radius {
+        for channel in 0 .. 3 {
+          sum[channel] -= light[index(i - radius)][channel];
+        }
+      }
+    }
+  }
+
+  out
+}
+
+fn png_error<E: ToString>(error: E) -> EtherdreamError {
+  EtherdreamError::from(IoError::other(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn line(from: (i16, i16), to: (i16, i16), lit: Point) -> Vec<Point> {
+    (0 ..= 100).map(|i| {
+      let x = from.0 as i32 + (to.0 as i32 - from.0 as i32) * i / 100;
+      let y = from.1 as i32 + (to.1 as i32 - from.1 as i32) * i / 100;
+      Point { x: x as i16, y: y as i16, ..lit }
+    }).collect()
+  }
+
+  #[test]
+  fn test_draws_lit_segments_only() {
+    let mut points = line((X_MIN, 0), (X_MAX, 0), Point::xy_rgb(0, 0, COLOR_MAX, 0, 0));
+    points.extend(line((X_MAX, 0), (X_MAX, Y_MAX), Point::xy_blank(0, 0)));
+
+    let mut renderer = Renderer::new(64, 65);
+    renderer.render(&points);
+    let image = renderer.image();
+
+    let (r, g, b) = image.get_pixel(32, 32);
+    assert!(r > 100 && g == 0 && b == 0);
+    assert_eq!((0, 0, 0), image.get_pixel(63, 10));
+
+    // Blanked moves show when asked for.
+    let mut renderer = Renderer::new(64, 65).with_blanked(0.5);
+    renderer.render(&points);
+    let (r, g, b) = renderer.image().get_pixel(63, 10);
+    assert!(r > 0 && r == g && g == b);
+  }
+
+  #[test]
+  fn test_additive_color() {
+    let mut points = line((X_MIN, 0), (X_MAX, 0), Point::xy_rgb(0, 0, COLOR_MAX, 0, 0));
+    points.extend(line((X_MIN, 0), (X_MAX, 0), Point::xy_rgb(0, 0, 0, COLOR_MAX, 0)));
+
+    let mut renderer = Renderer::new(64, 65);
+    renderer.render(&points);
+    let (r, g, b) = renderer.image().get_pixel(32, 32);
+    assert!(r > 100 && g > 100 && b == 0);
+  }
+
+  #[test]
+  fn test_persistence_and_glow() {
+    let points = line((X_MIN, 0), (X_MAX, 0), Point::xy_luma(0, 0, COLOR_MAX));
+
+    let mut renderer = Renderer::new(64, 65).with_persistence(0.5);
+    renderer.render(&points);
+    let lit = renderer.image().get_pixel(32, 32).0;
+    renderer.render(&[]);
+    let faded = renderer.image().get_pixel(32, 32).0;
+    assert!(faded > 0 && faded < lit);
+
+    let mut renderer = Renderer::new(64, 65);
+    renderer.render(&points);
+    assert_eq!((0, 0, 0), renderer.image().get_pixel(32, 35));
+
+    let mut renderer = Renderer::new(64, 65).with_glow(4, 1.0);
+    renderer.render(&points);
+    assert!(renderer.image().get_pixel(32, 35).0 > 0);
+  }
+
+  #[test]
+  fn test_png() {
+    let mut renderer = Renderer::new(8, 4);
+    renderer.render(&[Point::xy_luma(0, 0, COLOR_MAX); 10]);
+    let png = renderer.image().to_png().unwrap();
+    assert_eq!(b"\x89PNG", &png[.. 4]);
+  }
+}
@@ -0,0 +1,606 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Audience safety filters.
+
+use crate::control::OutputControl;
+use crate::filter::PointFilter;
+use crate::filter::blanked;
+use crate::filter::is_lit;
+use crate::protocol::COLOR_MAX;
+use crate::protocol::Point;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+/// Whether beams are kept out of a zone or confined to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZoneKind {
+  /// Points inside the zone are blanked.
+  Forbidden,
+  /// Points outside every allowed zone are blanked.
+  Allowed,
+}
+
+/// A polygon in DAC coordinates.
+#[derive(Clone, Debug)]
+pub struct Zone {
+  /// Whether the zone is forbidden or allowed.
+  pub kind: ZoneKind,
+  /// The polygon's vertices, in order. The polygon closes itself.
+  pub vertices: Vec<(i16, i16)>,
+}
+
+impl Zone {
+  /// CTOR for a zone beams must stay out of.
+  pub fn forbidden(vertices: Vec<(i16, i16)>) -> Zone {
+    Zone { kind: ZoneKind::Forbidden, vertices }
+  }
+
+  /// CTOR for a zone beams must stay inside of.
+  pub fn allowed(vertices: Vec<(i16, i16)>) -> Zone {
+    Zone { kind: ZoneKind::Allowed, vertices }
+  }
+
+  /// Whether the position lies inside the polygon.
+  pub fn contains(&self, x: f64, y: f64) -> bool {
+    let mut inside = false;
+    let mut j = match self.vertices.len() {
+      0 => return false,
+      len => len - 1,
+    };
+
+    for i in 0 .. self.vertices.len() {
+      let (xi, yi) = (self.vertices[i].0 as f64, self.vertices[i].1 as f64);
+      let (xj, yj) = (self.vertices[j].0 as f64, self.vertices[j].1 as f64);
+
+      if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+        inside = !inside;
+      }
+      j = i;
+    }
+
+    inside
+  }
+
+  /// Where the segment from `a` to `b` crosses the polygon's edges, as
+  /// fractions of the way from `a` to `b`.
+  fn crossings(&self, a: (f64, f64), b: (f64, f64), out: &mut Vec<f64>) {
+    let len = self.vertices.len();
+    for i in 0 .. len {
+      let c = self.vertices[i];
+      let d = self.vertices[(i + 1) % len];
+      let (cx, cy) = (c.0 as f64, c.1 as f64);
+      let (dx, dy) = (d.0 as f64, d.1 as f64);
+
+      let denominator = (b.0 - a.0) * (dy - cy) - (b.1 - a.1) * (dx - cx);
+      if denominator == 0.0 {
+        continue;
+      }
+
+      let t = ((cx - a.0) * (dy - cy) - (cy - a.1) * (dx - cx)) / denominator;
+      let u = ((cx - a.0) * (b.1 - a.1) - (cy - a.1) * (b.0 - a.0)) / denominator;
+
+      if t > 0.0 && t < 1.0 && (0.0 ..= 1.0).contains(&u) {
+        out.push(t);
+      }
+    }
+  }
+}
+
+/// Counts points blanked by a `SafetyZones` filter.
+/// Cheap to clone and may be read from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct ViolationCounter {
+  count: Arc<AtomicUsize>,
+}
+
+impl ViolationCounter {
+  /// The number of lit points blanked so far.
+  pub fn get(&self) -> usize {
+    self.count.load(Ordering::SeqCst)
+  }
+
+  /// Reset the count to zero.
+  pub fn reset(&self) {
+    self.count.store(0, Ordering::SeqCst);
+  }
+
+  fn add(&self, count: usize) {
+    self.count.fetch_add(count, Ordering::SeqCst);
+  }
+}
+
+/// Blanks every point inside a forbidden zone, or outside all allowed zones if
+/// any are defined. Lit lines that cross a zone edge are split at the edge so
+/// the beam goes dark exactly there. Set it with `Dac::set_safety_zones` so
+/// it runs after every filter and the color shift, and the zones apply to the
+/// final points. Used as a filter of its own, add it last.
+pub struct SafetyZones {
+  zones: Vec<Zone>,
+  violations: ViolationCounter,
+  last: Option<Point>,
+}
+
+impl SafetyZones {
+  /// CTOR.
+  pub fn new(zones: Vec<Zone>) -> SafetyZones {
+    SafetyZones {
+      zones,
+      violations: ViolationCounter::default(),
+      last: None,
+    }
+  }
+
+  /// The zones being enforced.
+  pub fn zones(&self) -> &[Zone] {
+    &self.zones
+  }
+
+  /// A counter of the lit points this filter blanked.
+  pub fn violations(&self) -> ViolationCounter {
+    self.violations.clone()
+  }
+
+  /// Whether a beam may be drawn at the position.
+  pub fn permits(&self, x: f64, y: f64) -> bool {
+    let mut has_allowed = false;
+    let mut in_allowed = false;
+
+    for zone in self.zones.iter() {
+      match zone.kind {
+        ZoneKind::Forbidden => {
+          if zone.contains(x, y) {
+            return false;
+          }
+        },
+        ZoneKind::Allowed => {
+          has_allowed = true;
+          in_allowed = in_allowed || zone.contains(x, y);
+        },
+      }
+    }
+
+    !has_allowed || in_allowed
+  }
+
+  /// Add points where the lit segment from `from` to `to` crosses between
+  /// permitted and forbidden space, so the light switches at the edge.
+  fn split_segment(&self, from: &Point, to: &Point, out: &mut Vec<Point>) {
+    let a = (from.x as f64, from.y as f64);
+    let b = (to.x as f64, to.y as f64);
+
+    let mut crossings = Vec::new();
+    for zone in self.zones.iter() {
+      zone.crossings(a, b, &mut crossings);
+    }
+
+    if crossings.is_empty() {
+      return;
+    }
+
+    crossings.sort_by(|l, r| l.partial_cmp(r).unwrap());
+    crossings.dedup();
+
+    let lerp = |t: f64| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+
+    let mut previous_t = 0.0;
+    for (i, t) in crossings.iter().enumerate() {
+      let next_t = crossings.get(i + 1).cloned().unwrap_or(1.0);
+      let before = lerp((previous_t + t) / 2.0);
+      let after = lerp((t + next_t) / 2.0);
+
+      let lit_before = self.permits(before.0, before.1);
+      let lit_after = self.permits(after.0, after.1);
+
+      if lit_before != lit_after {
+        let (x, y) = lerp(*t);
+        let edge = Point { x: x.round() as i16, y: y.round() as i16, ..*to };
+        out.push(if lit_before { edge } else { blanked(&edge) });
+        out.push(if lit_after { edge } else { blanked(&edge) });
+      }
+
+      previous_t = *t;
+    }
+  }
+}
+
+impl PointFilter for SafetyZones {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    if self.zones.is_empty() {
+      return;
+    }
+
+    let mut out = Vec::with_capacity(points.len());
+    let mut violations = 0;
+
+    for point in points.drain(..) {
+      let lit = is_lit(&point);
+
+      if lit {
+        if let Some(last) = self.last {
+          self.split_segment(&last, &point, &mut out);
+        }
+      }
+
+      if lit && !self.permits(point.x as f64, point.y as f64) {
+        violations += 1;
+        out.push(blanked(&point));
+      } else {
+        out.push(point);
+      }
+
+      self.last = Some(point);
+    }
+
+    if violations > 0 {
+      self.violations.add(violations);
+      warn!("Blanked {} points inside forbidden zones.", violations);
+    }
+
+    *points = out;
+  }
+}
+
+/// Exposure at a spot fades with a time constant this many times the allowed
+/// exposure.
+const DWELL_WINDOW : f64 = 10.0;
+
+/// Spots whose exposure fades below this, in points at full power, are
+/// forgotten. Well under one point, so a spot lit once is still remembered
+/// however long the allowed exposure is.
+const DWELL_FORGET : f64 = 0.01;
+
+/// What `DwellGuard` does to a beam that has dwelled too long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DwellAction {
+  /// Turn the beam off.
+  Blank,
+  /// Scale every color channel by the factor, from 0.0 to 1.0.
+  Dim(f64),
+}
+
+/// Events raised by safety filters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SafetyEvent {
+  /// A lit beam stayed near one spot for too long and is being limited.
+  DwellExceeded {
+    /// X coordinate of the spot.
+    x: i16,
+    /// Y coordinate of the spot.
+    y: i16,
+  },
+  /// The beam moved away and is no longer being limited.
+  DwellCleared,
+}
+
+/// Protects against a static beam. Tracks how much light lands on each spot,
+/// within a small radius, and limits the beam wherever that exceeds the
+/// allowed exposure.
+///
+/// Exposure is measured in seconds at full power: a point at full brightness
+/// adds `1 / point_rate` seconds, a point at half brightness adds half that.
+/// Exposure at a spot fades over ten times the allowed exposure rather than
+/// resetting when the beam moves away, so a beam hopping between a few spots
+/// is caught as well as one standing still.
+pub struct DwellGuard {
+  radius: f64,
+  max_exposure: f64,
+  point_rate: u32,
+  rate_source: Option<OutputControl>,
+  action: DwellAction,
+  spots: Vec<Spot>,
+  tripped: bool,
+  events: Vec<Sender<SafetyEvent>>,
+}
+
+/// Where light has landed recently.
+struct Spot {
+  x: f64,
+  y: f64,
+  /// Measured in points at full power to avoid accumulating rounding error.
+  exposure: f64,
+}
+
+impl DwellGuard {
+  /// CTOR. The point rate must match the rate the DAC plays at, unless
+  /// `follow_point_rate` keeps it up to date.
+  pub fn new(radius: i16, max_exposure: Duration, point_rate: u32) -> DwellGuard {
+    DwellGuard {
+      radius: radius as f64,
+      max_exposure: max_exposure.as_secs_f64(),
+      point_rate: point_rate.max(1),
+      rate_source: None,
+      action: DwellAction::Blank,
+      spots: Vec::new(),
+      tripped: false,
+      events: Vec::new(),
+    }
+  }
+
+  /// What happens to the beam once it dwells too long. Defaults to
+  /// `DwellAction::Blank`.
+  pub fn set_action(&mut self, action: DwellAction) {
+    self.action = action;
+  }
+
+  /// Update the point rate used to convert points into time.
+  pub fn set_point_rate(&mut self, point_rate: u32) {
+    self.point_rate = point_rate.max(1);
+  }
+
+  /// Take the point rate from a stream's controls before each batch, so
+  /// changes made through `DacHandle::set_point_rate` are accounted for.
+  pub fn follow_point_rate(&mut self, control: OutputControl) {
+    self.rate_source = Some(control);
+  }
+
+  /// Whether the beam is currently being limited.
+  pub fn is_tripped(&self) -> bool {
+    self.tripped
+  }
+
+  /// Receive the events this filter raises.
+  pub fn events(&mut self) -> Receiver<SafetyEvent> {
+    let (sender, receiver) = channel();
+    self.events.push(sender);
+    receiver
+  }
+
+  fn raise(&mut self, event: SafetyEvent) {
+    // Drop senders whose receivers hung up.
+    self.events.retain(|sender| sender.send(event).is_ok());
+  }
+}
+
+impl PointFilter for DwellGuard {
+  fn filter(&mut self, points: &mut Vec<Point>) {
+    if let Some(ref control) = self.rate_source {
+      self.point_rate = control.get_point_rate().max(1);
+    }
+
+    // In points at full power.
+    let limit = self.max_exposure * self.point_rate as f64;
+    let fade = (-1.0 / (limit * DWELL_WINDOW)).exp();
+
+    for point in points.iter_mut() {
+      let (x, y) = (point.x as f64, point.y as f64);
+
+      self.spots.retain_mut(|spot| {
+        spot.exposure *= fade;
+        spot.exposure > DWELL_FORGET
+      });
+
+      let brightness = [point.r, point.g, point.b, point.i].iter()
+          .cloned()
+          .max()
+          .unwrap_or(0) as f64 / COLOR_MAX as f64;
+
+      let radius = self.radius;
+      let nearest = self.spots.iter_mut()
+          .map(|spot| ((spot.x - x).hypot(spot.y - y), spot))
+          .filter(|&(distance, _)| distance <= radius)
+          .min_by(|a, b| a.0.total_cmp(&b.0))
+          .map(|(_, spot)| spot);
+
+      let exposure = match nearest {
+        Some(spot) => {
+          spot.exposure += brightness;
+          spot.exposure
+        },
+        None => {
+          if brightness > 0.0 {
+            self.spots.push(Spot { x, y, exposure: brightness });
+          }
+          brightness
+        },
+      };
+
+      if exposure <= limit {
+        if self.tripped {
+          self.tripped = false;
+          self.raise(SafetyEvent::DwellCleared);
+        }
+      } else {
+        if !self.tripped {
+          self.tripped = true;
+          warn!("Beam dwelled too long at ({}, {}).", point.x, point.y);
+          self.raise(SafetyEvent::DwellExceeded { x: point.x, y: point.y });
+        }
+
+        *point = match self.action {
+          DwellAction::Blank => blanked(point),
+          DwellAction::Dim(factor) => {
+            let dim = |c: u16| (c as f64 * factor.clamp(0.0, 1.0)) as u16;
+            Point {
+              r: dim(point.r),
+              g: dim(point.g),
+              b: dim(point.b),
+              i: dim(point.i),
+              ..*point
+            }
+          },
+        };
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::control::StreamControl;
+
+  fn square(min: i16, max: i16) -> Vec<(i16, i16)> {
+    vec![(min, min), (max, min), (max, max), (min, max)]
+  }
+
+  #[test]
+  fn test_zone_contains() {
+    let zone = Zone::forbidden(square(-100, 100));
+    assert!(zone.contains(0.0, 0.0));
+    assert!(zone.contains(99.0, -99.0));
+    assert!(!zone.contains(101.0, 0.0));
+    assert!(!zone.contains(0.0, -200.0));
+  }
+
+  #[test]
+  fn test_forbidden_points_blanked() {
+    let mut zones = SafetyZones::new(vec![Zone::forbidden(square(-100, 100))]);
+    let mut points = vec![
+      Point::xy_luma(0, 0, 100),
+      Point::xy_luma(0, 0, 100),
+    ];
+
+    zones.filter(&mut points);
+    assert!(points.iter().all(|p| !is_lit(p)));
+    assert_eq!(2, zones.violations().get());
+  }
+
+  #[test]
+  fn test_allowed_zone() {
+    let mut zones = SafetyZones::new(vec![Zone::allowed(square(-100, 100))]);
+    let mut points = vec![Point::xy_luma(50, 50, 100)];
+    zones.filter(&mut points);
+    assert!(is_lit(&points[0]));
+
+    let mut points = vec![Point::xy_luma(500, 500, 100)];
+    zones.filter(&mut points);
+    assert_eq!(vec![(100, true), (100, false), (500, false)],
+        points.iter().map(|p| (p.x, is_lit(p))).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn test_segment_split_at_edges() {
+    let mut zones = SafetyZones::new(vec![Zone::forbidden(square(-100, 100))]);
+    let mut points = vec![
+      Point::xy_luma(-1000, 0, 100),
+      Point::xy_luma(1000, 0, 100),
+    ];
+
+    zones.filter(&mut points);
+
+    let summary : Vec<(i16, bool)> = points.iter()
+        .map(|p| (p.x, is_lit(p)))
+        .collect();
+
+    assert_eq!(vec![
+      (-1000, true),
+      (-100, true),
+      (-100, false),
+      (100, false),
+      (100, true),
+      (1000, true),
+    ], summary);
+    assert_eq!(0, zones.violations().get());
+  }
+
+  #[test]
+  fn test_dwell_guard_trips() {
+    // 10 points at full power is 10ms of exposure at 1000 points per second.
+    let mut guard = DwellGuard::new(50, Duration::from_millis(10), 1000);
+    let events = guard.events();
+
+    let mut points = vec![Point::xy_luma(0, 0, COLOR_MAX); 12];
+    points[5].x = 20; // Wobbling within the radius doesn't help.
+    guard.filter(&mut points);
+
+    assert!(points[.. 10].iter().all(is_lit));
+    assert!(points[10 ..].iter().all(|p| !is_lit(p)));
+    assert!(guard.is_tripped());
+    assert_eq!(SafetyEvent::DwellExceeded { x: 0, y: 0 }, events.try_recv().unwrap());
+
+    let mut points = vec![Point::xy_luma(1000, 0, COLOR_MAX)];
+    guard.filter(&mut points);
+
+    assert!(is_lit(&points[0]));
+    assert!(!guard.is_tripped());
+    assert_eq!(SafetyEvent::DwellCleared, events.try_recv().unwrap());
+  }
+
+  #[test]
+  fn test_dwell_guard_catches_alternating_spots() {
+    let mut guard = DwellGuard::new(50, Duration::from_millis(10), 1000);
+    let mut points : Vec<Point> = (0 .. 40)
+        .map(|i| Point::xy_luma(i % 2 * 1000, 0, COLOR_MAX))
+        .collect();
+    guard.filter(&mut points);
+
+    assert!(points[.. 20].iter().all(is_lit));
+    assert!(points[30 ..].iter().all(|p| !is_lit(p)));
+    assert!(guard.is_tripped());
+  }
+
+  #[test]
+  fn test_dwell_guard_trips_at_full_rate() {
+    // A 100ms limit at 30k points per second is 3000 points.
+    let mut guard = DwellGuard::new(50, Duration::from_millis(100), 30_000);
+    let mut points = vec![Point::xy_luma(0, 0, COLOR_MAX); 30_000];
+    guard.filter(&mut points);
+
+    // Exposure fades as it builds, so the guard trips a little after 3000
+    // points, then only lets through as much light as fades away.
+    assert!(points[.. 3000].iter().all(is_lit));
+    assert!(points[3300 .. 3400].iter().all(|p| !is_lit(p)));
+    assert!(lit_fraction(&points[3300 ..]) < 0.15);
+  }
+
+  #[test]
+  fn test_dwell_guard_catches_alternating_spots_at_full_rate() {
+    let mut guard = DwellGuard::new(50, Duration::from_millis(100), 30_000);
+    let mut points : Vec<Point> = (0 .. 30_000)
+        .map(|i| Point::xy_luma(i % 2 * 1000, 0, COLOR_MAX))
+        .collect();
+    guard.filter(&mut points);
+
+    assert!(points[.. 6000].iter().all(is_lit));
+    assert!(points[6800 .. 6900].iter().all(|p| !is_lit(p)));
+    assert!(lit_fraction(&points[6800 ..]) < 0.15);
+  }
+
+  fn lit_fraction(points: &[Point]) -> f64 {
+    points.iter().filter(|p| is_lit(p)).count() as f64 / points.len() as f64
+  }
+
+  #[test]
+  fn test_dwell_guard_follows_point_rate() {
+    let control = Arc::new(StreamControl::default());
+    control.set_current_point_rate(1000);
+    let mut guard = DwellGuard::new(50, Duration::from_millis(10), 30_000);
+    guard.follow_point_rate(OutputControl::new(control.clone()));
+
+    let mut points = vec![Point::xy_luma(0, 0, COLOR_MAX); 5];
+    guard.filter(&mut points);
+    assert!(!guard.is_tripped());
+
+    // A tenth the rate, so each point is ten times the exposure.
+    control.set_current_point_rate(100);
+    let mut points = vec![Point::xy_luma(0, 0, COLOR_MAX); 5];
+    guard.filter(&mut points);
+    assert!(guard.is_tripped());
+  }
+
+  #[test]
+  fn test_dwell_guard_dims() {
+    let mut guard = DwellGuard::new(50, Duration::from_millis(1), 1000);
+    guard.set_action(DwellAction::Dim(0.5));
+
+    let mut points = vec![Point::xy_luma(0, 0, COLOR_MAX); 2];
+    guard.filter(&mut points);
+
+    assert_eq!(COLOR_MAX, points[0].r);
+    assert_eq!(COLOR_MAX / 2, points[1].r);
+  }
+
+  #[test]
+  fn test_dwell_guard_ignores_blank_points() {
+    let mut guard = DwellGuard::new(50, Duration::from_millis(1), 1000);
+    let mut points = vec![Point::xy_blank(0, 0); 100];
+    guard.filter(&mut points);
+    assert!(!guard.is_tripped());
+  }
+}
@@ -0,0 +1,115 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Terminating laser output when a `Dac` is dropped or the controlling code
+//! panics.
+
+use crate::connection::Connection;
+use crate::protocol::COMMAND_EMERGENCY_STOP;
+use crate::protocol::COMMAND_STOP;
+use std::time::Duration;
+
+/// How long to wait on the network when shutting down.
+const SHUTDOWN_TIMEOUT_MS : u64 = 100;
+
+/// What to send the DAC when output must be terminated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ShutdownMode {
+  /// Send nothing. The DAC plays out its buffer, then underflows.
+  Nothing,
+  /// Stop playback. The DAC returns to the idle state.
+  #[default]
+  Stop,
+  /// Emergency stop. The DAC must be cleared before it plays again.
+  EmergencyStop,
+}
+
+/// Terminates laser output when dropped, including while unwinding from a
+/// panic. Create one with `Dac::shutdown_guard` and keep it alive for as long
+/// as the DAC should be allowed to play.
+///
+/// The guard shares the DAC's connection. If the DAC is mid-command when the
+/// guard drops, the guard waits for that command's response before sending
+/// its own.
+pub struct ShutdownGuard {
+  connection: Connection,
+  mode: ShutdownMode,
+  armed: bool,
+}
+
+impl ShutdownGuard {
+  pub(crate) fn new(connection: Connection, mode: ShutdownMode) -> ShutdownGuard {
+    ShutdownGuard {
+      connection,
+      mode,
+      armed: true,
+    }
+  }
+
+  /// The command sent when the guard is dropped.
+  pub fn get_mode(&self) -> ShutdownMode {
+    self.mode
+  }
+
+  /// Drop the guard without terminating output.
+  pub fn disarm(mut self) {
+    self.armed = false;
+  }
+}
+
+impl Drop for ShutdownGuard {
+  fn drop(&mut self) {
+    if self.armed {
+      send_shutdown(&self.connection, self.mode);
+    }
+  }
+}
+
+/// Best-effort shutdown. Errors are ignored since there's nobody left to
+/// report them to, and short timeouts keep us from hanging on a dead DAC.
+pub(crate) fn send_shutdown(connection: &Connection, mode: ShutdownMode) {
+  let command = match mode {
+    ShutdownMode::Nothing => return,
+    ShutdownMode::Stop => COMMAND_STOP,
+    ShutdownMode::EmergencyStop => COMMAND_EMERGENCY_STOP,
+  };
+
+  let timeout = Duration::from_millis(SHUTDOWN_TIMEOUT_MS);
+  let _ = connection.exchange_with_timeout(command, timeout);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+  use std::net::TcpListener;
+  use std::net::TcpStream;
+
+  fn connect() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    (client, server)
+  }
+
+  #[test]
+  fn test_guard_sends_shutdown_on_drop() {
+    let (client, mut server) = connect();
+    drop(ShutdownGuard::new(Connection::new(client), ShutdownMode::EmergencyStop));
+
+    let mut buf = [0xAA; 1];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(COMMAND_EMERGENCY_STOP, buf[0]);
+  }
+
+  #[test]
+  fn test_disarmed_guard_sends_nothing() {
+    let (client, mut server) = connect();
+    ShutdownGuard::new(Connection::new(client), ShutdownMode::Stop).disarm();
+
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).unwrap();
+    assert!(buf.is_empty());
+  }
+}
@@ -0,0 +1,295 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Simulating where the beam actually goes.
+//!
+//! The DAC holds each point for one tick of its sample clock, and the galvos
+//! chase the held position with a lag set by their bandwidth. Corners round
+//! off, fast lines come up short, and underdamped scanners overshoot. The
+//! simulated path is a stream of points that can be measured or drawn with
+//! the `render` module to see how content will look at a point rate.
+
+use crate::geometry::saturate;
+use crate::protocol::Point;
+use std::f64::consts::PI;
+
+/// Frame rates below this flicker visibly, in frames per second.
+pub const FLICKER_THRESHOLD : f64 = 30.0;
+
+/// The most integration steps taken per sample. Galvos fast enough to need
+/// more settle within the sample.
+const MAX_INTEGRATION_STEPS : f64 = 1000.0;
+
+/// How galvos respond to a change in position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GalvoModel {
+  /// Moves toward the target at a rate proportional to the distance left,
+  /// never overshooting. `bandwidth` is the -3 dB frequency in hertz.
+  FirstOrder {
+    /// Bandwidth in hertz.
+    bandwidth: f64,
+  },
+  /// A mass on a spring with damping, as most closed-loop scanners behave.
+  /// `bandwidth` is the natural frequency in hertz. A `damping` ratio below
+  /// 1.0 overshoots; 1.0 or more doesn't.
+  SecondOrder {
+    /// Bandwidth in hertz.
+    bandwidth: f64,
+    /// Damping ratio.
+    damping: f64,
+  },
+}
+
+/// Measurements of a frame played through a `Simulator`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Report {
+  /// How often the frame is redrawn, in frames per second.
+  pub frame_rate: f64,
+  /// Whether the frame rate is low enough to flicker.
+  pub flicker: bool,
+  /// The furthest the beam went past a point it was moving toward, in DAC
+  /// units.
+  pub max_overshoot: f64,
+  /// The furthest the beam was from the point being output, in DAC units.
+  pub max_error: f64,
+  /// The average distance from the beam to the point being output, in DAC
+  /// units.
+  pub mean_error: f64,
+}
+
+/// Plays points through a model of the DAC's sample clock and the galvos.
+pub struct Simulator {
+  model: GalvoModel,
+  point_rate: u32,
+  substeps: usize,
+  position: (f64, f64),
+  velocity: (f64, f64),
+}
+
+impl Simulator {
+  /// CTOR. The beam is sampled four times per point, starting at rest at the
+  /// center.
+  pub fn new(model: GalvoModel, point_rate: u32) -> Simulator {
+    Simulator {
+      model,
+      point_rate: point_rate.max(1),
+      substeps: 4,
+      position: (0.0, 0.0),
+      velocity: (0.0, 0.0),
+    }
+  }
+
+  /// Sample the beam this many times per point.
+  pub fn with_substeps(mut self, substeps: usize) -> Simulator {
+    self.substeps = substeps.max(1);
+    self
+  }
+
+  /// The galvo model.
+  pub fn get_model(&self) -> GalvoModel {
+    self.model
+  }
+
+  /// The DAC's point rate.
+  pub fn get_point_rate(&self) -> u32 {
+    self.point_rate
+  }
+
+  /// Beam samples per point. Every sample of the path carries as much light
+  /// as a point in the `render` module, so divide a renderer's exposure by
+  /// this to keep brightness comparable.
+  pub fn get_substeps(&self) -> usize {
+    self.substeps
+  }
+
+  /// Bring the galvos to rest at the center.
+  pub fn reset(&mut self) {
+    self.position = (0.0, 0.0);
+    self.velocity = (0.0, 0.0);
+  }
+
+  /// The beam's path while the points play, `substeps` samples per point.
+  /// Each sample has the color of the point being output at the time.
+  /// Consecutive calls continue where the last left off.
+  pub fn simulate(&mut self, points: &[Point]) -> Vec<Point> {
+    let dt = 1.0 / (self.point_rate as f64 * self.substeps as f64);
+    let mut path = Vec::with_capacity(points.len() * self.substeps);
+
+    for point in points.iter() {
+      let target = (point.x as f64, point.y as f64);
+      for _ in 0 .. self.substeps {
+        self.step(target, dt);
+        let (x, y) = saturate(self.position.0, self.position.1);
+        path.push(Point { x, y, ..*point });
+      }
+    }
+
+    path
+  }
+
+  /// Play a frame in a loop until the galvos settle into a pattern, then
+  /// measure one pass. Leaves the galvos where that pass ends.
+  pub fn measure(&mut self, frame: &[Point]) -> Report {
+    let frame_rate = self.point_rate as f64 / frame.len().max(1) as f64;
+    let mut report = Report {
+      frame_rate,
+      flicker: frame_rate < FLICKER_THRESHOLD,
+      max_overshoot: 0.0,
+      max_error: 0.0,
+      mean_error: 0.0,
+    };
+    if frame.is_empty() {
+      return report;
+    }
+
+    for _ in 0 .. 2 {
+      self.simulate(frame);
+    }
+    let path = self.simulate(frame);
+
+    let mut previous = frame[frame.len() - 1];
+    let mut direction = (0.0, 0.0);
+    let mut total_error = 0.0;
+
+    for (i, sample) in path.iter().enumerate() {
+      let target = &frame[i / self.substeps];
+      if i % self.substeps == 0 {
+        direction.0 = signum_or(target.x as f64 - previous.x as f64, direction.0);
+        direction.1 = signum_or(target.y as f64 - previous.y as f64, direction.1);
+        previous = *target;
+      }
+
+      let dx = sample.x as f64 - target.x as f64;
+      let dy = sample.y as f64 - target.y as f64;
+      let overshoot = (dx * direction.0).max(dy * direction.1).max(0.0);
+      let error = dx.hypot(dy);
+
+      report.max_overshoot = report.max_overshoot.max(overshoot);
+      report.max_error = report.max_error.max(error);
+      total_error += error;
+    }
+
+    report.mean_error = total_error / path.len() as f64;
+    report
+  }
+
+  /// Advance the galvos toward the target.
+  fn step(&mut self, target: (f64, f64), dt: f64) {
+    match self.model {
+      GalvoModel::FirstOrder { bandwidth } => {
+        let blend = 1.0 - (-2.0 * PI * bandwidth * dt).exp();
+        self.position.0 += (target.0 - self.position.0) * blend;
+        self.position.1 += (target.1 - self.position.1) * blend;
+      },
+      GalvoModel::SecondOrder { bandwidth, damping } => {
+        let omega = 2.0 * PI * bandwidth;
+        // Keep integration steps short enough to stay stable.
+        let steps = (dt * omega / 0.05).ceil().max(1.0);
+        if steps > MAX_INTEGRATION_STEPS {
+          self.position = target;
+          self.velocity = (0.0, 0.0);
+          return;
+        }
+        let steps = steps as usize;
+        let h = dt / steps as f64;
+        for _ in 0 .. steps {
+          let ax = omega * omega * (target.0 - self.position.0)
+              - 2.0 * damping * omega * self.velocity.0;
+          let ay = omega * omega * (target.1 - self.position.1)
+              - 2.0 * damping * omega * self.velocity.1;
+          self.velocity.0 += ax * h;
+          self.velocity.1 += ay * h;
+          self.position.0 += self.velocity.0 * h;
+          self.position.1 += self.velocity.1 * h;
+        }
+      },
+    }
+  }
+}
+
+/// The sign of a change, or the previous sign if there was no change.
+fn signum_or(delta: f64, previous: f64) -> f64 {
+  if delta == 0.0 { previous } else { delta.signum() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::COLOR_MAX;
+
+  /// A jump to the right, held long enough to settle.
+  fn step_frame() -> Vec<Point> {
+    let mut frame = vec![Point::xy_blank(-10000, 0); 300];
+    frame.extend(vec![Point::xy_luma(10000, 0, COLOR_MAX); 300]);
+    frame
+  }
+
+  #[test]
+  fn test_first_order_settles_without_overshoot() {
+    let model = GalvoModel::FirstOrder { bandwidth: 1000.0 };
+    let mut simulator = Simulator::new(model, 30_000);
+    let path = simulator.simulate(&step_frame());
+
+    assert_eq!(600 * 4, path.len());
+    assert!(path[1200].x < 0);
+    assert!((path[path.len() - 1].x - 10000).abs() <= 1);
+    assert!(path.iter().all(|p| p.x <= 10000));
+
+    let report = simulator.measure(&step_frame());
+    assert!(report.max_overshoot < 1.0);
+    assert!(report.max_error > 10000.0);
+  }
+
+  #[test]
+  fn test_second_order_overshoots_when_underdamped() {
+    let underdamped = GalvoModel::SecondOrder { bandwidth: 1000.0, damping: 0.3 };
+    let critical = GalvoModel::SecondOrder { bandwidth: 1000.0, damping: 1.0 };
+
+    let report = Simulator::new(underdamped, 30_000).measure(&step_frame());
+    assert!(report.max_overshoot > 5000.0, "{:?}", report);
+
+    let report = Simulator::new(critical, 30_000).measure(&step_frame());
+    assert!(report.max_overshoot < 5.0, "{:?}", report);
+  }
+
+  #[test]
+  fn test_bandwidth_and_point_rate() {
+    let slow = GalvoModel::SecondOrder { bandwidth: 500.0, damping: 0.7 };
+    let fast = GalvoModel::SecondOrder { bandwidth: 5000.0, damping: 0.7 };
+    let frame = step_frame();
+
+    let slow = Simulator::new(slow, 30_000).measure(&frame);
+    let fast = Simulator::new(fast, 30_000).measure(&frame);
+    assert!(fast.mean_error < slow.mean_error);
+
+    assert_eq!(50.0, fast.frame_rate);
+    assert!(!fast.flicker);
+    assert!(Simulator::new(GalvoModel::FirstOrder { bandwidth: 1000.0 }, 12_000)
+        .measure(&frame).flicker);
+  }
+
+  #[test]
+  fn test_very_fast_galvos_settle_within_a_sample() {
+    let model = GalvoModel::SecondOrder { bandwidth: 1e12, damping: 0.7 };
+    let mut simulator = Simulator::new(model, 30_000);
+    let path = simulator.simulate(&step_frame());
+
+    let last = path.last().unwrap();
+    assert_eq!((10000, 0), (last.x, last.y));
+  }
+
+  #[test]
+  fn test_colors_follow_the_sample_clock() {
+    let model = GalvoModel::FirstOrder { bandwidth: 100.0 };
+    let mut simulator = Simulator::new(model, 30_000).with_substeps(3);
+    let frame = step_frame();
+    simulator.simulate(&frame[.. 298]);
+    let path = simulator.simulate(&frame[298 .. 302]);
+
+    let lit : Vec<bool> = path.iter().map(|p| p.i > 0).collect();
+    assert_eq!(vec![false; 6], lit[.. 6].to_vec());
+    assert_eq!(vec![true; 6], lit[6 ..].to_vec());
+    // The beam lags well behind the jump when the color changes.
+    assert!(path[6].x < -9000);
+  }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io>, <echelon@gmail.com>
+// Etherdream.rs, a library for the EtherDream laser projector DAC.
+
+//! Emergency stops the DAC if the point source takes too long.
+
+use crate::connection::Connection;
+use crate::protocol::COMMAND_EMERGENCY_STOP;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread::Builder;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How long to wait on the network when stopping.
+const STOP_TIMEOUT : Duration = Duration::from_millis(500);
+
+/// Watches calls to the point source from a separate thread. If a call is
+/// still running once the deadline passes, the watchdog sends the emergency
+/// stop command on the DAC's connection and reads the response itself. The
+/// stream loop isn't using the connection while the source runs, so the
+/// command goes out right away.
+pub(crate) struct Watchdog {
+  shared: Arc<(Mutex<State>, Condvar)>,
+  thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+  armed_at: Option<Instant>,
+  fired: bool,
+  exit: bool,
+}
+
+impl Watchdog {
+  /// Start the watchdog thread.
+  pub fn start(connection: Connection, deadline: Duration)
+      -> std::io::Result<Watchdog> {
+    let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+    let thread_shared = shared.clone();
+
+    let thread = Builder::new()
+        .name("etherdream-watchdog".to_string())
+        .spawn(move || {
+          let (ref lock, ref condvar) = *thread_shared;
+          let mut state = lock.lock().unwrap();
+
+          while !state.exit {
+            let armed_at = match state.armed_at {
+              Some(armed_at) if !state.fired => armed_at,
+              _ => {
+                state = condvar.wait(state).unwrap();
+                continue;
+              },
+            };
+
+            let elapsed = armed_at.elapsed();
+            if elapsed < deadline {
+              state = condvar.wait_timeout(state, deadline - elapsed).unwrap().0;
+              continue;
+            }
+
+            // Best effort. The stream loop reports the stall either way.
+            let _ = connection.exchange_with_timeout(COMMAND_EMERGENCY_STOP,
+                STOP_TIMEOUT);
+            state.fired = true;
+          }
+        })?;
+
+    Ok(Watchdog {
+      shared,
+      thread: Some(thread),
+    })
+  }
+
+  /// Call before asking the source for points.
+  pub fn arm(&self) {
+    let (ref lock, ref condvar) = *self.shared;
+    let mut state = lock.lock().unwrap();
+    state.armed_at = Some(Instant::now());
+    state.fired = false;
+    condvar.notify_one();
+  }
+
+  /// Call once the source returns. Returns whether the watchdog fired.
+  pub fn disarm(&self) -> bool {
+    let (ref lock, ref condvar) = *self.shared;
+    let mut state = lock.lock().unwrap();
+    state.armed_at = None;
+    condvar.notify_one();
+    state.fired
+  }
+}
+
+impl Drop for Watchdog {
+  fn drop(&mut self) {
+    {
+      let (ref lock, ref condvar) = *self.shared;
+      lock.lock().unwrap().exit = true;
+      condvar.notify_one();
+    }
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+  use std::net::TcpListener;
+  use std::net::TcpStream;
+  use std::thread;
+
+  fn connect() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+  }
+
+  #[test]
+  fn test_fires_after_deadline() {
+    let (client, mut server) = connect();
+    let connection = Connection::new(client);
+    let watchdog = Watchdog::start(connection, Duration::from_millis(10)).unwrap();
+
+    watchdog.arm();
+    thread::sleep(Duration::from_millis(100));
+    assert!(watchdog.disarm());
+
+    let mut buf = [0xAA; 1];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(COMMAND_EMERGENCY_STOP, buf[0]);
+  }
+
+  #[test]
+  fn test_quiet_within_deadline() {
+    let (client, mut server) = connect();
+    let connection = Connection::new(client);
+    let watchdog = Watchdog::start(connection, Duration::from_secs(10)).unwrap();
+
+    watchdog.arm();
+    assert!(!watchdog.disarm());
+    drop(watchdog);
+
+    // The connection closes without the emergency stop being sent.
+    let mut buf = [0; 1];
+    assert_eq!(0, server.read(&mut buf).unwrap());
+  }
+}